@@ -0,0 +1,102 @@
+//! Saving many small edits to each storage backend, the way `NoteService`
+//! does: the changes if the storage takes them, the whole note otherwise.
+//!
+//! `cargo bench --bench storage`
+
+// The CLI has no library target, so the storage modules are built in here.
+// Most of what they offer goes unused, and their tests are not run from here
+#[allow(dead_code, unused_imports)]
+#[path = "../src/log_storage.rs"]
+mod log_storage;
+#[allow(dead_code, unused_imports)]
+#[path = "../src/sqlite_storage.rs"]
+mod sqlite_storage;
+#[allow(dead_code, unused_imports)]
+#[path = "../src/storage.rs"]
+mod storage;
+
+use crdt_note::Note;
+use criterion::{BatchSize, BenchmarkId, Criterion, criterion_group, criterion_main};
+use log_storage::LogStorage;
+use sqlite_storage::SqliteStorage;
+use std::path::Path;
+use storage::{FileSystemStorage, Storage, StorageResult};
+use tempfile::TempDir;
+
+const NOTES: usize = 100;
+const EDITS: usize = 10;
+
+type Open = fn(&Path) -> StorageResult<Box<dyn Storage>>;
+
+// Each note's id, its saved bytes and the changes since the round before
+type Round = Vec<(String, Vec<u8>, Vec<u8>)>;
+
+fn rounds(words: usize) -> Vec<Round> {
+    let mut all: Vec<Note> = (0..NOTES)
+        .map(|i| Note::new(&format!("note {} {}", i, "words ".repeat(words))))
+        .collect();
+    let mut rounds = vec![
+        all.iter()
+            .map(|n| (n.id(), Note::into(n), Note::into(n)))
+            .collect(),
+    ];
+    for edit in 1..=EDITS {
+        let mut round = Vec::new();
+        for note in &mut all {
+            let edited = note.set_pinned(edit % 2 == 0);
+            let changes = edited.changes_since(&note.heads());
+            round.push((edited.id(), Note::into(&edited), changes));
+            *note = edited;
+        }
+        rounds.push(round);
+    }
+    rounds
+}
+
+fn save(storage: &dyn Storage, round: &Round) {
+    for (id, bytes, changes) in round {
+        if !storage.append(id, changes).unwrap() {
+            storage.set(id, bytes).unwrap();
+        }
+    }
+}
+
+fn bench_edits(c: &mut Criterion) {
+    let backends: [(&str, Open); 3] = [
+        ("files", |path| {
+            Ok(Box::new(FileSystemStorage::new(path.to_path_buf())?))
+        }),
+        ("sqlite", |path| Ok(Box::new(SqliteStorage::new(path)?))),
+        ("log", |path| Ok(Box::new(LogStorage::new(path)?))),
+    ];
+
+    let mut group = c.benchmark_group("edits");
+    group.sample_size(10);
+    for words in [50, 2000] {
+        let rounds = rounds(words);
+        for (name, open) in backends {
+            group.bench_function(BenchmarkId::new(name, words), |b| {
+                b.iter_batched(
+                    || {
+                        let dir = TempDir::new().unwrap();
+                        let storage = open(dir.path()).unwrap();
+                        for (id, bytes, _) in &rounds[0] {
+                            storage.set(id, bytes).unwrap();
+                        }
+                        (dir, storage)
+                    },
+                    |(_dir, storage)| {
+                        for round in &rounds[1..] {
+                            save(storage.as_ref(), round);
+                        }
+                    },
+                    BatchSize::PerIteration,
+                );
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_edits);
+criterion_main!(benches);
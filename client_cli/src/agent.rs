@@ -0,0 +1,240 @@
+//! A short-lived background process that holds the unlocked key in memory so
+//! each `qot` invocation does not have to ask for the passphrase again.
+//!
+//! `qot unlock` spawns `qot agent`, hands it the key over stdin and returns.
+//! The agent answers on a Unix socket in the data dir, readable only by the
+//! owner, and exits when its timeout passes or on `qot lock`. The key is
+//! never written to disk.
+
+use crdt_note::SealingKey;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+pub fn socket_path(base_path: &Path) -> PathBuf {
+    base_path.join("agent.sock")
+}
+
+/// Starts an agent holding `key` for `timeout`, replacing any running one.
+pub fn start(base_path: &Path, key: &SealingKey, timeout: Duration) -> Result<(), String> {
+    stop(base_path);
+
+    let socket = socket_path(base_path);
+    let exe = std::env::current_exe().map_err(|e| format!("{}", e))?;
+    let mut child = Command::new(exe)
+        .arg("agent")
+        .arg("--socket")
+        .arg(&socket)
+        .arg("--timeout")
+        .arg(timeout.as_secs().to_string())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|e| format!("Failed to start key agent: {}", e))?;
+
+    // Pass the key over a pipe, never on the command line or in the env
+    if let Some(mut stdin) = child.stdin.take() {
+        writeln!(stdin, "{}", to_hex(&key.to_bytes())).map_err(|e| format!("{}", e))?;
+    }
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while Instant::now() < deadline {
+        if fetch(base_path).is_some() {
+            return Ok(());
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+
+    Err("Key agent did not start".to_string())
+}
+
+/// The key held by a running agent, if any.
+pub fn fetch(base_path: &Path) -> Option<SealingKey> {
+    let reply = request(&socket_path(base_path), "KEY")?;
+    SealingKey::from_bytes(&from_hex(&reply)?).ok()
+}
+
+/// Stops a running agent. Returns whether one was running.
+pub fn stop(base_path: &Path) -> bool {
+    let socket = socket_path(base_path);
+    let stopped = request(&socket, "STOP").is_some();
+    std::fs::remove_file(&socket).ok();
+    stopped
+}
+
+/// The agent process itself: reads the key from stdin and serves it until
+/// the timeout passes or it is told to stop.
+pub fn run(socket: &Path, timeout: Duration) -> Result<(), String> {
+    let mut line = String::new();
+    std::io::stdin()
+        .read_line(&mut line)
+        .map_err(|e| format!("{}", e))?;
+    let key = from_hex(line.trim()).ok_or("Invalid key")?;
+    let deadline = Instant::now()
+        .checked_add(timeout)
+        .ok_or("Timeout too long")?;
+
+    let listener = bind_private(socket).map_err(|e| format!("{}", e))?;
+    serve(&listener, &key, deadline);
+    std::fs::remove_file(socket).ok();
+    Ok(())
+}
+
+/// Binds a Unix socket at `path`, replacing any there, that only the owner
+/// can connect to. It is bound in a directory only the owner can enter and
+/// made owner-only before it is moved into place, so no one else can
+/// connect in between.
+pub fn bind_private(path: &Path) -> std::io::Result<UnixListener> {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".{}", std::process::id()));
+    let dir = path.with_file_name(name);
+    std::fs::remove_dir_all(&dir).ok();
+    std::fs::DirBuilder::new().mode(0o700).create(&dir)?;
+
+    let bound = dir.join("socket");
+    let listener = UnixListener::bind(&bound).and_then(|listener| {
+        std::fs::set_permissions(&bound, std::fs::Permissions::from_mode(0o600))?;
+        std::fs::rename(&bound, path)?;
+        Ok(listener)
+    });
+    std::fs::remove_dir_all(&dir).ok();
+    listener
+}
+
+fn serve(listener: &UnixListener, key: &[u8], deadline: Instant) {
+    listener.set_nonblocking(true).ok();
+
+    while Instant::now() < deadline {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                if !answer(stream, key) {
+                    return;
+                }
+            }
+            Err(_) => std::thread::sleep(Duration::from_millis(50)),
+        }
+    }
+}
+
+// Returns false when the agent should stop
+fn answer(stream: UnixStream, key: &[u8]) -> bool {
+    stream.set_nonblocking(false).ok();
+    stream.set_read_timeout(Some(Duration::from_secs(1))).ok();
+
+    let mut reader = BufReader::new(&stream);
+    let mut command = String::new();
+    if reader.read_line(&mut command).is_err() {
+        return true;
+    }
+
+    let mut writer = &stream;
+    match command.trim() {
+        "KEY" => {
+            writeln!(writer, "{}", to_hex(key)).ok();
+            true
+        }
+        "STOP" => {
+            writeln!(writer, "OK").ok();
+            false
+        }
+        _ => true,
+    }
+}
+
+fn request(socket: &Path, command: &str) -> Option<String> {
+    let mut stream = UnixStream::connect(socket).ok()?;
+    stream.set_read_timeout(Some(Duration::from_secs(1))).ok();
+    writeln!(stream, "{}", command).ok()?;
+
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply).ok()?;
+    Some(reply.trim().to_string())
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_serve_fetch_and_stop() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let key = SealingKey::generate("correct horse").unwrap();
+        let envelope = key.seal(b"note bytes").unwrap();
+
+        let listener = UnixListener::bind(socket_path(temp_dir.path())).unwrap();
+        let key_bytes = key.to_bytes();
+        let agent = std::thread::spawn(move || {
+            serve(
+                &listener,
+                &key_bytes,
+                Instant::now() + Duration::from_secs(30),
+            )
+        });
+
+        let fetched = fetch(temp_dir.path()).unwrap();
+        assert_eq!(fetched.unseal(&envelope).unwrap(), b"note bytes");
+
+        assert!(stop(temp_dir.path()));
+        agent.join().unwrap();
+        assert!(fetch(temp_dir.path()).is_none());
+        assert!(!stop(temp_dir.path()));
+    }
+
+    #[test]
+    fn test_bound_socket_is_private_from_the_start() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let socket = socket_path(temp_dir.path());
+        std::fs::write(&socket, b"left over").unwrap();
+
+        let _listener = bind_private(&socket).unwrap();
+        let mode = std::fs::metadata(&socket).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(UnixStream::connect(&socket).is_ok());
+
+        // Only the socket is left behind
+        let names: Vec<_> = std::fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(names, ["agent.sock"]);
+    }
+
+    #[test]
+    fn test_serve_stops_after_timeout() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let listener = UnixListener::bind(socket_path(temp_dir.path())).unwrap();
+
+        serve(
+            &listener,
+            b"key",
+            Instant::now() + Duration::from_millis(100),
+        );
+    }
+
+    #[test]
+    fn test_hex_round_trip() {
+        let bytes = vec![0u8, 1, 127, 255];
+        assert_eq!(from_hex(&to_hex(&bytes)), Some(bytes));
+        assert_eq!(from_hex("abc"), None);
+        assert_eq!(from_hex("zz"), None);
+    }
+}
@@ -0,0 +1,284 @@
+use qot_client::Session;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+
+/// The server notes sync with, unless the `server` setting says otherwise
+pub const DEFAULT_SERVER: &str = "http://localhost:4000";
+
+/// What `session.json` holds: the server signed in to and its tokens
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StoredSession {
+    pub server: String,
+    pub session: Session,
+}
+
+/// The signed-in session. The tokens are as good as a password for the
+/// notes, so the file is readable by the owner only.
+pub struct SessionStore {
+    path: PathBuf,
+}
+
+impl SessionStore {
+    pub fn new(base_path: &Path) -> Self {
+        Self {
+            path: base_path.join("session.json"),
+        }
+    }
+
+    /// The current session, or `None` if not signed in.
+    pub fn load(&self) -> Result<Option<StoredSession>, String> {
+        match fs::read(&self.path) {
+            Ok(json) => serde_json::from_slice(&json)
+                .map(Some)
+                .map_err(|e| format!("{}", e)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(format!("{}", e)),
+        }
+    }
+
+    pub fn save(&self, stored: &StoredSession) -> Result<(), String> {
+        let json = serde_json::to_vec_pretty(stored).map_err(|e| format!("{}", e))?;
+        write_private(&self.path, &json)
+    }
+
+    /// Returns whether there was a session to remove.
+    pub fn clear(&self) -> Result<bool, String> {
+        match fs::remove_file(&self.path) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(format!("{}", e)),
+        }
+    }
+}
+
+/// A random value for the login callback to carry, so that only the link
+/// requested by this login can finish it.
+pub fn new_state() -> Result<String, String> {
+    let mut bytes = [0u8; 16];
+    getrandom::fill(&mut bytes).map_err(|e| format!("{}", e))?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// The path the login callback is served at, which carries `state`.
+pub fn callback_path(state: &str) -> String {
+    format!("/callback/{}", state)
+}
+
+/// Waits for the magic link token, from whichever comes first: the browser
+/// opening the link on `listener` with `state`, or the link or token pasted
+/// on stdin.
+pub fn wait_for_token(listener: TcpListener, state: String) -> Result<String, String> {
+    let (sender, tokens) = mpsc::channel();
+
+    let callback = sender.clone();
+    std::thread::spawn(move || {
+        if let Some(token) = accept_callback(&listener, &state) {
+            callback.send(token).ok();
+        }
+    });
+
+    // Nothing is left to wait for once stdin closes, unless the browser
+    // still might open the link
+    std::thread::spawn(move || {
+        let stdin = std::io::stdin();
+        let mut line = String::new();
+        while stdin.lock().read_line(&mut line).unwrap_or(0) > 0 {
+            if let Some(token) = token_from(&line) {
+                sender.send(token).ok();
+                return;
+            }
+            line.clear();
+        }
+    });
+
+    tokens
+        .recv()
+        .map_err(|_| "No token was received".to_string())
+}
+
+// Answers requests until one carries a token and `state`. Browsers may ask
+// for other paths, like a favicon, first, and a page elsewhere could send
+// the browser here with a token of its own.
+fn accept_callback(listener: &TcpListener, state: &str) -> Option<String> {
+    for stream in listener.incoming() {
+        let Ok(mut stream) = stream else {
+            continue;
+        };
+
+        let mut request_line = String::new();
+        BufReader::new(&stream).read_line(&mut request_line).ok()?;
+        let path = request_line.split_whitespace().nth(1).unwrap_or_default();
+        let token = token_from(path);
+        let from_this_login = path.split('?').next() == Some(&callback_path(state));
+
+        let (status, page) = match token {
+            Some(_) if from_this_login => ("200 OK", "Signed in. You can close this tab."),
+            Some(_) => ("403 Forbidden", "This link is not from this sign-in"),
+            None => ("404 Not Found", "Not found"),
+        };
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            page.len(),
+            page
+        )
+        .ok();
+
+        if token.is_some() && from_this_login {
+            return token;
+        }
+    }
+    None
+}
+
+/// The token in a magic link, a callback path, or a bare token.
+pub fn token_from(input: &str) -> Option<String> {
+    let input = input.trim();
+    if input.is_empty() {
+        return None;
+    }
+
+    match input.split_once('?') {
+        Some((_, query)) => query
+            .split('&')
+            .find_map(|pair| pair.strip_prefix("token="))
+            .filter(|token| !token.is_empty())
+            .map(decode),
+        None if input.contains('/') => None,
+        None => Some(input.to_string()),
+    }
+}
+
+// Magic link tokens are URL-safe base64, but a mail client may still
+// percent-encode them
+fn decode(value: &str) -> String {
+    let mut decoded = Vec::with_capacity(value.len());
+    let mut bytes = value.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte == b'%' {
+            let hex: Vec<u8> = bytes.by_ref().take(2).collect();
+            match std::str::from_utf8(&hex)
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            {
+                Some(byte) => decoded.push(byte),
+                None => {
+                    decoded.push(b'%');
+                    decoded.extend(hex);
+                }
+            }
+        } else {
+            decoded.push(byte);
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+// Only the owner can read the file, even if it already existed
+fn write_private(path: &Path, bytes: &[u8]) -> Result<(), String> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|e| format!("{}", e))?;
+    }
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    let mut file = options.open(path).map_err(|e| format!("{}", e))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(0o600))
+            .map_err(|e| format!("{}", e))?;
+    }
+    file.write_all(bytes).map_err(|e| format!("{}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use qot_client::User;
+    use std::io::Read;
+    use std::net::TcpStream;
+
+    fn stored() -> StoredSession {
+        StoredSession {
+            server: "http://localhost:4000".to_string(),
+            session: Session {
+                access_token: "access".to_string(),
+                refresh_token: "refresh".to_string(),
+                user: User {
+                    id: "user-1".to_string(),
+                    email: "ada@example.com".to_string(),
+                },
+            },
+        }
+    }
+
+    #[test]
+    fn test_save_load_and_clear() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let sessions = SessionStore::new(temp_dir.path());
+        assert_eq!(sessions.load().unwrap(), None);
+        assert!(!sessions.clear().unwrap());
+
+        sessions.save(&stored()).unwrap();
+        assert_eq!(sessions.load().unwrap(), Some(stored()));
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let path = temp_dir.path().join("session.json");
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+            sessions.save(&stored()).unwrap();
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        assert!(sessions.clear().unwrap());
+        assert_eq!(sessions.load().unwrap(), None);
+    }
+
+    #[test]
+    fn test_token_from() {
+        assert_eq!(token_from("abc123\n"), Some("abc123".to_string()));
+        assert_eq!(
+            token_from("http://localhost:5173/auth/verify?token=abc%2D123"),
+            Some("abc-123".to_string())
+        );
+        assert_eq!(
+            token_from("/callback?state=x&token=abc"),
+            Some("abc".to_string())
+        );
+        assert_eq!(token_from("/favicon.ico"), None);
+        assert_eq!(token_from("/callback?token="), None);
+        assert_eq!(token_from("  "), None);
+    }
+
+    #[test]
+    fn test_callback_receives_token() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let callback = std::thread::spawn(move || accept_callback(&listener, "s3cret"));
+
+        let get = |path: &str| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+
+        assert!(get("/favicon.ico").starts_with("HTTP/1.1 404"));
+        assert!(get("/callback?token=forged").starts_with("HTTP/1.1 403"));
+        assert!(get("/callback/guess?token=forged").starts_with("HTTP/1.1 403"));
+        assert!(get("/callback/s3cret?token=abc").contains("Signed in"));
+        assert_eq!(callback.join().unwrap(), Some("abc".to_string()));
+    }
+}
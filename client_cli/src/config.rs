@@ -0,0 +1,479 @@
+use crate::auth;
+use crate::storage::{self, Backend};
+use directories::ProjectDirs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use toml_edit::{DocumentMut, Item, Table, value};
+
+/// A setting `config.toml` may hold, and the variable that overrides it.
+pub struct Key {
+    pub name: &'static str,
+    pub env: &'static str,
+    pub default: Option<&'static str>,
+    pub about: &'static str,
+    check: fn(&str) -> Result<(), String>,
+}
+
+/// Every setting there is; any other key in the file is an error.
+pub const KEYS: &[Key] = &[
+    Key {
+        name: "server",
+        env: "QOT_SERVER",
+        default: Some(auth::DEFAULT_SERVER),
+        about: "Server to sign in to",
+        check: check_server,
+    },
+    Key {
+        name: "profile",
+        env: "QOT_PROFILE",
+        default: None,
+        about: "Profile to use when --profile is not given",
+        check: check_profile,
+    },
+    Key {
+        name: "editor",
+        env: "QOT_EDITOR",
+        default: None,
+        about: "Command 'qot edit' opens notes with (else $VISUAL, $EDITOR, vi)",
+        check: check_editor,
+    },
+    Key {
+        name: "storage",
+        env: "QOT_STORAGE",
+        default: Some("files"),
+        about: "Where notes are kept: files, sqlite or log (see 'qot migrate-storage')",
+        check: |value| value.parse::<Backend>().map(drop),
+    },
+    Key {
+        name: "list.sort",
+        env: "QOT_LIST_SORT",
+        default: Some("oldest"),
+        about: "Order of notes within each group: oldest or newest first",
+        check: |value| value.parse::<Sort>().map(drop),
+    },
+    Key {
+        name: "list.format",
+        env: "QOT_LIST_FORMAT",
+        default: Some("numbered"),
+        about: "How 'qot list' prints notes: numbered or plain",
+        check: |value| value.parse::<ListFormat>().map(drop),
+    },
+];
+
+/// Order of notes within the pinned, unpinned and archived groups.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Sort {
+    #[default]
+    Oldest,
+    Newest,
+}
+
+impl FromStr for Sort {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "oldest" => Ok(Sort::Oldest),
+            "newest" => Ok(Sort::Newest),
+            _ => Err(format!("'{}' is not a sort: use oldest or newest", s)),
+        }
+    }
+}
+
+/// How `qot list` prints notes.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ListFormat {
+    /// Index, markers and content, one note per line
+    #[default]
+    Numbered,
+    /// Content only, for piping into other tools
+    Plain,
+}
+
+impl FromStr for ListFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "numbered" => Ok(ListFormat::Numbered),
+            "plain" => Ok(ListFormat::Plain),
+            _ => Err(format!(
+                "'{}' is not a list format: use numbered or plain",
+                s
+            )),
+        }
+    }
+}
+
+fn check_server(value: &str) -> Result<(), String> {
+    if value.starts_with("http://") || value.starts_with("https://") {
+        Ok(())
+    } else {
+        Err(format!(
+            "'{}' is not a server URL: use http:// or https://",
+            value
+        ))
+    }
+}
+
+fn check_profile(value: &str) -> Result<(), String> {
+    if crate::service::is_profile_name(value) {
+        Ok(())
+    } else {
+        Err(format!(
+            "'{}' is not a profile name: use letters, digits, '-' and '_'",
+            value
+        ))
+    }
+}
+
+fn check_editor(value: &str) -> Result<(), String> {
+    if value.trim().is_empty() {
+        Err("The editor command cannot be empty".to_string())
+    } else {
+        Ok(())
+    }
+}
+
+/// Where a setting's value came from.
+#[derive(Debug, PartialEq)]
+pub enum Source {
+    Env(&'static str),
+    File,
+    Default,
+}
+
+/// The config file: `QOT_CONFIG` if set, else `config.toml` in the
+/// platform config directory.
+pub fn path() -> Result<PathBuf, String> {
+    match std::env::var_os("QOT_CONFIG") {
+        Some(path) => std::path::absolute(PathBuf::from(path)).map_err(|e| format!("{}", e)),
+        None => {
+            let proj_dirs =
+                ProjectDirs::from("", "", "qot").ok_or("Failed to determine config directory")?;
+            Ok(proj_dirs.config_dir().join("config.toml"))
+        }
+    }
+}
+
+/// Settings from the config file, with environment variables on top.
+/// Flags are applied by the commands that take them.
+pub struct Config {
+    path: PathBuf,
+    doc: DocumentMut,
+}
+
+impl Config {
+    /// Reads and checks the file. A missing file is an empty config.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let config = Self::read(path)?;
+        for (name, item) in config.entries() {
+            let key = key(&name).map_err(|e| format!("{} in {}", e, path.display()))?;
+            let text = item
+                .as_str()
+                .ok_or_else(|| format!("{} in {} must be a string", name, path.display()))?;
+            (key.check)(text).map_err(|e| format!("{} in {}: {}", name, path.display(), e))?;
+        }
+        Ok(config)
+    }
+
+    /// Reads the file without checking it, so `qot config set` can still
+    /// fix a file that `load` rejects.
+    pub fn read(path: &Path) -> Result<Self, String> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e)),
+        };
+        let doc = text
+            .parse::<DocumentMut>()
+            .map_err(|e| format!("Failed to parse {}: {}", path.display(), e))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            doc,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The value of `name`: its environment variable, else the file, else
+    /// the key's default.
+    pub fn get(&self, name: &str) -> Result<Option<String>, String> {
+        Ok(self.lookup(name)?.map(|(value, _)| value))
+    }
+
+    /// Like `get`, parsed.
+    pub fn parsed<T: FromStr<Err = String> + Default>(&self, name: &str) -> Result<T, String> {
+        match self.get(name)? {
+            Some(value) => value.parse(),
+            None => Ok(T::default()),
+        }
+    }
+
+    /// The value of `name` and where it came from, defaults included.
+    pub fn lookup(&self, name: &str) -> Result<Option<(String, Source)>, String> {
+        let key = key(name)?;
+        if let Ok(value) = std::env::var(key.env) {
+            (key.check)(&value).map_err(|e| format!("${}: {}", key.env, e))?;
+            return Ok(Some((value, Source::Env(key.env))));
+        }
+        if let Some(value) = self.file_value(name) {
+            return Ok(Some((value, Source::File)));
+        }
+        Ok(key
+            .default
+            .map(|value| (value.to_string(), Source::Default)))
+    }
+
+    /// Checks `value` and stores it under `name`; `save` writes it out.
+    pub fn set(&mut self, name: &str, text: &str) -> Result<(), String> {
+        let key = key(name)?;
+        (key.check)(text).map_err(|e| format!("{}: {}", name, e))?;
+
+        match name.split_once('.') {
+            // An inline table, as in `list = { format = "plain" }`, is
+            // edited in place
+            Some((section, field)) => {
+                match self.doc.get_mut(section).and_then(Item::as_table_like_mut) {
+                    Some(table) => {
+                        table.insert(field, value(text));
+                    }
+                    None => {
+                        let mut table = Table::new();
+                        table.insert(field, value(text));
+                        self.doc.insert(section, Item::Table(table));
+                    }
+                }
+            }
+            None => {
+                self.doc.insert(name, value(text));
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes `name` from the file, returning whether it was set.
+    pub fn unset(&mut self, name: &str) -> Result<bool, String> {
+        key(name)?;
+        let removed = match name.split_once('.') {
+            Some((section, field)) => self
+                .doc
+                .get_mut(section)
+                .and_then(Item::as_table_like_mut)
+                .and_then(|table| table.remove(field))
+                .is_some(),
+            None => self.doc.remove(name).is_some(),
+        };
+        Ok(removed)
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir).map_err(|e| format!("{}", e))?;
+        }
+        storage::write_atomic(&self.path, self.doc.to_string().as_bytes())
+            .map_err(|e| format!("Failed to write {}: {}", self.path.display(), e))
+    }
+
+    fn file_value(&self, name: &str) -> Option<String> {
+        let item = match name.split_once('.') {
+            Some((section, field)) => self.doc.get(section)?.get(field)?,
+            None => self.doc.get(name)?,
+        };
+        item.as_str().map(str::to_string)
+    }
+
+    // Every leaf in the file as a dotted name, tables included one level deep
+    fn entries(&self) -> Vec<(String, &Item)> {
+        let mut entries = Vec::new();
+        for (name, item) in self.doc.iter() {
+            match item.as_table_like() {
+                Some(table)
+                    if KEYS
+                        .iter()
+                        .any(|key| key.name.starts_with(&format!("{}.", name))) =>
+                {
+                    for (field, item) in table.iter() {
+                        entries.push((format!("{}.{}", name, field), item));
+                    }
+                }
+                _ => entries.push((name.to_string(), item)),
+            }
+        }
+        entries
+    }
+}
+
+fn key(name: &str) -> Result<&'static Key, String> {
+    KEYS.iter().find(|key| key.name == name).ok_or_else(|| {
+        let known: Vec<&str> = KEYS.iter().map(|key| key.name).collect();
+        match closest(name) {
+            Some(guess) => format!("Unknown key '{}' (did you mean '{}'?)", name, guess),
+            None => format!("Unknown key '{}' (known keys: {})", name, known.join(", ")),
+        }
+    })
+}
+
+// The known key a typo most likely meant, if any is close enough
+fn closest(name: &str) -> Option<&'static str> {
+    KEYS.iter()
+        .map(|key| (distance(name, key.name), key.name))
+        .filter(|(distance, _)| *distance <= 2)
+        .min()
+        .map(|(_, name)| name)
+}
+
+// Levenshtein distance
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut previous = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let current = row[j + 1];
+            row[j + 1] = if ca == *cb {
+                previous
+            } else {
+                1 + previous.min(row[j]).min(current)
+            };
+            previous = current;
+        }
+    }
+    row[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn config_with(text: &str) -> (TempDir, Result<Config, String>) {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("config.toml");
+        std::fs::write(&path, text).unwrap();
+        let config = Config::load(&path);
+        (dir, config)
+    }
+
+    #[test]
+    fn test_missing_file_is_empty() {
+        let dir = TempDir::new().unwrap();
+        let config = Config::load(&dir.path().join("config.toml")).unwrap();
+
+        assert_eq!(config.file_value("server"), None);
+        assert_eq!(config.file_value("list.sort"), None);
+    }
+
+    #[test]
+    fn test_reads_keys_and_sections() {
+        let (_dir, config) =
+            config_with("server = \"https://qot.example\"\n[list]\nsort = \"newest\"\n");
+        let config = config.unwrap();
+
+        assert_eq!(
+            config.file_value("server").as_deref(),
+            Some("https://qot.example")
+        );
+        assert_eq!(config.file_value("list.sort").as_deref(), Some("newest"));
+        assert_eq!(config.file_value("list.format"), None);
+    }
+
+    #[test]
+    fn test_unknown_key_suggests_the_closest() {
+        let (_dir, config) = config_with("[list]\nsrot = \"newest\"\n");
+        let error = config.err().unwrap();
+
+        assert!(error.contains("Unknown key 'list.srot'"), "{}", error);
+        assert!(error.contains("did you mean 'list.sort'?"), "{}", error);
+        assert!(error.contains("config.toml"), "{}", error);
+
+        let (_dir, config) = config_with("colour = \"blue\"\n");
+        let error = config.err().unwrap();
+        assert!(error.contains("known keys: server, profile"), "{}", error);
+    }
+
+    #[test]
+    fn test_invalid_values_are_rejected() {
+        let (_dir, config) = config_with("[list]\nformat = \"fancy\"\n");
+        let error = config.err().unwrap();
+        assert!(error.contains("list.format"), "{}", error);
+        assert!(error.contains("use numbered or plain"), "{}", error);
+
+        let (_dir, config) = config_with("server = 4000\n");
+        assert!(config.err().unwrap().contains("must be a string"));
+
+        let (_dir, config) = config_with("profile = \"../etc\"\n");
+        assert!(config.err().unwrap().contains("not a profile name"));
+    }
+
+    #[test]
+    fn test_set_keeps_comments_and_other_keys() {
+        let (dir, config) = config_with("# Where I sync\nserver = \"https://qot.example\"\n");
+        let mut config = config.unwrap();
+
+        config.set("list.sort", "newest").unwrap();
+        config.set("editor", "nano").unwrap();
+        assert!(config.set("list.sort", "sideways").is_err());
+        assert!(config.set("list.colour", "blue").is_err());
+        config.save().unwrap();
+
+        let text = std::fs::read_to_string(dir.path().join("config.toml")).unwrap();
+        assert!(text.starts_with("# Where I sync\n"), "{}", text);
+
+        let config = Config::load(&dir.path().join("config.toml")).unwrap();
+        assert_eq!(
+            config.file_value("server").as_deref(),
+            Some("https://qot.example")
+        );
+        assert_eq!(config.file_value("list.sort").as_deref(), Some("newest"));
+        assert_eq!(config.file_value("editor").as_deref(), Some("nano"));
+    }
+
+    #[test]
+    fn test_set_and_unset_in_an_inline_table() {
+        let (_dir, config) = config_with("list = { format = \"plain\" }\n");
+        let mut config = config.unwrap();
+
+        config.set("list.sort", "newest").unwrap();
+        assert_eq!(config.file_value("list.format").as_deref(), Some("plain"));
+        assert_eq!(config.file_value("list.sort").as_deref(), Some("newest"));
+        assert!(config.doc.to_string().starts_with("list = {"));
+
+        assert!(config.unset("list.format").unwrap());
+        assert_eq!(config.file_value("list.format"), None);
+        assert_eq!(config.file_value("list.sort").as_deref(), Some("newest"));
+    }
+
+    #[test]
+    fn test_unset() {
+        let (_dir, config) = config_with("editor = \"nano\"\n[list]\nsort = \"newest\"\n");
+        let mut config = config.unwrap();
+
+        assert!(config.unset("list.sort").unwrap());
+        assert!(config.unset("editor").unwrap());
+        assert!(!config.unset("editor").unwrap());
+        assert!(config.unset("nope").is_err());
+        assert_eq!(config.file_value("list.sort"), None);
+    }
+
+    #[test]
+    fn test_defaults_and_parsing() {
+        let (_dir, config) = config_with("[list]\nformat = \"plain\"\n");
+        let config = config.unwrap();
+
+        assert_eq!(config.file_value("list.format").as_deref(), Some("plain"));
+        assert_eq!("newest".parse::<Sort>(), Ok(Sort::Newest));
+        assert_eq!(ListFormat::default(), ListFormat::Numbered);
+    }
+
+    #[test]
+    fn test_distance() {
+        assert_eq!(distance("sort", "sort"), 0);
+        assert_eq!(distance("srot", "sort"), 2);
+        assert_eq!(distance("servr", "server"), 1);
+        assert_eq!(distance("", "abc"), 3);
+    }
+}
@@ -0,0 +1,182 @@
+use crdt_note::{ChangeStatus, DeviceKey};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// This device's signing key (`device.key`) and the public keys of the
+/// devices whose changes it trusts (`trusted_devices.json`). Signing is off
+/// until the device key is created with `qot device init`.
+pub struct DeviceStore {
+    key_path: PathBuf,
+    trusted_path: PathBuf,
+}
+
+impl DeviceStore {
+    pub fn new(base_path: &Path) -> Self {
+        Self {
+            key_path: base_path.join("device.key"),
+            trusted_path: base_path.join("trusted_devices.json"),
+        }
+    }
+
+    pub fn exists(&self) -> bool {
+        self.key_path.exists()
+    }
+
+    /// Creates the key for this device and trusts it.
+    pub fn init(&self, name: &str) -> Result<DeviceKey, String> {
+        if self.exists() {
+            return Err(format!(
+                "A device key already exists at {}",
+                self.key_path.display()
+            ));
+        }
+
+        let key = DeviceKey::generate()?;
+        write_private(&self.key_path, &key.to_bytes())?;
+        self.trust(&key.public_key(), name)?;
+
+        Ok(key)
+    }
+
+    /// This device's key, or `None` if signing is not set up.
+    pub fn device_key(&self) -> Result<Option<DeviceKey>, String> {
+        if !self.exists() {
+            return Ok(None);
+        }
+
+        let bytes = fs::read(&self.key_path).map_err(|e| format!("{}", e))?;
+        DeviceKey::from_bytes(&bytes).map(Some)
+    }
+
+    /// Trusted public keys and the names they were given.
+    pub fn trusted(&self) -> Result<BTreeMap<String, String>, String> {
+        match fs::read(&self.trusted_path) {
+            Ok(json) => serde_json::from_slice(&json).map_err(|e| format!("{}", e)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+            Err(e) => Err(format!("{}", e)),
+        }
+    }
+
+    pub fn trust(&self, public_key: &str, name: &str) -> Result<(), String> {
+        let public_key = public_key.trim().to_lowercase();
+        if public_key.len() != 64 || !public_key.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err("A device public key is 64 hex characters".to_string());
+        }
+
+        let mut trusted = self.trusted()?;
+        trusted.insert(public_key, name.to_string());
+        self.write_trusted(&trusted)
+    }
+
+    /// Returns whether the key was trusted.
+    pub fn untrust(&self, public_key: &str) -> Result<bool, String> {
+        let mut trusted = self.trusted()?;
+        let removed = trusted.remove(&public_key.trim().to_lowercase()).is_some();
+        self.write_trusted(&trusted)?;
+        Ok(removed)
+    }
+
+    fn write_trusted(&self, trusted: &BTreeMap<String, String>) -> Result<(), String> {
+        if let Some(parent) = self.trusted_path.parent() {
+            fs::create_dir_all(parent).map_err(|e| format!("{}", e))?;
+        }
+        let json = serde_json::to_vec_pretty(trusted).map_err(|e| format!("{}", e))?;
+        fs::write(&self.trusted_path, json).map_err(|e| format!("{}", e))
+    }
+}
+
+/// Whether every change in the note was signed by a trusted device.
+pub fn is_verified(note: &crdt_note::Note, trusted: &BTreeMap<String, String>) -> bool {
+    note.verify().iter().all(|change| match &change.status {
+        ChangeStatus::Signed { device } => trusted.contains_key(device),
+        ChangeStatus::Unsigned | ChangeStatus::BadSignature { .. } => false,
+    })
+}
+
+// The secret key is readable by the owner only
+fn write_private(path: &Path, bytes: &[u8]) -> Result<(), String> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|e| format!("{}", e))?;
+    }
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    let mut file = options.open(path).map_err(|e| format!("{}", e))?;
+    std::io::Write::write_all(&mut file, bytes).map_err(|e| format!("{}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_init_and_device_key() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let devices = DeviceStore::new(temp_dir.path());
+        assert!(devices.device_key().unwrap().is_none());
+
+        let key = devices.init("laptop").unwrap();
+        assert!(devices.init("laptop").is_err());
+
+        let loaded = devices.device_key().unwrap().unwrap();
+        assert_eq!(loaded.public_key(), key.public_key());
+        assert_eq!(
+            devices.trusted().unwrap().get(&key.public_key()),
+            Some(&"laptop".to_string())
+        );
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(temp_dir.path().join("device.key"))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+
+    #[test]
+    fn test_trust_and_untrust() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let devices = DeviceStore::new(temp_dir.path());
+        let phone = DeviceKey::generate().unwrap();
+
+        assert!(devices.trust("not a key", "phone").is_err());
+
+        devices
+            .trust(&phone.public_key().to_uppercase(), "phone")
+            .unwrap();
+        assert!(devices.trusted().unwrap().contains_key(&phone.public_key()));
+
+        assert!(devices.untrust(&phone.public_key()).unwrap());
+        assert!(!devices.untrust(&phone.public_key()).unwrap());
+        assert!(devices.trusted().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_is_verified() {
+        let laptop = DeviceKey::generate().unwrap();
+        let phone = DeviceKey::generate().unwrap();
+        let trusted = BTreeMap::from([(laptop.public_key(), "laptop".to_string())]);
+
+        let note = crdt_note::Note::new_signed("one", &laptop);
+        assert!(is_verified(&note, &trusted));
+
+        // Edited by a device nobody trusts
+        assert!(!is_verified(
+            &note.with_signer(&phone).update("two"),
+            &trusted
+        ));
+
+        // Edited without signing
+        assert!(!is_verified(
+            &crdt_note::Note::from(&crdt_note::Note::into(&note)).update("two"),
+            &trusted
+        ));
+    }
+}
@@ -0,0 +1,259 @@
+use crate::storage::{self, Backend, EncryptedStorage};
+use crdt_note::SealingKey;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+// Sealed with the key on init so unlock can tell a wrong passphrase apart
+// from a corrupt envelope without storing anything derived from the key.
+const CHECK_PLAINTEXT: &[u8] = b"qot key check";
+
+/// What `key.json` holds: the salt and a sealed check value. Neither the
+/// passphrase nor the derived key is ever written to disk.
+#[derive(Serialize, Deserialize)]
+struct KeyFile {
+    version: u8,
+    salt: Vec<u8>,
+    check: Vec<u8>,
+    /// Whether the notes on this device are encrypted with the key too
+    #[serde(default)]
+    at_rest: bool,
+    /// Whether every note written before that has been encrypted since
+    #[serde(default)]
+    at_rest_done: bool,
+}
+
+/// How far the notes on this device are encrypted with the key
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AtRest {
+    Off,
+    /// New writes are sealed; older notes may still be plaintext
+    Migrating,
+    /// Every note is sealed
+    On,
+}
+
+/// The passphrase-derived key that seals notes before they leave the device
+pub struct KeyStore {
+    path: PathBuf,
+}
+
+impl KeyStore {
+    pub fn new(base_path: &Path) -> Self {
+        Self {
+            path: base_path.join("key.json"),
+        }
+    }
+
+    pub fn exists(&self) -> bool {
+        self.path.exists()
+    }
+
+    pub fn init(&self, passphrase: &str) -> Result<SealingKey, String> {
+        if self.exists() {
+            return Err(format!("A key already exists at {}", self.path.display()));
+        }
+        if passphrase.is_empty() {
+            return Err("Passphrase cannot be empty".to_string());
+        }
+
+        let key = SealingKey::generate(passphrase).map_err(|e| format!("{}", e))?;
+        let key_file = KeyFile {
+            version: 1,
+            salt: key.salt(),
+            check: key.seal(CHECK_PLAINTEXT).map_err(|e| format!("{}", e))?,
+            at_rest: false,
+            at_rest_done: false,
+        };
+        self.write(&key_file)?;
+
+        Ok(key)
+    }
+
+    pub fn unlock(&self, passphrase: &str) -> Result<SealingKey, String> {
+        let key_file = self.read()?;
+
+        let key = SealingKey::derive(passphrase, &key_file.salt).map_err(|e| format!("{}", e))?;
+        match key.unseal(&key_file.check) {
+            Ok(check) if check == CHECK_PLAINTEXT => Ok(key),
+            _ => Err("Wrong passphrase".to_string()),
+        }
+    }
+
+    /// `Off` only when there is no key: a key file that cannot be read
+    /// is an error rather than a reason to read notes as plaintext.
+    pub fn encrypts_at_rest(&self) -> Result<AtRest, String> {
+        if !self.path.try_exists().map_err(|e| format!("{}", e))? {
+            return Ok(AtRest::Off);
+        }
+        let key_file = self.read()?;
+        Ok(match (key_file.at_rest, key_file.at_rest_done) {
+            (false, _) => AtRest::Off,
+            (true, false) => AtRest::Migrating,
+            (true, true) => AtRest::On,
+        })
+    }
+
+    pub fn set_encrypts_at_rest(&self) -> Result<(), String> {
+        let mut key_file = self.read()?;
+        key_file.at_rest = true;
+        self.write(&key_file)
+    }
+
+    /// Marks every note as encrypted, so a plaintext one is refused.
+    pub fn set_encrypted_at_rest(&self) -> Result<(), String> {
+        let mut key_file = self.read()?;
+        key_file.at_rest_done = true;
+        self.write(&key_file)
+    }
+
+    fn read(&self) -> Result<KeyFile, String> {
+        let json = fs::read(&self.path).map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => "No key yet. Create one with: qot key init".to_string(),
+            _ => format!("Could not read {}: {}", self.path.display(), e),
+        })?;
+        serde_json::from_slice(&json).map_err(|e| format!("{}", e))
+    }
+
+    fn write(&self, key_file: &KeyFile) -> Result<(), String> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent).map_err(|e| format!("{}", e))?;
+        }
+        let json = serde_json::to_vec_pretty(key_file).map_err(|e| format!("{}", e))?;
+        storage::write_atomic_private(&self.path, &json).map_err(|e| format!("{}", e))
+    }
+}
+
+/// The unlocked key: from the key agent if one is running, otherwise by
+/// asking for the passphrase.
+pub fn current_key(base_path: &Path) -> Result<SealingKey, String> {
+    #[cfg(unix)]
+    if let Some(key) = crate::agent::fetch(base_path) {
+        return Ok(key);
+    }
+
+    let passphrase = read_passphrase("Passphrase: ")?;
+    KeyStore::new(base_path).unlock(&passphrase)
+}
+
+/// Keeps an unlocked key in memory for `timeout` so later invocations can
+/// use it without the passphrase.
+#[cfg(unix)]
+pub fn cache(base_path: &Path, key: &SealingKey, timeout: Duration) -> Result<(), String> {
+    crate::agent::start(base_path, key, timeout)
+}
+
+#[cfg(not(unix))]
+pub fn cache(_base_path: &Path, _key: &SealingKey, _timeout: Duration) -> Result<(), String> {
+    Err(
+        "Keeping the key unlocked needs Unix sockets; the passphrase will be asked for each time"
+            .to_string(),
+    )
+}
+
+/// Forgets a cached key. Returns whether one was cached.
+pub fn forget(base_path: &Path) -> bool {
+    #[cfg(unix)]
+    return crate::agent::stop(base_path);
+
+    #[cfg(not(unix))]
+    false
+}
+
+/// Encrypts the notes already on this device and marks the store so every
+/// later write is encrypted too. Safe to run again if interrupted.
+pub fn encrypt_at_rest(base_path: &Path, backend: Backend) -> Result<usize, String> {
+    let keys = KeyStore::new(base_path);
+    let key = current_key(base_path)?;
+
+    // Mark first: an interrupted migration leaves a store that reads both
+    // sealed and plaintext notes and only writes sealed ones
+    keys.set_encrypts_at_rest()?;
+
+    let storage = backend.open(base_path).map_err(|e| format!("{}", e))?;
+    let encrypted = EncryptedStorage::new(storage, key)
+        .encrypt_in_place()
+        .map_err(|e| format!("{}", e))?;
+    keys.set_encrypted_at_rest()?;
+    Ok(encrypted)
+}
+
+/// Reads the passphrase from `QOT_PASSPHRASE` if set, otherwise prompts
+/// without echoing.
+pub fn read_passphrase(prompt: &str) -> Result<String, String> {
+    if let Ok(passphrase) = std::env::var("QOT_PASSPHRASE") {
+        return Ok(passphrase);
+    }
+
+    rpassword::prompt_password(prompt).map_err(|e| {
+        format!(
+            "Could not read passphrase ({}). Run 'qot unlock' or set QOT_PASSPHRASE",
+            e
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_init_and_unlock() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let keys = KeyStore::new(temp_dir.path());
+        assert!(!keys.exists());
+
+        let key = keys.init("correct horse").unwrap();
+        assert!(keys.exists());
+        assert!(keys.init("correct horse").is_err());
+
+        let sealed = key.seal(b"note bytes").unwrap();
+        let unlocked = keys.unlock("correct horse").unwrap();
+        assert_eq!(unlocked.unseal(&sealed).unwrap(), b"note bytes");
+
+        let result = keys.unlock("battery staple");
+        assert_eq!(result.err(), Some("Wrong passphrase".to_string()));
+    }
+
+    #[test]
+    fn test_unlock_without_key() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let keys = KeyStore::new(temp_dir.path());
+
+        assert!(
+            keys.unlock("anything")
+                .unwrap_err()
+                .contains("qot key init")
+        );
+    }
+
+    #[test]
+    fn test_encrypts_at_rest() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let keys = KeyStore::new(temp_dir.path());
+        assert_eq!(keys.encrypts_at_rest(), Ok(AtRest::Off));
+        assert!(keys.set_encrypts_at_rest().is_err());
+
+        keys.init("correct horse").unwrap();
+        assert_eq!(keys.encrypts_at_rest(), Ok(AtRest::Off));
+
+        keys.set_encrypts_at_rest().unwrap();
+        assert_eq!(keys.encrypts_at_rest(), Ok(AtRest::Migrating));
+        keys.set_encrypted_at_rest().unwrap();
+        assert_eq!(keys.encrypts_at_rest(), Ok(AtRest::On));
+        assert!(keys.unlock("correct horse").is_ok());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let path = temp_dir.path().join("key.json");
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        // A key file that cannot be read does not mean plaintext
+        fs::write(temp_dir.path().join("key.json"), b"not json").unwrap();
+        assert!(keys.encrypts_at_rest().is_err());
+    }
+}
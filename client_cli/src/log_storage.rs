@@ -0,0 +1,562 @@
+use crate::storage::{self, Storage, StorageError, StorageResult, Update};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+const MAGIC: &[u8; 8] = b"QOTLOG1\n";
+// The magic, then the generation, which compaction bumps so that other
+// processes know to read the log again from the start
+const HEADER_LEN: u64 = 16;
+// Length of the body that follows, a checksum of that length, then one of
+// the body. The length has its own so that a corrupt one is not mistaken
+// for a record torn off the end
+const FRAME_LEN: usize = 12;
+
+const SNAPSHOT: u8 = 1;
+const CHANGES: u8 = 2;
+const DELETE: u8 = 3;
+
+// A note with this many changes appended gets a fresh snapshot instead
+const MAX_CHANGES: usize = 64;
+// The log is rewritten once it is this big and twice the notes it holds
+const COMPACT_MIN_LEN: u64 = 1024 * 1024;
+
+/// The log `LogStorage` keeps every note in
+pub fn log_path(base_path: &Path) -> PathBuf {
+    base_path.join("notes.log")
+}
+
+/// Storage in one append-only log, `notes.log`.
+///
+/// `set` appends the whole value; `append` adds just the automerge changes
+/// made on top of a stored note, so syncing many small edits writes
+/// little. A note is read back as its last snapshot followed by the
+/// changes since, which automerge loads as one. Once the log is mostly
+/// history it is rewritten with one snapshot per note.
+///
+/// A record torn by a crash is cut off the end before the next write.
+/// Writers take an advisory lock on `notes.log.lock`; readers pick up what
+/// other processes appended as they go.
+pub struct LogStorage {
+    path: PathBuf,
+    lock_path: PathBuf,
+    state: RefCell<State>,
+    compact_min_len: u64,
+}
+
+#[derive(Default)]
+struct State {
+    generation: u64,
+    // How far the log has been read
+    end: u64,
+    // Each note's last snapshot and the changes appended since
+    notes: HashMap<String, Vec<Vec<u8>>>,
+}
+
+impl State {
+    fn value(&self, key: &str) -> Option<Vec<u8>> {
+        self.notes.get(key).map(|chunks| chunks.concat())
+    }
+
+    fn apply(&mut self, kind: u8, key: String, payload: Vec<u8>) {
+        match kind {
+            SNAPSHOT => {
+                self.notes.insert(key, vec![payload]);
+            }
+            CHANGES => self.notes.entry(key).or_default().push(payload),
+            _ => {
+                self.notes.remove(&key);
+            }
+        }
+    }
+
+    // What the log would take with one snapshot per note
+    fn live_len(&self) -> u64 {
+        self.notes
+            .iter()
+            .map(|(key, chunks)| {
+                let value: usize = chunks.iter().map(Vec::len).sum();
+                (FRAME_LEN + 3 + key.len() + value) as u64
+            })
+            .sum::<u64>()
+            + HEADER_LEN
+    }
+}
+
+impl LogStorage {
+    pub fn new(base_path: &Path) -> StorageResult<Self> {
+        fs::create_dir_all(base_path)?;
+        let path = log_path(base_path);
+        let storage = Self {
+            lock_path: path.with_extension("log.lock"),
+            path,
+            state: RefCell::new(State::default()),
+            compact_min_len: COMPACT_MIN_LEN,
+        };
+
+        let _lock = storage.lock()?;
+        if !storage.path.exists() {
+            storage::write_atomic(&storage.path, &header(0))?;
+        }
+        storage.refresh(true)?;
+        Ok(storage)
+    }
+
+    fn lock(&self) -> StorageResult<fs::File> {
+        Ok(storage::lock_file(&self.lock_path)?)
+    }
+
+    // Reads what was appended since last time, or the whole log again if
+    // it was compacted. With `repair`, which needs the lock, a torn record
+    // at the end is cut off; without it, reading stops short of it. A bad
+    // record with more after it is corruption rather than a crash, and an
+    // error
+    fn refresh(&self, repair: bool) -> StorageResult<()> {
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(repair)
+            .open(&self.path)?;
+        let mut state = self.state.borrow_mut();
+
+        let mut head = [0u8; HEADER_LEN as usize];
+        file.read_exact(&mut head)
+            .ok()
+            .filter(|_| &head[..8] == MAGIC)
+            .ok_or_else(|| {
+                StorageError::SerializationError(format!(
+                    "{} is not a qot log",
+                    self.path.display()
+                ))
+            })?;
+        let generation = u64::from_le_bytes(head[8..].try_into().unwrap());
+        if generation != state.generation || state.end == 0 {
+            *state = State {
+                generation,
+                end: HEADER_LEN,
+                ..State::default()
+            };
+        }
+
+        file.seek(SeekFrom::Start(state.end))?;
+        let mut rest = Vec::new();
+        file.read_to_end(&mut rest)?;
+
+        let mut offset = 0;
+        while let Some((kind, key, payload, len)) = parse_record(&rest[offset..]) {
+            state.apply(kind, key, payload);
+            offset += len;
+        }
+        state.end += offset as u64;
+
+        let tail = &rest[offset..];
+        if tail.is_empty() {
+            return Ok(());
+        }
+        if !is_torn(tail) {
+            return Err(StorageError::SerializationError(format!(
+                "{} is corrupt at byte {}",
+                self.path.display(),
+                state.end
+            )));
+        }
+        if repair {
+            file.set_len(state.end)?;
+            file.sync_all()?;
+        }
+        Ok(())
+    }
+
+    // Appends one record, holding the lock, and rewrites the log if that
+    // leaves it mostly history
+    fn push(&self, kind: u8, key: &str, payload: &[u8]) -> StorageResult<()> {
+        if key.len() > u16::MAX as usize {
+            return Err(StorageError::SerializationError(format!(
+                "key of {} bytes is too long for the log",
+                key.len()
+            )));
+        }
+        let bytes = record(kind, key, payload);
+
+        let mut file = fs::OpenOptions::new().append(true).open(&self.path)?;
+        file.write_all(&bytes)?;
+        file.sync_data()?;
+
+        let mut state = self.state.borrow_mut();
+        state.apply(kind, key.to_string(), payload.to_vec());
+        state.end += bytes.len() as u64;
+        let compact = state.end >= self.compact_min_len && state.end >= 2 * state.live_len();
+        drop(state);
+
+        if compact {
+            self.rewrite()?;
+        }
+        Ok(())
+    }
+
+    // One snapshot per note, dropping the history of changes and deleted
+    // notes
+    fn rewrite(&self) -> StorageResult<()> {
+        let mut state = self.state.borrow_mut();
+        let generation = state.generation + 1;
+
+        let mut bytes = header(generation).to_vec();
+        let mut notes = HashMap::new();
+        for (key, chunks) in &state.notes {
+            let value = chunks.concat();
+            let snapshot = match chunks.len() {
+                1 => value,
+                _ => crdt_note::compact(&value).unwrap_or(value),
+            };
+            bytes.extend_from_slice(&record(SNAPSHOT, key, &snapshot));
+            notes.insert(key.clone(), vec![snapshot]);
+        }
+        storage::write_atomic(&self.path, &bytes)?;
+
+        *state = State {
+            generation,
+            end: bytes.len() as u64,
+            notes,
+        };
+        Ok(())
+    }
+}
+
+impl Storage for LogStorage {
+    fn get(&self, key: &str) -> StorageResult<Option<Vec<u8>>> {
+        self.refresh(false)?;
+        Ok(self.state.borrow().value(key))
+    }
+
+    fn set(&self, key: &str, value: &[u8]) -> StorageResult<()> {
+        let _lock = self.lock()?;
+        self.refresh(true)?;
+        self.push(SNAPSHOT, key, value)
+    }
+
+    fn update(
+        &self,
+        key: &str,
+        update: &mut dyn FnMut(Option<Vec<u8>>) -> Update,
+    ) -> StorageResult<()> {
+        let _lock = self.lock()?;
+        self.refresh(true)?;
+        let current = self.state.borrow().value(key);
+        let value = update(current)?;
+        self.push(SNAPSHOT, key, &value)
+    }
+
+    fn append(&self, key: &str, changes: &[u8]) -> StorageResult<bool> {
+        let _lock = self.lock()?;
+        self.refresh(true)?;
+        let chunks = match self.state.borrow().notes.get(key) {
+            Some(chunks) => chunks.len(),
+            None => return Ok(false),
+        };
+        if changes.is_empty() {
+            return Ok(true);
+        }
+
+        // Past this many, reading the note back means applying a long run
+        // of changes, so it gets a fresh snapshot instead
+        if chunks >= MAX_CHANGES {
+            let mut value = self.state.borrow().value(key).unwrap_or_default();
+            value.extend_from_slice(changes);
+            let snapshot = crdt_note::compact(&value).unwrap_or(value);
+            self.push(SNAPSHOT, key, &snapshot)?;
+        } else {
+            self.push(CHANGES, key, changes)?;
+        }
+        Ok(true)
+    }
+
+    fn delete(&self, key: &str) -> StorageResult<()> {
+        let _lock = self.lock()?;
+        self.refresh(true)?;
+        if !self.state.borrow().notes.contains_key(key) {
+            return Ok(());
+        }
+        self.push(DELETE, key, &[])
+    }
+
+    fn list(&self) -> StorageResult<Vec<String>> {
+        self.refresh(false)?;
+        Ok(self.state.borrow().notes.keys().cloned().collect())
+    }
+}
+
+fn header(generation: u64) -> [u8; HEADER_LEN as usize] {
+    let mut header = [0u8; HEADER_LEN as usize];
+    header[..8].copy_from_slice(MAGIC);
+    header[8..].copy_from_slice(&generation.to_le_bytes());
+    header
+}
+
+// The frame, then the kind, key and payload
+fn record(kind: u8, key: &str, payload: &[u8]) -> Vec<u8> {
+    let mut body = Vec::with_capacity(3 + key.len() + payload.len());
+    body.push(kind);
+    body.extend_from_slice(&(key.len() as u16).to_le_bytes());
+    body.extend_from_slice(key.as_bytes());
+    body.extend_from_slice(payload);
+
+    let len = (body.len() as u32).to_le_bytes();
+    let mut record = Vec::with_capacity(FRAME_LEN + body.len());
+    record.extend_from_slice(&len);
+    record.extend_from_slice(&crc32fast::hash(&len).to_le_bytes());
+    record.extend_from_slice(&crc32fast::hash(&body).to_le_bytes());
+    record.extend_from_slice(&body);
+    record
+}
+
+// Whether a record that does not parse was cut short by a crash: its frame
+// is incomplete, its checked length runs to the end of the log, or the rest
+// is zeros the file grew by but never had written
+fn is_torn(tail: &[u8]) -> bool {
+    if tail.len() < FRAME_LEN || tail.iter().all(|b| *b == 0) {
+        return true;
+    }
+    frame_len(tail).is_some_and(|len| FRAME_LEN + len >= tail.len())
+}
+
+// The body length the frame at the start of `bytes` gives, if it matches
+// its checksum
+fn frame_len(bytes: &[u8]) -> Option<usize> {
+    let len = bytes.get(..4)?;
+    let checksum = u32::from_le_bytes(bytes.get(4..8)?.try_into().ok()?);
+    (crc32fast::hash(len) == checksum).then(|| u32::from_le_bytes(len.try_into().unwrap()) as usize)
+}
+
+// The record at the start of `bytes` and its length, or `None` if it is
+// incomplete or does not match its checksum
+fn parse_record(bytes: &[u8]) -> Option<(u8, String, Vec<u8>, usize)> {
+    let len = frame_len(bytes)?;
+    let checksum = u32::from_le_bytes(bytes.get(8..12)?.try_into().ok()?);
+    let body = bytes.get(FRAME_LEN..FRAME_LEN + len)?;
+    if crc32fast::hash(body) != checksum {
+        return None;
+    }
+
+    let kind = *body.first()?;
+    let key_len = u16::from_le_bytes(body.get(1..3)?.try_into().ok()?) as usize;
+    let key = String::from_utf8(body.get(3..3 + key_len)?.to_vec()).ok()?;
+    if !matches!(kind, SNAPSHOT | CHANGES | DELETE) {
+        return None;
+    }
+    Some((kind, key, body[3 + key_len..].to_vec(), FRAME_LEN + len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crdt_note::Note;
+    use tempfile::TempDir;
+
+    fn saved(note: &Note) -> Vec<u8> {
+        Note::into(note)
+    }
+
+    fn log_len(dir: &TempDir) -> u64 {
+        fs::metadata(log_path(dir.path())).unwrap().len()
+    }
+
+    #[test]
+    fn test_set_get_delete_and_reopen() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = LogStorage::new(temp_dir.path()).unwrap();
+
+        storage.set("note-1", b"first").unwrap();
+        storage.set("note-1", b"second").unwrap();
+        storage.set("note-2", b"other").unwrap();
+        storage.delete("note-2").unwrap();
+        storage.delete("note-3").unwrap();
+
+        let reopened = LogStorage::new(temp_dir.path()).unwrap();
+        assert_eq!(reopened.get("note-1").unwrap(), Some(b"second".to_vec()));
+        assert_eq!(reopened.list().unwrap(), ["note-1"]);
+    }
+
+    #[test]
+    fn test_edits_append_only_the_changes() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = LogStorage::new(temp_dir.path()).unwrap();
+        let mut note = Note::new("a note with some content to it");
+        storage.set(&note.id(), &saved(&note)).unwrap();
+        let first = log_len(&temp_dir);
+
+        let pinned = note.set_pinned(true);
+        let changes = pinned.changes_since(&note.heads());
+        assert!(storage.append(&pinned.id(), &changes).unwrap());
+        let appended = log_len(&temp_dir) - first;
+        assert!(appended < saved(&pinned).len() as u64);
+        note = pinned;
+
+        // Nothing new appends nothing, and there is nothing to append to
+        // for a note that is not stored
+        assert!(storage.append(&note.id(), &[]).unwrap());
+        assert_eq!(log_len(&temp_dir), first + appended);
+        assert!(!storage.append("missing", &changes).unwrap());
+
+        let reopened = LogStorage::new(temp_dir.path()).unwrap();
+        let loaded = Note::from(&reopened.get(&note.id()).unwrap().unwrap());
+        assert_eq!(loaded.heads(), note.heads());
+        assert!(loaded.pinned());
+    }
+
+    #[test]
+    fn test_torn_tail_is_cut_off() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = LogStorage::new(temp_dir.path()).unwrap();
+        storage.set("note-1", b"kept").unwrap();
+        let good = log_len(&temp_dir);
+
+        // A crash halfway through appending the next record
+        let torn = record(SNAPSHOT, "note-2", b"never finished");
+        let mut file = fs::OpenOptions::new()
+            .append(true)
+            .open(log_path(temp_dir.path()))
+            .unwrap();
+        file.write_all(&torn[..torn.len() - 3]).unwrap();
+
+        let reopened = LogStorage::new(temp_dir.path()).unwrap();
+        assert_eq!(log_len(&temp_dir), good);
+        assert_eq!(reopened.list().unwrap(), ["note-1"]);
+        reopened.set("note-2", b"written").unwrap();
+
+        let again = LogStorage::new(temp_dir.path()).unwrap();
+        assert_eq!(again.get("note-2").unwrap(), Some(b"written".to_vec()));
+    }
+
+    #[test]
+    fn test_corrupt_record_is_cut_off() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = LogStorage::new(temp_dir.path()).unwrap();
+        storage.set("note-1", b"kept").unwrap();
+        let good = log_len(&temp_dir);
+        storage.set("note-2", b"flipped").unwrap();
+
+        let path = log_path(temp_dir.path());
+        let mut bytes = fs::read(&path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        fs::write(&path, bytes).unwrap();
+
+        let reopened = LogStorage::new(temp_dir.path()).unwrap();
+        assert_eq!(log_len(&temp_dir), good);
+        assert_eq!(reopened.get("note-2").unwrap(), None);
+    }
+
+    #[test]
+    fn test_corruption_before_the_end_is_an_error() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = LogStorage::new(temp_dir.path()).unwrap();
+        storage.set("note-1", b"flipped").unwrap();
+        storage.set("note-2", b"after it").unwrap();
+
+        let path = log_path(temp_dir.path());
+        let mut bytes = fs::read(&path).unwrap();
+        let len = bytes.len();
+        bytes[HEADER_LEN as usize + FRAME_LEN + 3] ^= 0xff;
+        fs::write(&path, &bytes).unwrap();
+
+        // Nothing after the bad record is thrown away
+        assert!(matches!(
+            LogStorage::new(temp_dir.path()),
+            Err(StorageError::SerializationError(_))
+        ));
+        assert_eq!(log_len(&temp_dir), len as u64);
+    }
+
+    #[test]
+    fn test_corrupt_length_is_an_error() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = LogStorage::new(temp_dir.path()).unwrap();
+        storage.set("note-1", b"first").unwrap();
+        storage.set("note-2", b"second").unwrap();
+
+        // A length pointing past the end of the log, which on its own would
+        // look like a record torn off by a crash
+        let path = log_path(temp_dir.path());
+        let mut bytes = fs::read(&path).unwrap();
+        let len = bytes.len();
+        bytes[HEADER_LEN as usize + 3] ^= 0xff;
+        fs::write(&path, &bytes).unwrap();
+
+        assert!(matches!(
+            LogStorage::new(temp_dir.path()),
+            Err(StorageError::SerializationError(_))
+        ));
+        assert_eq!(log_len(&temp_dir), len as u64);
+    }
+
+    #[test]
+    fn test_zeros_past_the_last_record_are_cut_off() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = LogStorage::new(temp_dir.path()).unwrap();
+        storage.set("note-1", b"kept").unwrap();
+        let good = log_len(&temp_dir);
+
+        let file = fs::OpenOptions::new()
+            .append(true)
+            .open(log_path(temp_dir.path()))
+            .unwrap();
+        file.set_len(good + 64).unwrap();
+
+        let reopened = LogStorage::new(temp_dir.path()).unwrap();
+        assert_eq!(log_len(&temp_dir), good);
+        assert_eq!(reopened.get("note-1").unwrap(), Some(b"kept".to_vec()));
+    }
+
+    #[test]
+    fn test_compaction_keeps_every_note() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut storage = LogStorage::new(temp_dir.path()).unwrap();
+        let mut note = Note::new("edited");
+        storage.set(&note.id(), &saved(&note)).unwrap();
+        for i in 0..MAX_CHANGES + 10 {
+            let edited = note.update(&format!("edit {}", i));
+            let changes = edited.changes_since(&note.heads());
+            assert!(storage.append(&note.id(), &changes).unwrap());
+            note = edited;
+        }
+        assert!(storage.state.borrow().notes[&note.id()].len() <= MAX_CHANGES);
+
+        // Another process reading the log before it is compacted
+        let other = LogStorage::new(temp_dir.path()).unwrap();
+        assert_eq!(other.list().unwrap(), [note.id()]);
+        storage.set("gone", &[0; 4096]).unwrap();
+        let before = log_len(&temp_dir);
+
+        storage.compact_min_len = 0;
+        storage.delete("gone").unwrap();
+        assert!(log_len(&temp_dir) < before / 2);
+
+        let loaded = Note::from(&other.get(&note.id()).unwrap().unwrap());
+        assert_eq!(loaded.content(), format!("edit {}", MAX_CHANGES + 9));
+        assert_eq!(loaded.heads(), note.heads());
+        assert_eq!(other.list().unwrap(), [note.id()]);
+    }
+
+    #[test]
+    fn test_other_processes_see_appends() {
+        let temp_dir = TempDir::new().unwrap();
+        let one = LogStorage::new(temp_dir.path()).unwrap();
+        let two = LogStorage::new(temp_dir.path()).unwrap();
+
+        one.set("note-1", b"from one").unwrap();
+        assert_eq!(two.get("note-1").unwrap(), Some(b"from one".to_vec()));
+        two.delete("note-1").unwrap();
+        assert!(one.list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_not_a_log_is_an_error() {
+        let temp_dir = TempDir::new().unwrap();
+        fs::write(log_path(temp_dir.path()), b"something else entirely").unwrap();
+        assert!(matches!(
+            LogStorage::new(temp_dir.path()),
+            Err(StorageError::SerializationError(_))
+        ));
+    }
+}
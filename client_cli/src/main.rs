@@ -1,8 +1,38 @@
+#[cfg(unix)]
+mod agent;
+mod auth;
+mod config;
+mod devices;
+mod keys;
+mod log_storage;
+mod outbox;
+mod peer;
 mod service;
+mod sqlite_storage;
 mod storage;
+mod sync;
+#[cfg(unix)]
+mod watch;
 
+use auth::{SessionStore, StoredSession};
 use clap::{CommandFactory, Parser, Subcommand};
+use config::{Config, ListFormat};
+use devices::DeviceStore;
+use keys::KeyStore;
+use outbox::Outbox;
+use qot_client::HttpClient;
 use service::NoteService;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use storage::Backend;
+
+// How long `qot unlock` keeps the key in memory at most
+const MAX_UNLOCK_MINUTES: u64 = 7 * 24 * 60;
+
+fn unlock_minutes() -> clap::builder::RangedU64ValueParser {
+    clap::value_parser!(u64).range(1..=MAX_UNLOCK_MINUTES)
+}
 
 #[derive(Parser)]
 #[command(name = "qot")]
@@ -11,12 +41,24 @@ use service::NoteService;
     qot get milk          # Create a new note (implicit)\n  \
     qot add buy eggs      # Create a new note (explicit)\n  \
     qot list              # Show all notes\n  \
+    qot pin 3             # Keep note #3 at the top\n  \
+    qot archive 2         # Hide note #2 from the list\n  \
     qot delete 2          # Delete note #2")]
 #[command(version)]
 struct Cli {
     #[command(subcommand)]
     command: Option<Commands>,
 
+    /// Keep notes, keys and the session in this directory instead of the
+    /// platform data directory (or set $QOT_DATA_DIR)
+    #[arg(long, global = true, value_name = "DIR")]
+    data_dir: Option<PathBuf>,
+
+    /// Use a separate store with this name, e.g. work (or set $QOT_PROFILE,
+    /// or the 'profile' setting)
+    #[arg(long, global = true, value_name = "NAME")]
+    profile: Option<String>,
+
     /// Note content (when not using a subcommand)
     #[arg(trailing_var_arg = true, allow_hyphen_values = true, hide = true)]
     content: Vec<String>,
@@ -33,24 +75,253 @@ enum Commands {
     },
     /// List all notes with their indices
     #[command(visible_alias = "l")]
-    List,
+    List {
+        /// Also show archived notes
+        #[arg(long)]
+        archived: bool,
+        /// numbered or plain (defaults to the 'list.format' setting)
+        #[arg(long)]
+        format: Option<ListFormat>,
+    },
+    /// Change a note's content in your editor
+    #[command(visible_alias = "e")]
+    Edit {
+        /// The index number shown in 'qot list' (e.g., 1, 2, 3)
+        index: usize,
+    },
     /// Delete a note by its index number
     #[command(visible_alias = "d")]
     Delete {
         /// The index number shown in 'qot list' (e.g., 1, 2, 3)
         index: usize,
     },
+    /// Pin a note so it is listed first
+    Pin {
+        /// The index number shown in 'qot list' (e.g., 1, 2, 3)
+        index: usize,
+    },
+    /// Unpin a pinned note
+    Unpin {
+        /// The index number shown in 'qot list' (e.g., 1, 2, 3)
+        index: usize,
+    },
+    /// Archive a note, hiding it from 'qot list'
+    Archive {
+        /// The index number shown in 'qot list' (e.g., 1, 2, 3)
+        index: usize,
+    },
+    /// Restore an archived note
+    Unarchive {
+        /// The index number shown in 'qot list --archived'
+        index: usize,
+    },
+    /// Print every note, archived included, as JSON
+    Export,
+    /// Add notes from JSON written by 'qot export', keeping their ids
+    Import {
+        /// File to read (defaults to standard input)
+        file: Option<PathBuf>,
+    },
+    /// Manage the passphrase-derived key that encrypts synced notes
+    Key {
+        #[command(subcommand)]
+        command: KeyCommands,
+    },
+    /// Manage this device's signing key and the devices it trusts
+    Device {
+        #[command(subcommand)]
+        command: DeviceCommands,
+    },
+    /// Unlock the key and keep it in memory for a while (same as 'qot key unlock')
+    Unlock {
+        /// Minutes to keep the key unlocked, at most a week
+        #[arg(long, default_value_t = 15, value_parser = unlock_minutes())]
+        timeout: u64,
+    },
+    /// Forget the unlocked key
+    Lock,
+    /// Encrypt the notes stored on this device with the key
+    Encrypt,
+    /// Move the notes into another kind of storage and switch to it. Stop
+    /// any 'qot watch' first
+    MigrateStorage {
+        /// files, sqlite or log
+        #[arg(long)]
+        to: Backend,
+    },
+    /// Hold an unlocked key in memory (started by 'qot unlock')
+    #[command(hide = true)]
+    Agent {
+        #[arg(long)]
+        socket: PathBuf,
+        /// Seconds until the agent exits
+        #[arg(long, value_parser = clap::value_parser!(u64).range(1..=MAX_UNLOCK_MINUTES * 60))]
+        timeout: u64,
+    },
+    /// Pick a value for each field edited concurrently on different devices
+    Resolve {
+        /// The index number shown in 'qot list' (e.g., 1, 2, 3)
+        index: usize,
+    },
+    /// Sign in to the sync server with a link sent to your email
+    Login {
+        email: String,
+        /// Server URL (defaults to the 'server' setting)
+        #[arg(long)]
+        server: Option<String>,
+    },
+    /// Sign out and forget the session
+    Logout,
+    /// Show who is signed in
+    Whoami,
+    /// Merge notes with the server, both ways
+    Sync {
+        /// Sync with another device running 'qot serve' at host:port
+        /// instead, using the key in $QOT_PEER_KEY
+        #[arg(long)]
+        peer: Option<String>,
+    },
+    /// Merge the conflict copies a file sync tool left into their notes
+    Reconcile,
+    /// Let other devices sync with this one over the network, until stopped
+    Serve {
+        /// Address to listen on, e.g. 0.0.0.0:7777
+        #[arg(long)]
+        listen: String,
+    },
+    /// Show the sign-in and changes waiting to be pushed
+    Status,
+    /// Keep syncing with the server as notes change, until stopped
+    Watch {
+        /// Report on a running 'qot watch' instead of starting one
+        #[arg(long)]
+        status: bool,
+    },
+    /// Read and change the settings in config.toml (or $QOT_CONFIG)
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommands,
+    },
+}
+
+#[derive(Subcommand)]
+enum ConfigCommands {
+    /// Print the value a setting has, after environment overrides
+    Get { key: String },
+    /// Save a setting to the config file
+    Set { key: String, value: String },
+    /// Remove a setting from the config file
+    Unset { key: String },
+    /// Show every setting, its value and where the value comes from
+    List,
+}
+
+#[derive(Subcommand)]
+enum KeyCommands {
+    /// Create the key from a new passphrase
+    Init,
+    /// Unlock the key and keep it in memory for a while
+    Unlock {
+        /// Minutes to keep the key unlocked, at most a week
+        #[arg(long, default_value_t = 15, value_parser = unlock_minutes())]
+        timeout: u64,
+    },
+    /// Forget the unlocked key
+    Lock,
+}
+
+#[derive(Subcommand)]
+enum DeviceCommands {
+    /// Create this device's key and start signing changes
+    Init {
+        /// Name to list this device under
+        #[arg(long, default_value = "this device")]
+        name: String,
+    },
+    /// Print this device's public key, to trust it on other devices
+    Show,
+    /// List the trusted devices
+    List,
+    /// Trust changes signed by another device
+    Trust {
+        /// The public key shown by 'qot device show' on that device
+        public_key: String,
+        /// Name to list the device under
+        #[arg(long, default_value = "")]
+        name: String,
+    },
+    /// Stop trusting a device
+    Untrust { public_key: String },
 }
 
 fn main() {
     let cli = Cli::parse();
+    let exit = |e: String| -> ! {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    };
 
-    let mut note_service = NoteService::new().unwrap_or_else(|e| {
+    // Config commands must work on a config that does not load, to fix it
+    let config_path = config::path().unwrap_or_else(|e| exit(e));
+    if let Some(Commands::Config { command }) = cli.command {
+        return report(config_command(&config_path, command));
+    }
+    let config = Config::load(&config_path).unwrap_or_else(|e| exit(e));
+
+    let profile = match cli.profile {
+        Some(profile) => Some(profile),
+        None => config.get("profile").unwrap_or_else(|e| exit(e)),
+    };
+    let base_path = service::data_dir(cli.data_dir, profile).unwrap_or_else(|e| exit(e));
+    let base_path = base_path.as_path();
+    let backend: Backend = config.parsed("storage").unwrap_or_else(|e| exit(e));
+
+    // Key commands run before the note store is opened, since opening an
+    // encrypted store needs the key
+    match cli.command {
+        Some(Commands::Key { command }) => manage_key(base_path, command),
+        Some(Commands::Device { command }) => manage_devices(base_path, command),
+        Some(Commands::Unlock { timeout }) => {
+            manage_key(base_path, KeyCommands::Unlock { timeout })
+        }
+        Some(Commands::Lock) => manage_key(base_path, KeyCommands::Lock),
+        Some(Commands::Encrypt) => encrypt_notes(base_path, backend),
+        Some(Commands::MigrateStorage { to }) => {
+            report(migrate_storage(base_path, &config_path, to))
+        }
+        Some(Commands::Agent { socket, timeout }) => run_agent(&socket, timeout),
+        Some(Commands::Login { email, server }) => {
+            report(login(base_path, &config, &email, server))
+        }
+        Some(Commands::Logout) => report(logout(base_path)),
+        Some(Commands::Whoami) => report(whoami(base_path)),
+        Some(Commands::Status) => report(status(base_path)),
+        Some(Commands::Watch { status }) => run_watch(base_path, backend, status),
+        command => run_note_command(base_path, backend, &config, command, cli.content),
+    }
+}
+
+fn run_note_command(
+    base_path: &Path,
+    backend: Backend,
+    config: &Config,
+    command: Option<Commands>,
+    content: Vec<String>,
+) {
+    let mut note_service = NoteService::new(base_path, backend).unwrap_or_else(|e| {
         eprintln!("Failed to initialize service: {}", e);
         std::process::exit(1);
     });
+    // Every command sorts the same way, so indices match 'qot list'
+    match config.parsed("list.sort") {
+        Ok(sort) => note_service.set_sort(sort),
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    }
 
-    match cli.command {
+    match command {
         Some(Commands::Add { content }) => {
             if content.is_empty() {
                 eprintln!("Error: note content cannot be empty");
@@ -59,20 +330,74 @@ fn main() {
             let note_content = content.join(" ");
             create_note(&mut note_service, &note_content);
         }
-        Some(Commands::List) => {
-            list_notes(&mut note_service);
+        Some(Commands::List { archived, format }) => {
+            match format.map_or_else(|| config.parsed("list.format"), Ok) {
+                Ok(format) => list_notes(&mut note_service, archived, format),
+                Err(e) => report(Err(e)),
+            }
+        }
+        Some(Commands::Edit { index }) => {
+            report(edit_note(base_path, config, &mut note_service, index));
         }
         Some(Commands::Delete { index }) => {
             delete_note(&mut note_service, index);
         }
+        Some(Commands::Pin { index }) => {
+            pin_note(&mut note_service, index, true);
+        }
+        Some(Commands::Unpin { index }) => {
+            pin_note(&mut note_service, index, false);
+        }
+        Some(Commands::Archive { index }) => {
+            archive_note(&mut note_service, index, true);
+        }
+        Some(Commands::Unarchive { index }) => {
+            archive_note(&mut note_service, index, false);
+        }
+        Some(Commands::Export) => {
+            export_notes(&mut note_service);
+        }
+        Some(Commands::Import { file }) => {
+            import_notes(&mut note_service, file);
+        }
+        Some(Commands::Resolve { index }) => {
+            resolve_note(&mut note_service, index);
+        }
+        Some(Commands::Sync { peer: None }) => {
+            report(sync_notes(base_path, &mut note_service));
+        }
+        Some(Commands::Sync { peer: Some(peer) }) => {
+            report(sync_peer(&mut note_service, &peer));
+        }
+        Some(Commands::Reconcile) => {
+            report(reconcile_notes(&mut note_service));
+        }
+        Some(Commands::Serve { listen }) => {
+            serve_peers(&mut note_service, &listen);
+        }
+        Some(
+            Commands::Key { .. }
+            | Commands::Device { .. }
+            | Commands::Unlock { .. }
+            | Commands::Lock
+            | Commands::Encrypt
+            | Commands::MigrateStorage { .. }
+            | Commands::Agent { .. }
+            | Commands::Login { .. }
+            | Commands::Logout
+            | Commands::Whoami
+            | Commands::Status
+            | Commands::Watch { .. }
+            | Commands::Config { .. },
+        ) => unreachable!("handled before opening the note store"),
         None => {
             // No subcommand - treat as implicit note creation
-            if cli.content.is_empty() {
+            if content.is_empty() {
                 // Show help when no arguments provided
                 Cli::command().print_help().unwrap();
                 std::process::exit(0);
             }
-            let note_content = cli.content.join(" ");
+            let note_content = content.join(" ");
             create_note(&mut note_service, &note_content);
         }
     }
@@ -90,14 +415,39 @@ fn create_note(note_service: &mut NoteService, content: &str) {
     }
 }
 
-fn list_notes(note_service: &mut NoteService) {
+fn list_notes(note_service: &mut NoteService, show_archived: bool, format: ListFormat) {
     match note_service.list() {
         Ok(notes) => {
             if notes.is_empty() {
                 println!("No notes yet. Create one with: qot get milk");
+            } else if !show_archived && notes.iter().all(|note| note.archived) {
+                println!(
+                    "No notes besides {} archived. Show them with: qot list --archived",
+                    notes.len()
+                );
             } else {
+                // Archived notes are listed last, so skipping them keeps
+                // the indices of the remaining notes unchanged
+                let mut unverified = 0;
                 for (i, note) in notes.iter().enumerate() {
-                    println!("{}. {}", i + 1, note.content);
+                    if note.archived && !show_archived {
+                        continue;
+                    }
+                    if note.unverified {
+                        unverified += 1;
+                    }
+                    match format {
+                        ListFormat::Numbered => {
+                            println!("{}. {}{}", i + 1, note_marker(note), note.content)
+                        }
+                        ListFormat::Plain => println!("{}", note.content),
+                    }
+                }
+                if unverified > 0 {
+                    eprintln!(
+                        "Warning: {} note(s) have changes not signed by a trusted device",
+                        unverified
+                    );
                 }
             }
         }
@@ -108,6 +458,542 @@ fn list_notes(note_service: &mut NoteService) {
     }
 }
 
+fn note_marker(note: &service::Note) -> String {
+    let mut marker = String::new();
+    if note.unverified {
+        marker.push_str("[unverified] ");
+    }
+    if !note.conflicts.is_empty() {
+        marker.push_str("[conflict] ");
+    }
+    if note.archived {
+        marker.push_str("[archived] ");
+    } else if note.pinned {
+        marker.push_str("[pinned] ");
+    }
+    marker
+}
+
+fn pin_note(note_service: &mut NoteService, index: usize, pinned: bool) {
+    match note_service.set_pinned_by_index(index, pinned) {
+        Ok(note) => {
+            let action = if pinned { "Pinned" } else { "Unpinned" };
+            println!("{}: {}", action, note.content);
+        }
+        Err(e) => {
+            eprintln!("Error updating note: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn archive_note(note_service: &mut NoteService, index: usize, archived: bool) {
+    match note_service.set_archived_by_index(index, archived) {
+        Ok(note) => {
+            let action = if archived { "Archived" } else { "Unarchived" };
+            println!("{}: {}", action, note.content);
+        }
+        Err(e) => {
+            eprintln!("Error updating note: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn edit_note(
+    base_path: &Path,
+    config: &Config,
+    note_service: &mut NoteService,
+    index: usize,
+) -> Result<String, String> {
+    let editor = match config.get("editor")? {
+        Some(editor) => editor,
+        None => ["VISUAL", "EDITOR"]
+            .iter()
+            .find_map(|name| std::env::var(name).ok().filter(|v| !v.trim().is_empty()))
+            .unwrap_or_else(|| "vi".to_string()),
+    };
+    let note = note_service.note_at(index)?;
+
+    // The note in plaintext, so readable by the owner only, and kept in
+    // the data directory rather than a shared /tmp. The name is this edit's
+    // own, so another edit of the same note keeps its file
+    let mut nonce = [0u8; 8];
+    getrandom::fill(&mut nonce).map_err(|e| format!("{}", e))?;
+    let nonce: String = nonce.iter().map(|b| format!("{:02x}", b)).collect();
+    let file = base_path.join(format!(".edit-{}.{}.txt", note.id, nonce));
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+        .open(&file)
+        .and_then(|mut opened| writeln!(opened, "{}", note.content))
+        .map_err(|e| format!("{}", e))?;
+    let edited = run_editor(&editor, &file)
+        .and_then(|_| std::fs::read_to_string(&file).map_err(|e| format!("{}", e)));
+    let _ = std::fs::remove_file(&file);
+
+    let content = edited?.trim_end().to_string();
+    if content.is_empty() {
+        return Err("note content cannot be empty".to_string());
+    }
+    if content == note.content {
+        return Ok(format!("Unchanged: {}", note.content));
+    }
+    // Other processes may have moved the note in the list meanwhile
+    let note = note_service.edit(&note.id, &content)?;
+    Ok(format!("Edited note: {}", note.content))
+}
+
+// The editor may carry arguments, as in "code --wait"
+fn run_editor(editor: &str, file: &Path) -> Result<(), String> {
+    let mut parts = editor.split_whitespace();
+    let program = parts.next().ok_or("The editor command is empty")?;
+    let status = std::process::Command::new(program)
+        .args(parts)
+        .arg(file)
+        .status()
+        .map_err(|e| format!("Could not run '{}': {}", editor, e))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("'{}' exited with {}", editor, status))
+    }
+}
+
+fn config_command(path: &Path, command: ConfigCommands) -> Result<String, String> {
+    match command {
+        ConfigCommands::Get { key } => Ok(Config::load(path)?.get(&key)?.unwrap_or_default()),
+        ConfigCommands::Set { key, value } => {
+            let mut config = Config::read(path)?;
+            config.set(&key, &value)?;
+            config.save()?;
+            Ok(format!("Set {} = {}", key, value))
+        }
+        ConfigCommands::Unset { key } => {
+            let mut config = Config::read(path)?;
+            if config.unset(&key)? {
+                config.save()?;
+                Ok(format!("Unset {}", key))
+            } else {
+                Ok(format!("{} is not set in {}", key, path.display()))
+            }
+        }
+        ConfigCommands::List => {
+            let config = Config::load(path)?;
+            let mut lines = vec![format!("# {}", config.path().display())];
+            for key in config::KEYS {
+                let line = match config.lookup(key.name)? {
+                    Some((value, config::Source::Env(name))) => {
+                        format!("{} = {} (from ${})", key.name, value, name)
+                    }
+                    Some((value, config::Source::File)) => format!("{} = {}", key.name, value),
+                    Some((value, config::Source::Default)) => {
+                        format!("{} = {} (default)", key.name, value)
+                    }
+                    None => format!("{} is not set", key.name),
+                };
+                lines.push(format!("{:<40} # {}", line, key.about));
+            }
+            Ok(lines.join("\n"))
+        }
+    }
+}
+
+fn export_notes(note_service: &mut NoteService) {
+    let json = note_service
+        .export()
+        .and_then(|snapshots| serde_json::to_string_pretty(&snapshots).map_err(|e| e.to_string()));
+
+    match json {
+        Ok(json) => println!("{}", json),
+        Err(e) => {
+            eprintln!("Error exporting notes: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn import_notes(note_service: &mut NoteService, file: Option<PathBuf>) {
+    let json = match file {
+        Some(path) => std::fs::read_to_string(path),
+        None => std::io::read_to_string(std::io::stdin()),
+    };
+
+    let imported = json
+        .map_err(|e| e.to_string())
+        .and_then(|json| {
+            serde_json::from_str::<Vec<crdt_note::NoteSnapshot>>(&json).map_err(|e| e.to_string())
+        })
+        .and_then(|snapshots| note_service.import(&snapshots));
+
+    match imported {
+        Ok(count) => println!("Imported {} notes", count),
+        Err(e) => {
+            eprintln!("Error importing notes: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn manage_key(base_path: &Path, command: KeyCommands) {
+    report(key_command(base_path, command));
+}
+
+fn key_command(base_path: &Path, command: KeyCommands) -> Result<String, String> {
+    let keys = KeyStore::new(base_path);
+
+    match command {
+        KeyCommands::Init => {
+            let passphrase = keys::read_passphrase("New passphrase: ")?;
+            if std::env::var("QOT_PASSPHRASE").is_err()
+                && keys::read_passphrase("Repeat passphrase: ")? != passphrase
+            {
+                return Err("Passphrases do not match".to_string());
+            }
+            keys.init(&passphrase)
+                .map(|_| "Key created. Notes will be encrypted before syncing.".to_string())
+        }
+        KeyCommands::Unlock { timeout } => {
+            let passphrase = keys::read_passphrase("Passphrase: ")?;
+            let key = keys.unlock(&passphrase)?;
+            keys::cache(base_path, &key, Duration::from_secs(timeout * 60))
+                .map(|_| format!("Key unlocked for {} minutes", timeout))
+        }
+        KeyCommands::Lock => {
+            if keys::forget(base_path) {
+                Ok("Key locked".to_string())
+            } else {
+                Ok("Key was not unlocked".to_string())
+            }
+        }
+    }
+}
+
+fn manage_devices(base_path: &Path, command: DeviceCommands) {
+    report(device_command(&DeviceStore::new(base_path), command));
+}
+
+fn device_command(devices: &DeviceStore, command: DeviceCommands) -> Result<String, String> {
+    match command {
+        DeviceCommands::Init { name } => devices.init(&name).map(|key| {
+            format!(
+                "Device key created. Changes made here are now signed.\nPublic key: {}",
+                key.public_key()
+            )
+        }),
+        DeviceCommands::Show => match devices.device_key()? {
+            Some(key) => Ok(key.public_key()),
+            None => Err("No device key yet. Create one with: qot device init".to_string()),
+        },
+        DeviceCommands::List => {
+            let trusted = devices.trusted()?;
+            if trusted.is_empty() {
+                return Ok("No trusted devices".to_string());
+            }
+            Ok(trusted
+                .iter()
+                .map(|(public_key, name)| format!("{}  {}", public_key, name))
+                .collect::<Vec<_>>()
+                .join("\n"))
+        }
+        DeviceCommands::Trust { public_key, name } => devices
+            .trust(&public_key, &name)
+            .map(|_| "Device trusted".to_string()),
+        DeviceCommands::Untrust { public_key } => {
+            if devices.untrust(&public_key)? {
+                Ok("Device no longer trusted".to_string())
+            } else {
+                Ok("Device was not trusted".to_string())
+            }
+        }
+    }
+}
+
+fn encrypt_notes(base_path: &Path, backend: Backend) {
+    match keys::encrypt_at_rest(base_path, backend) {
+        Ok(count) => println!("Encrypted {} notes", count),
+        Err(e) => {
+            eprintln!("Error encrypting notes: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn migrate_storage(base_path: &Path, config_path: &Path, to: Backend) -> Result<String, String> {
+    let mut moved = 0;
+    if let Some(from) = to.displaced_by(base_path) {
+        let source = from.open(base_path).map_err(|e| format!("{}", e))?;
+        if !source
+            .conflict_copies()
+            .map_err(|e| format!("{}", e))?
+            .is_empty()
+        {
+            return Err("Merge the conflict copies first with: qot reconcile".to_string());
+        }
+        let target = to.open(base_path).map_err(|e| format!("{}", e))?;
+        moved = storage::migrate(&source, &target).map_err(|e| format!("{}", e))?;
+        drop(source);
+        from.remove(base_path).map_err(|e| format!("{}", e))?;
+    }
+
+    let mut config = Config::read(config_path)?;
+    config.set("storage", &to.to_string())?;
+    config.save()?;
+    let mut message = format!("Moved {} notes to {} storage", moved, to);
+    if std::env::var_os("QOT_STORAGE").is_some() {
+        message.push_str(". Note that $QOT_STORAGE overrides the storage setting");
+    }
+    Ok(message)
+}
+
+#[cfg(unix)]
+fn run_agent(socket: &std::path::Path, timeout: u64) {
+    if let Err(e) = agent::run(socket, Duration::from_secs(timeout)) {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(not(unix))]
+fn run_agent(_socket: &std::path::Path, _timeout: u64) {
+    eprintln!("Error: the key agent needs Unix sockets");
+    std::process::exit(1);
+}
+
+fn login(
+    base_path: &Path,
+    config: &Config,
+    email: &str,
+    server: Option<String>,
+) -> Result<String, String> {
+    let sessions = SessionStore::new(base_path);
+    let server = match server {
+        Some(server) => server,
+        None => config.get("server")?.unwrap_or_default(),
+    };
+    let mut client = HttpClient::new(&server);
+
+    // The link opens a one-shot listener here, so following it on this
+    // computer finishes the login without pasting anything
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").map_err(|e| format!("{}", e))?;
+    let port = listener.local_addr().map_err(|e| format!("{}", e))?.port();
+    let state = auth::new_state()?;
+    let redirect_uri = format!("http://127.0.0.1:{}{}", port, auth::callback_path(&state));
+    client
+        .request_magic_link_to(email, &redirect_uri)
+        .map_err(|e| format!("Could not request a sign-in link: {}", e))?;
+    eprintln!(
+        "A sign-in link was sent to {}. Open it on this computer, or paste the link or token here:",
+        email
+    );
+
+    let token = auth::wait_for_token(listener, state)?;
+    let session = client.verify(&token).map_err(|e| match e {
+        qot_client::ClientError::Unauthorized => "The link is invalid or has expired".to_string(),
+        e => format!("Could not sign in: {}", e),
+    })?;
+    sessions.save(&StoredSession {
+        server,
+        session: session.clone(),
+    })?;
+
+    Ok(format!("Logged in as {}", session.user.email))
+}
+
+fn logout(base_path: &Path) -> Result<String, String> {
+    let sessions = SessionStore::new(base_path);
+    let Some(stored) = sessions.load()? else {
+        return Ok("Not logged in".to_string());
+    };
+
+    // The local tokens go either way; revoking them is best effort
+    let mut client = HttpClient::new(&stored.server);
+    client.set_session(Some(stored.session));
+    if let Err(e) = client.logout() {
+        eprintln!("Warning: could not revoke the session on the server: {}", e);
+    }
+    sessions.clear()?;
+
+    Ok("Logged out".to_string())
+}
+
+fn whoami(base_path: &Path) -> Result<String, String> {
+    match SessionStore::new(base_path).load()? {
+        Some(stored) => Ok(format!(
+            "{} ({}) on {}",
+            stored.session.user.email, stored.session.user.id, stored.server
+        )),
+        None => Err("Not logged in. Sign in with: qot login <email>".to_string()),
+    }
+}
+
+fn status(base_path: &Path) -> Result<String, String> {
+    let mut lines = vec![match SessionStore::new(base_path).load()? {
+        Some(stored) => format!(
+            "Logged in as {} on {}",
+            stored.session.user.email, stored.server
+        ),
+        None => "Not logged in".to_string(),
+    }];
+
+    let pending = Outbox::new(base_path).pending()?;
+    lines.push(format!("Changes waiting to be pushed: {}", pending.len()));
+
+    // The first line of a running watch's report says whether it is
+    // connected; the count above already covers the rest
+    #[cfg(unix)]
+    lines.push(match watch::status(base_path) {
+        Ok(watching) => format!("Watch: {}", watching.lines().next().unwrap_or_default()),
+        Err(e) => format!("Watch: {}", e),
+    });
+    Ok(lines.join("\n"))
+}
+
+fn sync_notes(base_path: &Path, note_service: &mut NoteService) -> Result<String, String> {
+    let sessions = SessionStore::new(base_path);
+    let Some(stored) = sessions.load()? else {
+        return Err("Not logged in. Sign in with: qot login <email>".to_string());
+    };
+
+    // Notes are sealed before they leave the device once a key is set up
+    let key = if KeyStore::new(base_path).exists() {
+        Some(keys::current_key(base_path)?)
+    } else {
+        None
+    };
+
+    let mut client = HttpClient::new(&stored.server);
+    client.set_session(Some(stored.session.clone()));
+    let result = sync::sync(
+        note_service,
+        &mut client,
+        &sync::SyncState::new(base_path, &stored.server),
+        key.as_ref(),
+    );
+
+    // Keep the access token if it was refreshed
+    if let Some(session) = client.session()
+        && *session != stored.session
+    {
+        sessions.save(&StoredSession {
+            server: stored.server.clone(),
+            session: session.clone(),
+        })?;
+    }
+
+    let report = result.map_err(|e| format!("Sync failed: {}", e))?;
+    if report.skipped > 0 {
+        eprintln!(
+            "Warning: skipped {} server note(s) that could not be read",
+            report.skipped
+        );
+    }
+    Ok(format!(
+        "Synced with {}: {} created, {} updated, {} merged, {} deleted",
+        stored.server, report.created, report.updated, report.merged, report.deleted
+    ))
+}
+
+fn sync_peer(note_service: &mut NoteService, address: &str) -> Result<String, String> {
+    let key = peer::read_peer_key()?;
+    let report = peer::sync_with_peer(note_service, address, &key)
+        .map_err(|e| format!("Sync failed: {}", e))?;
+    Ok(format!(
+        "Synced with {}: {} updated here, {} updated there",
+        address, report.updated_here, report.updated_there
+    ))
+}
+
+fn reconcile_notes(note_service: &mut NoteService) -> Result<String, String> {
+    match note_service.reconcile()? {
+        0 => Ok("No conflict copies found".to_string()),
+        merged => Ok(format!("Merged {} conflict copies", merged)),
+    }
+}
+
+fn serve_peers(note_service: &mut NoteService, address: &str) {
+    let listener = peer::read_peer_key().and_then(|key| {
+        let listener = std::net::TcpListener::bind(address)
+            .map_err(|e| format!("Could not listen on {}: {}", address, e))?;
+        Ok((key, listener))
+    });
+    let (key, listener) = listener.unwrap_or_else(|e| {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    });
+
+    match listener.local_addr() {
+        Ok(address) => println!("Listening on {}", address),
+        Err(_) => println!("Listening on {}", address),
+    }
+    peer::serve(note_service, &listener, &key);
+}
+
+fn report(result: Result<String, String>) {
+    match result {
+        Ok(message) => println!("{}", message),
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn run_watch(base_path: &Path, backend: Backend, status: bool) {
+    if status {
+        report(watch::status(base_path));
+    } else if let Err(e) = watch::run(base_path, backend) {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(not(unix))]
+fn run_watch(_base_path: &Path, _backend: Backend, _status: bool) {
+    eprintln!("Error: qot watch needs Unix sockets and signals");
+    std::process::exit(1);
+}
+
+fn resolve_note(note_service: &mut NoteService, index: usize) {
+    let (note, conflicts) = note_service.conflicts_by_index(index).unwrap_or_else(|e| {
+        eprintln!("Error resolving note: {}", e);
+        std::process::exit(1);
+    });
+
+    if conflicts.is_empty() {
+        println!("Note {} has no conflicts", index);
+        return;
+    }
+
+    let stdin = std::io::stdin();
+    for (field, values) in conflicts {
+        println!("Conflicting values for '{}':", field);
+        for (i, value) in values.iter().enumerate() {
+            println!("  {}. {}", i + 1, value);
+        }
+        print!("Keep which value? ");
+        std::io::stdout().flush().ok();
+
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 {
+            eprintln!("Error resolving note: no choice given");
+            std::process::exit(1);
+        }
+        let choice = line.trim().parse::<usize>().unwrap_or(0);
+
+        match note_service.resolve(&note.id, &field, choice) {
+            Ok(_) => println!("Resolved '{}'", field),
+            Err(e) => {
+                eprintln!("Error resolving note: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
 fn delete_note(note_service: &mut NoteService, index: usize) {
     match note_service.delete_by_index(index) {
         Ok(content) => {
@@ -0,0 +1,193 @@
+use crate::storage;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// A change made on this device that the server has not seen yet
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum Operation {
+    /// The note was created or edited, up to `heads`
+    Upsert {
+        id: String,
+        heads: Vec<String>,
+    },
+    Delete {
+        id: String,
+    },
+}
+
+impl Operation {
+    pub fn id(&self) -> &str {
+        match self {
+            Operation::Upsert { id, .. } | Operation::Delete { id } => id,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    pub seq: u64,
+    #[serde(flatten)]
+    pub operation: Operation,
+}
+
+/// Pending operations in `outbox.jsonl`, one JSON entry per line in the
+/// order they were made. Entries are appended and synced to disk before the
+/// command that made them returns, and removed once the server has the
+/// change. Replaying an entry again is harmless: an upsert pushes whatever
+/// the note holds now, and deleting twice is the same as once.
+pub struct Outbox {
+    path: PathBuf,
+}
+
+impl Outbox {
+    pub fn new(base_path: &Path) -> Self {
+        Self {
+            path: base_path.join("outbox.jsonl"),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn record(&self, operation: Operation) -> Result<(), String> {
+        let _lock = self.lock()?;
+        let text = self.read()?;
+        let seq = parse(&text).last().map_or(1, |entry| entry.seq + 1);
+
+        // Start on a fresh line if the last append was cut short
+        let mut line = String::new();
+        if !text.is_empty() && !text.ends_with('\n') {
+            line.push('\n');
+        }
+        line.push_str(
+            &serde_json::to_string(&Entry { seq, operation }).map_err(|e| format!("{}", e))?,
+        );
+        line.push('\n');
+
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(|e| format!("{}", e))?;
+        file.write_all(line.as_bytes())
+            .and_then(|_| file.sync_data())
+            .map_err(|e| format!("{}", e))
+    }
+
+    /// Entries in the order they were recorded. A line cut short by a crash
+    /// mid-append is skipped.
+    pub fn pending(&self) -> Result<Vec<Entry>, String> {
+        Ok(parse(&self.read()?))
+    }
+
+    /// Removes entries the server now has.
+    pub fn acknowledge(&self, done: &[Entry]) -> Result<(), String> {
+        let _lock = self.lock()?;
+        let pending = self.pending()?;
+        let remaining: Vec<&Entry> = pending
+            .iter()
+            .filter(|entry| !done.contains(entry))
+            .collect();
+        if remaining.len() == pending.len() {
+            return Ok(());
+        }
+
+        let mut text = String::new();
+        for entry in remaining {
+            text.push_str(&serde_json::to_string(entry).map_err(|e| format!("{}", e))?);
+            text.push('\n');
+        }
+
+        // Swap the file in whole, so a crash leaves the old or new list
+        storage::write_atomic(&self.path, text.as_bytes()).map_err(|e| format!("{}", e))
+    }
+
+    // Other processes append while this one rewrites, so they take turns
+    fn lock(&self) -> Result<fs::File, String> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent).map_err(|e| format!("{}", e))?;
+        }
+        storage::lock_file(&self.path.with_extension("lock")).map_err(|e| format!("{}", e))
+    }
+
+    fn read(&self) -> Result<String, String> {
+        match fs::read_to_string(&self.path) {
+            Ok(text) => Ok(text),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(String::new()),
+            Err(e) => Err(format!("{}", e)),
+        }
+    }
+}
+
+fn parse(text: &str) -> Vec<Entry> {
+    text.lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upsert(id: &str) -> Operation {
+        Operation::Upsert {
+            id: id.to_string(),
+            heads: vec!["abc".to_string()],
+        }
+    }
+
+    #[test]
+    fn test_record_and_acknowledge_in_order() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let outbox = Outbox::new(temp_dir.path());
+        assert!(outbox.pending().unwrap().is_empty());
+
+        outbox.record(upsert("a")).unwrap();
+        outbox
+            .record(Operation::Delete {
+                id: "b".to_string(),
+            })
+            .unwrap();
+        outbox.record(upsert("c")).unwrap();
+
+        let pending = outbox.pending().unwrap();
+        let ids: Vec<&str> = pending.iter().map(|entry| entry.operation.id()).collect();
+        assert_eq!(ids, ["a", "b", "c"]);
+        assert_eq!(pending[2].seq, 3);
+
+        outbox.acknowledge(&pending[..2]).unwrap();
+        assert_eq!(outbox.pending().unwrap(), &pending[2..]);
+
+        // Acknowledging again changes nothing
+        outbox.acknowledge(&pending[..2]).unwrap();
+        assert_eq!(outbox.pending().unwrap().len(), 1);
+
+        // Sequence numbers keep counting up
+        outbox.record(upsert("d")).unwrap();
+        assert_eq!(outbox.pending().unwrap()[1].seq, 4);
+    }
+
+    #[test]
+    fn test_torn_line_is_skipped() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let outbox = Outbox::new(temp_dir.path());
+        outbox.record(upsert("a")).unwrap();
+
+        let path = temp_dir.path().join("outbox.jsonl");
+        let mut text = fs::read_to_string(&path).unwrap();
+        text.push_str("{\"seq\":2,\"op\":\"ups");
+        fs::write(&path, text).unwrap();
+
+        assert_eq!(outbox.pending().unwrap().len(), 1);
+
+        // The next entry is not lost to the torn one
+        outbox.record(upsert("b")).unwrap();
+        let pending = outbox.pending().unwrap();
+        assert_eq!(pending.len(), 2);
+        assert_eq!(pending[1].operation.id(), "b");
+    }
+}
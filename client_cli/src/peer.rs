@@ -0,0 +1,435 @@
+//! `qot serve` and `qot sync --peer`: syncs notes straight between two
+//! devices over TCP, with no server in between.
+//!
+//! Both sides must know the same pre-shared peer key. The server opens with
+//! a random challenge, the client answers with its own challenge and an
+//! HMAC-SHA256 of both under the key, and the server proves the key back.
+//! No notes are exchanged before both sides have proved it. The connection
+//! itself is not encrypted, so use it on networks you trust.
+//!
+//! Each side then sends the heads of every note it has. Notes whose heads
+//! differ are synced one at a time, in id order, with the automerge sync
+//! protocol: the client sends a message, the server answers, and a note is
+//! done once a round passes with nothing to say either way. Only the
+//! changes the other side is missing cross the wire.
+//!
+//! Deleting a note is not shared: a note deleted on one side comes back
+//! from the other.
+//!
+//! Every message is a frame: a big-endian u32 length, then that many bytes.
+//! An empty frame means nothing to send. The server ends with one more empty
+//! frame once it has saved what it received.
+
+use crate::service::{NoteService, is_note_id};
+use crdt_note::PeerState;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+const VERSION: u8 = 1;
+const NONCE_LEN: usize = 32;
+const MAX_FRAME_LEN: usize = 64 * 1024 * 1024;
+// A peer that stops answering should not hold up the other side for good
+const TIMEOUT: Duration = Duration::from_secs(30);
+// The protocol settles a note in a few rounds; more means something is off
+const MAX_ROUNDS: usize = 64;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// What one peer sync changed.
+#[derive(Debug, Default, PartialEq)]
+pub struct PeerReport {
+    /// Notes that changed on this side
+    pub updated_here: usize,
+    /// Notes that changed on the peer
+    pub updated_there: usize,
+}
+
+/// Reads the peer key from `QOT_PEER_KEY` if set, otherwise prompts.
+pub fn read_peer_key() -> Result<String, String> {
+    let key = match std::env::var("QOT_PEER_KEY") {
+        Ok(key) => key,
+        Err(_) => rpassword::prompt_password("Peer key: ").map_err(|e| {
+            format!(
+                "Could not read the peer key ({}). Set QOT_PEER_KEY instead",
+                e
+            )
+        })?,
+    };
+    if key.is_empty() {
+        return Err("The peer key cannot be empty".to_string());
+    }
+    Ok(key)
+}
+
+/// Answers peers on `listener` one at a time, until the process is stopped.
+pub fn serve(service: &mut NoteService, listener: &TcpListener, key: &str) {
+    for stream in listener.incoming() {
+        let Ok(stream) = stream else {
+            continue;
+        };
+        let peer = stream
+            .peer_addr()
+            .map(|addr| addr.to_string())
+            .unwrap_or_else(|_| "unknown peer".to_string());
+
+        match serve_connection(service, stream, key) {
+            Ok(report) => println!(
+                "Synced with {}: {} updated here, {} updated there",
+                peer, report.updated_here, report.updated_there
+            ),
+            Err(e) => eprintln!("Error syncing with {}: {}", peer, e),
+        }
+    }
+}
+
+/// Syncs with a peer that is running `qot serve` at `address`.
+pub fn sync_with_peer(
+    service: &mut NoteService,
+    address: &str,
+    key: &str,
+) -> Result<PeerReport, String> {
+    let mut stream = TcpStream::connect(address)
+        .map_err(|e| format!("Could not connect to {}: {}", address, e))?;
+    set_timeouts(&stream)?;
+
+    let hello = read_frame(&mut stream)?;
+    let server_nonce = match hello.split_first() {
+        Some((&VERSION, nonce)) if nonce.len() == NONCE_LEN => nonce.to_vec(),
+        Some((&VERSION, _)) | None => return Err("The peer sent a malformed greeting".to_string()),
+        Some((version, _)) => {
+            return Err(format!("The peer speaks protocol version {}", version));
+        }
+    };
+
+    let client_nonce = nonce()?;
+    let mut answer = client_nonce.clone();
+    answer.extend(
+        proof(key, b"qot peer client", &server_nonce, &client_nonce)
+            .finalize()
+            .into_bytes(),
+    );
+    write_frame(&mut stream, &answer)?;
+
+    let server_proof = read_frame(&mut stream)?;
+    if server_proof.is_empty() {
+        return Err("The peer rejected the key".to_string());
+    }
+    proof(key, b"qot peer server", &client_nonce, &server_nonce)
+        .verify_slice(&server_proof)
+        .map_err(|_| "The peer could not prove it has the key".to_string())?;
+
+    sync_notes(service, &mut stream, Side::Client)
+}
+
+fn serve_connection(
+    service: &mut NoteService,
+    mut stream: TcpStream,
+    key: &str,
+) -> Result<PeerReport, String> {
+    set_timeouts(&stream)?;
+
+    let server_nonce = nonce()?;
+    let mut hello = vec![VERSION];
+    hello.extend(&server_nonce);
+    write_frame(&mut stream, &hello)?;
+
+    let answer = read_frame(&mut stream)?;
+    if answer.len() <= NONCE_LEN {
+        return Err("Malformed answer to the challenge".to_string());
+    }
+    let (client_nonce, client_proof) = answer.split_at(NONCE_LEN);
+    if proof(key, b"qot peer client", &server_nonce, client_nonce)
+        .verify_slice(client_proof)
+        .is_err()
+    {
+        write_frame(&mut stream, &[]).ok();
+        return Err("Wrong peer key".to_string());
+    }
+
+    let server_proof = proof(key, b"qot peer server", client_nonce, &server_nonce);
+    write_frame(&mut stream, &server_proof.finalize().into_bytes())?;
+
+    sync_notes(service, &mut stream, Side::Server)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Side {
+    // Speaks first in every round
+    Client,
+    Server,
+}
+
+fn sync_notes(
+    service: &mut NoteService,
+    stream: &mut TcpStream,
+    side: Side,
+) -> Result<PeerReport, String> {
+    let ours = heads(service)?;
+    let json = serde_json::to_vec(&ours).map_err(|e| format!("{}", e))?;
+    let theirs = match side {
+        Side::Client => {
+            write_frame(stream, &json)?;
+            read_frame(stream)?
+        }
+        Side::Server => {
+            let theirs = read_frame(stream)?;
+            write_frame(stream, &json)?;
+            theirs
+        }
+    };
+    let theirs: BTreeMap<String, Vec<String>> =
+        serde_json::from_slice(&theirs).map_err(|e| format!("Malformed note list: {}", e))?;
+    if let Some(id) = theirs.keys().find(|id| !is_note_id(id)) {
+        return Err(format!("The peer sent an invalid note id: {:?}", id));
+    }
+
+    // Both sides work out the same list, so they go through it in step
+    let differ: BTreeSet<&String> = ours
+        .keys()
+        .chain(theirs.keys())
+        .filter(|id| ours.get(*id) != theirs.get(*id))
+        .collect();
+
+    let mut report = PeerReport::default();
+    for id in differ {
+        let note = match service.get(id)? {
+            Some(note) if ours.contains_key(id) => note,
+            _ => crdt_note::Note::blank(),
+        };
+        let synced = sync_note(stream, side, note)?;
+        if synced.id() != *id {
+            return Err(format!("The peer sent a different note for {}", id));
+        }
+
+        let synced_heads = synced.heads();
+        if ours.get(id) != Some(&synced_heads) {
+            service.save(synced)?;
+            report.updated_here += 1;
+        }
+        if theirs.get(id) != Some(&synced_heads) {
+            report.updated_there += 1;
+        }
+    }
+
+    // The client only reports success once the server has saved too
+    match side {
+        Side::Client => {
+            read_frame(stream)?;
+        }
+        Side::Server => write_frame(stream, &[])?,
+    }
+    Ok(report)
+}
+
+// Takes turns with the peer until a round passes with nothing to send
+fn sync_note(
+    stream: &mut TcpStream,
+    side: Side,
+    mut note: crdt_note::Note,
+) -> Result<crdt_note::Note, String> {
+    let mut state = PeerState::new();
+
+    for _ in 0..MAX_ROUNDS {
+        let (sent, received) = match side {
+            Side::Client => {
+                let sent = note.sync_message(&mut state).unwrap_or_default();
+                write_frame(stream, &sent)?;
+                let received = read_frame(stream)?;
+                if !received.is_empty() {
+                    note = note.receive_sync_message(&mut state, &received)?;
+                }
+                (sent, received)
+            }
+            Side::Server => {
+                let received = read_frame(stream)?;
+                if !received.is_empty() {
+                    note = note.receive_sync_message(&mut state, &received)?;
+                }
+                let sent = note.sync_message(&mut state).unwrap_or_default();
+                write_frame(stream, &sent)?;
+                (sent, received)
+            }
+        };
+
+        if sent.is_empty() && received.is_empty() {
+            return Ok(note);
+        }
+    }
+
+    Err(format!("Note {} did not settle", note.id()))
+}
+
+// The heads of every readable note
+fn heads(service: &NoteService) -> Result<BTreeMap<String, Vec<String>>, String> {
+    let mut heads = BTreeMap::new();
+    for id in service.ids()? {
+        // A note cut short mid-write is left for the peer to fill in
+        if let Some(note) = service.get(&id)?
+            && note.id() == id
+        {
+            heads.insert(id, note.heads());
+        }
+    }
+    Ok(heads)
+}
+
+fn proof(key: &str, label: &[u8], first: &[u8], second: &[u8]) -> HmacSha256 {
+    let mut mac =
+        HmacSha256::new_from_slice(key.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(label);
+    mac.update(first);
+    mac.update(second);
+    mac
+}
+
+fn nonce() -> Result<Vec<u8>, String> {
+    let mut nonce = vec![0; NONCE_LEN];
+    getrandom::fill(&mut nonce).map_err(|e| format!("{}", e))?;
+    Ok(nonce)
+}
+
+fn set_timeouts(stream: &TcpStream) -> Result<(), String> {
+    stream
+        .set_read_timeout(Some(TIMEOUT))
+        .and_then(|_| stream.set_write_timeout(Some(TIMEOUT)))
+        .map_err(|e| format!("{}", e))
+}
+
+fn write_frame(stream: &mut impl Write, bytes: &[u8]) -> Result<(), String> {
+    let len = u32::try_from(bytes.len()).map_err(|_| "Message too large".to_string())?;
+    stream
+        .write_all(&len.to_be_bytes())
+        .and_then(|_| stream.write_all(bytes))
+        .and_then(|_| stream.flush())
+        .map_err(|e| format!("Connection to the peer failed: {}", e))
+}
+
+fn read_frame(stream: &mut impl Read) -> Result<Vec<u8>, String> {
+    let mut len = [0; 4];
+    stream
+        .read_exact(&mut len)
+        .map_err(|e| format!("Connection to the peer failed: {}", e))?;
+
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(format!("The peer sent a {} byte message, too large", len));
+    }
+
+    let mut bytes = vec![0; len];
+    stream
+        .read_exact(&mut bytes)
+        .map_err(|e| format!("Connection to the peer failed: {}", e))?;
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::test_service;
+    use std::path::Path;
+
+    // Serves one connection from the store in `server_dir` on a thread,
+    // then syncs `client` against it
+    fn sync_pair(
+        server_dir: &Path,
+        server_key: &str,
+        client: &mut NoteService,
+        client_key: &str,
+    ) -> (Result<PeerReport, String>, Result<PeerReport, String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server_dir = server_dir.to_path_buf();
+        let server_key = server_key.to_string();
+        let serving = std::thread::spawn(move || {
+            let mut server = test_service(&server_dir);
+            let (stream, _) = listener.accept().unwrap();
+            serve_connection(&mut server, stream, &server_key)
+        });
+
+        let client_result = sync_with_peer(client, &address, client_key);
+        (client_result, serving.join().unwrap())
+    }
+
+    fn contents(service: &mut NoteService) -> Vec<String> {
+        let mut contents: Vec<String> = service
+            .list()
+            .unwrap()
+            .into_iter()
+            .map(|note| note.content)
+            .collect();
+        contents.sort();
+        contents
+    }
+
+    #[test]
+    fn test_peers_converge() {
+        let server_dir = tempfile::tempdir().unwrap();
+        let client_dir = tempfile::tempdir().unwrap();
+        let mut client = test_service(client_dir.path());
+
+        test_service(server_dir.path())
+            .create("from the server")
+            .unwrap();
+        client.create("from the client").unwrap();
+
+        let (client_result, server_result) =
+            sync_pair(server_dir.path(), "hunter2", &mut client, "hunter2");
+        let report = client_result.unwrap();
+        assert_eq!(
+            report,
+            PeerReport {
+                updated_here: 1,
+                updated_there: 1
+            }
+        );
+        assert_eq!(server_result.unwrap(), report);
+
+        let both = vec!["from the client", "from the server"];
+        assert_eq!(contents(&mut client), both);
+        assert_eq!(contents(&mut test_service(server_dir.path())), both);
+
+        // Concurrent edits to the same note merge
+        let id = client.ids().unwrap()[0].clone();
+        let here = client.get(&id).unwrap().unwrap().set_pinned(true);
+        client.save(here).unwrap();
+        let mut server = test_service(server_dir.path());
+        let there = server.get(&id).unwrap().unwrap().set_archived(true);
+        server.save(there).unwrap();
+
+        let (client_result, _) = sync_pair(server_dir.path(), "hunter2", &mut client, "hunter2");
+        assert_eq!(client_result.unwrap().updated_here, 1);
+        let merged = client.get(&id).unwrap().unwrap();
+        assert!(merged.pinned() && merged.archived());
+        assert_eq!(merged.heads(), server.get(&id).unwrap().unwrap().heads());
+
+        // Nothing left to do
+        let (client_result, _) = sync_pair(server_dir.path(), "hunter2", &mut client, "hunter2");
+        assert_eq!(client_result.unwrap(), PeerReport::default());
+    }
+
+    #[test]
+    fn test_wrong_key_is_rejected() {
+        let server_dir = tempfile::tempdir().unwrap();
+        let client_dir = tempfile::tempdir().unwrap();
+        let mut client = test_service(client_dir.path());
+        test_service(server_dir.path()).create("secret").unwrap();
+
+        let (client_result, server_result) =
+            sync_pair(server_dir.path(), "hunter2", &mut client, "guess");
+        assert_eq!(client_result, Err("The peer rejected the key".to_string()));
+        assert_eq!(server_result, Err("Wrong peer key".to_string()));
+        assert!(client.ids().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_note_ids_stay_in_the_notes_directory() {
+        assert!(is_note_id("0190a1b2-c3d4-7e5f-8a9b-0c1d2e3f4a5b"));
+        assert!(!is_note_id("../session"));
+        assert!(!is_note_id("notes/x"));
+        assert!(!is_note_id(""));
+    }
+}
@@ -1,91 +1,369 @@
-use crate::storage::{FileSystemStorage, Storage};
+use crate::config::Sort;
+use crate::devices::{self, DeviceStore};
+use crate::keys::{self, AtRest, KeyStore};
+use crate::outbox::{self, Operation, Outbox};
+use crate::storage::{Backend, EncryptedStorage, Storage, StorageError};
+use crdt_note::{DeviceKey, IdGenerator, SystemIdGenerator};
 use directories::ProjectDirs;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
 
 // Simple view struct for Note data
 #[derive(Clone, Debug)]
 pub struct Note {
     pub id: String,
     pub content: String,
+    pub pinned: bool,
+    pub archived: bool,
+    pub conflicts: Vec<String>,
+    /// Has changes not signed by a trusted device. Only checked once this
+    /// device signs its own changes.
+    pub unverified: bool,
 }
 
+impl From<&crdt_note::Note> for Note {
+    fn from(crdt_note: &crdt_note::Note) -> Self {
+        Self {
+            id: crdt_note.id(),
+            content: crdt_note.content(),
+            pinned: crdt_note.pinned(),
+            archived: crdt_note.archived(),
+            conflicts: crdt_note.conflicts(),
+            unverified: false,
+        }
+    }
+}
+
+/// A conflicted field and its competing values
+pub type Conflict = (String, Vec<String>);
+
 pub struct NoteService {
     notes: HashMap<String, crdt_note::Note>,
-    storage: FileSystemStorage,
+    storage: Box<dyn Storage>,
+    ids: Box<dyn IdGenerator>,
+    // This device's key and the devices it trusts, when signing is set up
+    signer: Option<DeviceKey>,
+    trusted: BTreeMap<String, String>,
+    // Changes the server has not seen yet
+    outbox: Outbox,
+    sort: Sort,
+}
+
+/// The directory notes, keys and the session live under: `dir` if given,
+/// else `QOT_DATA_DIR`, else the platform data directory. A profile is a
+/// separate store in `profiles/<name>` under it.
+pub fn data_dir(dir: Option<PathBuf>, profile: Option<String>) -> Result<PathBuf, String> {
+    let dir = match dir.or_else(|| std::env::var_os("QOT_DATA_DIR").map(PathBuf::from)) {
+        Some(dir) => std::path::absolute(&dir).map_err(|e| format!("{}", e))?,
+        None => {
+            // Determine storage path using ProjectDirs
+            let proj_dirs =
+                ProjectDirs::from("", "", "qot").ok_or("Failed to determine storage directory")?;
+            proj_dirs.data_dir().to_path_buf()
+        }
+    };
+
+    match profile {
+        Some(name) if is_profile_name(&name) => Ok(dir.join("profiles").join(name)),
+        Some(name) => Err(format!(
+            "Invalid profile name '{}': use letters, digits, '-' and '_'",
+            name
+        )),
+        None => Ok(dir),
+    }
+}
+
+// Profile names become directory names, so they must not reach outside
+pub fn is_profile_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+// Ids name files in the notes directory, so ones from elsewhere must not
+// reach outside it
+pub fn is_note_id(id: &str) -> bool {
+    !id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
 }
 
 impl NoteService {
-    pub fn new() -> Result<Self, String> {
-        // Determine storage path using ProjectDirs
-        let proj_dirs =
-            ProjectDirs::from("", "", "qot").ok_or("Failed to determine storage directory")?;
-        let base_path = proj_dirs.data_dir().to_path_buf();
+    pub fn new(base_path: &Path, backend: Backend) -> Result<Self, String> {
+        // Opening the wrong backend would show an empty store
+        if let Some(other) = backend.displaced_by(base_path) {
+            return Err(format!(
+                "The notes here are kept in {}, but the storage setting is {}. \
+                 Set it back or run: qot migrate-storage --to {}",
+                other, backend, backend
+            ));
+        }
+
+        let storage = backend.open(base_path).map_err(|e| format!("{}", e))?;
+        let storage: Box<dyn Storage> = match KeyStore::new(base_path).encrypts_at_rest()? {
+            AtRest::Off => Box::new(storage),
+            AtRest::Migrating => Box::new(EncryptedStorage::new(
+                storage,
+                keys::current_key(base_path)?,
+            )),
+            AtRest::On => Box::new(
+                EncryptedStorage::new(storage, keys::current_key(base_path)?).sealed_only(),
+            ),
+        };
 
-        let storage = FileSystemStorage::new(base_path).map_err(|e| format!("{}", e))?;
+        let devices = DeviceStore::new(base_path);
 
         Ok(Self {
             notes: HashMap::new(),
             storage,
+            ids: Box::new(SystemIdGenerator),
+            signer: devices.device_key()?,
+            trusted: devices.trusted()?,
+            outbox: Outbox::new(base_path),
+            sort: Sort::default(),
         })
     }
 
     pub fn create(&mut self, content: &str) -> Result<Note, String> {
         // Create note using crdt_note
-        let crdt_note = crdt_note::Note::new(content);
-        let note_id = crdt_note.id();
-        let note_content = crdt_note.content();
+        let crdt_note = match &self.signer {
+            Some(key) => crdt_note::Note::generate_signed(self.ids.as_mut(), content, key),
+            None => crdt_note::Note::generate(self.ids.as_mut(), content),
+        };
 
         // Validate the note was created successfully
-        if note_id.is_empty() {
+        if crdt_note.id().is_empty() {
             return Err("Failed to create note".to_string());
         }
 
-        // Persist to storage
-        let bytes = crdt_note::Note::into(&crdt_note);
-        self.storage
-            .set(&note_id, &bytes)
-            .map_err(|e| format!("{}", e))?;
-
-        // Store in memory
-        let crdt_note_copy = crdt_note::Note::from(&bytes);
-        self.notes.insert(note_id.clone(), crdt_note_copy);
+        self.save(crdt_note)
+    }
 
-        Ok(Note {
-            id: note_id,
-            content: note_content,
-        })
+    /// Orders notes within each group of `list`, and so their indices.
+    pub fn set_sort(&mut self, sort: Sort) {
+        self.sort = sort;
     }
 
+    /// Lists every note in index order: pinned notes first, then the rest,
+    /// with archived notes last. Within each group notes are sorted by
+    /// UUIDv7 timestamp, oldest first unless `set_sort` says otherwise.
+    /// Archived notes sit at the end so the indices of visible notes are the
+    /// same whether or not they are shown.
     pub fn list(&mut self) -> Result<Vec<Note>, String> {
+        self.reconcile()?;
         let mut uuids = self.storage.list().map_err(|e| format!("{}", e))?;
 
-        // Sort by UUIDv7 timestamp (oldest first)
+        // Sort by UUIDv7 timestamp
         uuids.sort();
+        if self.sort == Sort::Newest {
+            uuids.reverse();
+        }
 
         let mut note_list = Vec::new();
         for uuid in uuids {
             if let Some(bytes) = self.storage.get(&uuid).map_err(|e| format!("{}", e))? {
-                // Deserialize from storage
-                let crdt_note = crdt_note::Note::from(&bytes);
-                let note_id = crdt_note.id();
-                let note_content = crdt_note.content();
+                // Deserialize from storage, signing later edits if set up
+                let mut crdt_note = crdt_note::Note::from(&bytes);
+                if let Some(key) = &self.signer {
+                    crdt_note = crdt_note.with_signer(key);
+                }
+                let note = self.view(&crdt_note);
 
                 // Store in memory cache
-                self.notes.insert(note_id.clone(), crdt_note);
+                self.notes.insert(note.id.clone(), crdt_note);
 
-                note_list.push(Note {
-                    id: note_id,
-                    content: note_content,
-                });
+                note_list.push(note);
             }
         }
 
+        // Stable sort keeps the timestamp order within each group
+        note_list.sort_by_key(|note| (note.archived, !note.pinned));
+
         Ok(note_list)
     }
 
+    /// Snapshots of every note, archived included, in `list` order.
+    pub fn export(&mut self) -> Result<Vec<crdt_note::NoteSnapshot>, String> {
+        let notes = self.list()?;
+
+        notes
+            .iter()
+            .map(|note| self.cached(&note.id).map(|crdt_note| crdt_note.snapshot()))
+            .collect()
+    }
+
+    /// Recreates notes from snapshots, keeping their ids. Notes whose id is
+    /// already in the store are skipped, since a snapshot has no history to
+    /// merge. Returns the number of notes imported.
+    pub fn import(&mut self, snapshots: &[crdt_note::NoteSnapshot]) -> Result<usize, String> {
+        let existing = self.storage.list().map_err(|e| format!("{}", e))?;
+
+        let mut imported = 0;
+        for snapshot in snapshots {
+            if snapshot.id.is_empty() || existing.contains(&snapshot.id) {
+                continue;
+            }
+            self.save(crdt_note::Note::from_snapshot(snapshot))?;
+            imported += 1;
+        }
+
+        Ok(imported)
+    }
+
     pub fn delete_by_index(&mut self, index: usize) -> Result<String, String> {
+        let note = self.note_at(index)?;
+
+        self.delete(&note.id)?;
+
+        Ok(note.content)
+    }
+
+    pub fn set_pinned_by_index(&mut self, index: usize, pinned: bool) -> Result<Note, String> {
+        let note = self.note_at(index)?;
+        let crdt_note = self.cached(&note.id)?.set_pinned(pinned);
+        self.save(crdt_note)
+    }
+
+    /// Replaces the content of a note loaded by `note_at`. Takes the note
+    /// id rather than an index because other processes can add or move
+    /// notes while it is edited; their changes to this note are merged in.
+    pub fn edit(&mut self, id: &str, content: &str) -> Result<Note, String> {
+        if self.get(id)?.is_none() {
+            return Err(format!("Note {} was deleted", id));
+        }
+        let crdt_note = self.cached(id)?.update(content);
+        self.save(crdt_note)
+    }
+
+    pub fn set_archived_by_index(&mut self, index: usize, archived: bool) -> Result<Note, String> {
+        let note = self.note_at(index)?;
+        let crdt_note = self.cached(&note.id)?.set_archived(archived);
+        self.save(crdt_note)
+    }
+
+    /// A note together with its conflicted fields and their competing values.
+    pub fn conflicts_by_index(&mut self, index: usize) -> Result<(Note, Vec<Conflict>), String> {
+        let note = self.note_at(index)?;
+        let crdt_note = self.cached(&note.id)?;
+
+        let conflicts = note
+            .conflicts
+            .iter()
+            .map(|field| (field.clone(), crdt_note.conflict_values(field)))
+            .collect();
+
+        Ok((note, conflicts))
+    }
+
+    /// Settles `field` on the value numbered `choice` (1-based) in
+    /// `conflicts_by_index`. Takes the note id rather than an index because
+    /// resolving a field like `pinned` can move the note in the list.
+    pub fn resolve(&mut self, id: &str, field: &str, choice: usize) -> Result<Note, String> {
+        let crdt_note = self.cached(id)?;
+
+        if !crdt_note.conflicts().iter().any(|f| f == field) {
+            return Err(format!("Field '{}' has no conflict", field));
+        }
+
+        let values = crdt_note.conflict_values(field);
+        if choice == 0 || choice > values.len() {
+            return Err(format!(
+                "Choice {} out of range (1-{})",
+                choice,
+                values.len()
+            ));
+        }
+
+        let crdt_note = crdt_note.resolve(field, choice - 1);
+        self.save(crdt_note)
+    }
+
+    /// Merges the conflict copies a file sync tool left beside notes into
+    /// the notes and removes them. Copies that cannot be read as the note
+    /// they are named for are left alone. Returns how many were merged.
+    pub fn reconcile(&mut self) -> Result<usize, String> {
+        let copies = self
+            .storage
+            .conflict_copies()
+            .map_err(|e| format!("{}", e))?;
+
+        let mut merged = 0;
+        for (id, copy) in copies {
+            let Ok(Some(bytes)) = self.storage.get(&copy) else {
+                continue;
+            };
+            let copy_note = crdt_note::Note::from(&bytes);
+            if copy_note.id() != id {
+                continue;
+            }
+
+            match self.get(&id)? {
+                Some(note) if note.id() == id => {
+                    let merged_note = note.merge(&copy_note);
+                    if merged_note.id() != id {
+                        continue;
+                    }
+                    if merged_note.heads() != note.heads() {
+                        self.save(merged_note)?;
+                    }
+                }
+                // The copy is all that is left of the note
+                _ => {
+                    self.save(copy_note)?;
+                }
+            }
+
+            self.storage.delete(&copy).map_err(|e| format!("{}", e))?;
+            merged += 1;
+        }
+
+        Ok(merged)
+    }
+
+    /// Ids of every note in the store, archived included.
+    pub fn ids(&self) -> Result<Vec<String>, String> {
+        self.storage.list().map_err(|e| format!("{}", e))
+    }
+
+    /// The stored note with this id, signing later edits if set up.
+    pub fn get(&self, id: &str) -> Result<Option<crdt_note::Note>, String> {
+        let bytes = self.storage.get(id).map_err(|e| format!("{}", e))?;
+        Ok(bytes.map(|bytes| {
+            let crdt_note = crdt_note::Note::from(&bytes);
+            match &self.signer {
+                Some(key) => crdt_note.with_signer(key),
+                None => crdt_note,
+            }
+        }))
+    }
+
+    pub fn delete(&mut self, id: &str) -> Result<(), String> {
+        self.outbox
+            .record(Operation::Delete { id: id.to_string() })?;
+        self.delete_synced(id)
+    }
+
+    /// Deletes a note the server has already deleted, without queueing
+    /// the deletion to push.
+    pub fn delete_synced(&mut self, id: &str) -> Result<(), String> {
+        check_note_id(id)?;
+        self.notes.remove(id);
+        self.storage.delete(id).map_err(|e| format!("{}", e))
+    }
+
+    /// Changes waiting to be pushed, oldest first.
+    pub fn pending(&self) -> Result<Vec<outbox::Entry>, String> {
+        self.outbox.pending()
+    }
+
+    /// Drops changes from the outbox once the server has them.
+    pub fn acknowledge(&self, done: &[outbox::Entry]) -> Result<(), String> {
+        self.outbox.acknowledge(done)
+    }
+
+    /// Resolves a 1-based index against the same ordering `list` returns.
+    pub fn note_at(&mut self, index: usize) -> Result<Note, String> {
         // Get current sorted list
-        let notes = self.list()?;
+        let mut notes = self.list()?;
 
         // Check if index is valid (1-based)
         if index == 0 || index > notes.len() {
@@ -93,47 +371,152 @@ impl NoteService {
         }
 
         // Get the note at the given index (convert to 0-based)
-        let note = &notes[index - 1];
-        let note_id = note.id.clone();
-        let note_content = note.content.clone();
+        Ok(notes.swap_remove(index - 1))
+    }
+
+    fn cached(&self, id: &str) -> Result<&crdt_note::Note, String> {
+        self.notes
+            .get(id)
+            .ok_or_else(|| format!("Note {} not loaded", id))
+    }
 
-        // Delete from storage
+    fn view(&self, crdt_note: &crdt_note::Note) -> Note {
+        let mut note = Note::from(crdt_note);
+        note.unverified = self.signer.is_some() && !devices::is_verified(crdt_note, &self.trusted);
+        note
+    }
+
+    /// Saves a note and queues it to push. Queued first, so a crash in
+    /// between at worst pushes a note that did not change.
+    pub fn save(&mut self, crdt_note: crdt_note::Note) -> Result<Note, String> {
+        self.outbox.record(Operation::Upsert {
+            id: crdt_note.id(),
+            heads: crdt_note.heads(),
+        })?;
+        self.save_synced(crdt_note)
+    }
+
+    /// Saves a note the server already has, e.g. one sync merged in,
+    /// without queueing it to push.
+    ///
+    /// Another process may have written the note since it was read, so the
+    /// note is merged with what is on disk before it is written.
+    pub fn save_synced(&mut self, crdt_note: crdt_note::Note) -> Result<Note, String> {
+        let id = crdt_note.id();
+        check_note_id(&id)?;
+
+        // A storage that keeps a log takes just the changes since the copy
+        // last read or written, and merges them with whatever is there
+        if let Some(previous) = self.notes.get(&id) {
+            let changes = crdt_note.changes_since(&previous.heads());
+            if self
+                .storage
+                .append(&id, &changes)
+                .map_err(|e| format!("{}", e))?
+            {
+                let note = self.view(&crdt_note);
+                self.notes.insert(id, crdt_note);
+                return Ok(note);
+            }
+        }
+
+        let mut saved = Some(crdt_note);
         self.storage
-            .delete(&note_id)
+            .update(&id, &mut |current| {
+                let mut crdt_note = saved.take().ok_or_else(|| {
+                    StorageError::SerializationError("Note written twice".to_string())
+                })?;
+
+                // A note cut short mid-write has nothing to add
+                if let Some(bytes) = current {
+                    let on_disk = crdt_note::Note::from(&bytes);
+                    if on_disk.id() == id {
+                        crdt_note = crdt_note.merge(&on_disk);
+                    }
+                }
+                if crdt_note.id() != id {
+                    return Err(StorageError::SerializationError(format!(
+                        "Could not merge note {} with the copy on disk",
+                        id
+                    )));
+                }
+
+                let bytes = crdt_note::Note::into(&crdt_note);
+                saved = Some(crdt_note);
+                Ok(bytes)
+            })
             .map_err(|e| format!("{}", e))?;
+        let crdt_note = saved.ok_or("The note was not saved")?;
+
+        let note = self.view(&crdt_note);
+
+        // Store in memory
+        self.notes.insert(note.id.clone(), crdt_note);
+
+        Ok(note)
+    }
+}
+
+fn check_note_id(id: &str) -> Result<(), String> {
+    if is_note_id(id) {
+        Ok(())
+    } else {
+        Err(format!("Invalid note id: {:?}", id))
+    }
+}
+
+/// A service over unencrypted files in `base_path`, for other modules' tests
+#[cfg(test)]
+pub fn test_service(base_path: &std::path::Path) -> NoteService {
+    let storage = crate::storage::FileSystemStorage::new(base_path.to_path_buf()).unwrap();
+    service_with(base_path, storage, SystemIdGenerator)
+}
 
-        Ok(note_content)
+/// A service over `storage` without signing, its outbox in `base_path`
+#[cfg(test)]
+fn service_with(
+    base_path: &std::path::Path,
+    storage: impl Storage + 'static,
+    ids: impl IdGenerator + 'static,
+) -> NoteService {
+    NoteService {
+        notes: HashMap::new(),
+        storage: Box::new(storage),
+        ids: Box::new(ids),
+        signer: None,
+        trusted: BTreeMap::new(),
+        outbox: Outbox::new(base_path),
+        sort: Sort::default(),
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::thread::sleep;
-    use std::time::Duration;
+    use crate::storage::{FileSystemStorage, InMemoryStorage};
+    use crdt_note::SequentialIdGenerator;
 
     #[test]
     fn test_list_returns_notes_sorted_by_creation_time() {
         let temp_dir = tempfile::tempdir().unwrap();
-        let storage = FileSystemStorage::new(temp_dir.path().to_path_buf()).unwrap();
+        let storage = InMemoryStorage::new();
 
         let mut service = NoteService {
             notes: HashMap::new(),
-            storage,
+            storage: Box::new(storage),
+            ids: Box::new(SequentialIdGenerator::default()),
+            signer: None,
+            trusted: BTreeMap::new(),
+            outbox: Outbox::new(temp_dir.path()),
+            sort: Sort::default(),
         };
 
         // Create first note
         let note1 = service.create("First note").unwrap();
 
-        // Small delay to ensure different timestamps
-        sleep(Duration::from_millis(10));
-
         // Create second note
         let note2 = service.create("Second note").unwrap();
 
-        // Small delay to ensure different timestamps
-        sleep(Duration::from_millis(10));
-
         // Create third note
         let note3 = service.create("Third note").unwrap();
 
@@ -157,18 +540,21 @@ mod tests {
     #[test]
     fn test_delete_by_index() {
         let temp_dir = tempfile::tempdir().unwrap();
-        let storage = FileSystemStorage::new(temp_dir.path().to_path_buf()).unwrap();
+        let storage = InMemoryStorage::new();
 
         let mut service = NoteService {
             notes: HashMap::new(),
-            storage,
+            storage: Box::new(storage),
+            ids: Box::new(SequentialIdGenerator::default()),
+            signer: None,
+            trusted: BTreeMap::new(),
+            outbox: Outbox::new(temp_dir.path()),
+            sort: Sort::default(),
         };
 
         // Create three notes
         service.create("First note").unwrap();
-        sleep(Duration::from_millis(10));
         service.create("Second note").unwrap();
-        sleep(Duration::from_millis(10));
         service.create("Third note").unwrap();
 
         // Verify we have 3 notes
@@ -185,6 +571,34 @@ mod tests {
         assert_eq!(notes[1].content, "Third note");
     }
 
+    #[test]
+    fn test_edit_by_id_after_other_processes_move_notes() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut service = test_service(temp_dir.path());
+        let mut other = test_service(temp_dir.path());
+        service.create("First note").unwrap();
+        service.create("Second note").unwrap();
+        service.create("Third note").unwrap();
+
+        // Picked by index, then the list changes under the editor
+        let second = service.note_at(2).unwrap();
+        let third = service.note_at(3).unwrap();
+        other.set_pinned_by_index(3, true).unwrap();
+
+        service.edit(&second.id, "Second, edited").unwrap();
+        let contents: Vec<String> = service
+            .list()
+            .unwrap()
+            .into_iter()
+            .map(|note| note.content)
+            .collect();
+        assert_eq!(contents, ["Third note", "First note", "Second, edited"]);
+
+        other.delete(&third.id).unwrap();
+        assert!(service.edit(&third.id, "Third, edited").is_err());
+        assert_eq!(service.list().unwrap().len(), 2);
+    }
+
     #[test]
     fn test_delete_by_index_out_of_range() {
         let temp_dir = tempfile::tempdir().unwrap();
@@ -192,7 +606,12 @@ mod tests {
 
         let mut service = NoteService {
             notes: HashMap::new(),
-            storage,
+            storage: Box::new(storage),
+            ids: Box::new(SequentialIdGenerator::default()),
+            signer: None,
+            trusted: BTreeMap::new(),
+            outbox: Outbox::new(temp_dir.path()),
+            sort: Sort::default(),
         };
 
         // Create one note
@@ -211,4 +630,237 @@ mod tests {
         // Verify note is still there
         assert_eq!(service.list().unwrap().len(), 1);
     }
+
+    #[test]
+    fn test_pinned_first_and_archived_last() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let storage = FileSystemStorage::new(temp_dir.path().to_path_buf()).unwrap();
+        let mut service = service_with(temp_dir.path(), storage, SequentialIdGenerator::default());
+
+        service.create("First note").unwrap();
+        service.create("Second note").unwrap();
+        service.create("Third note").unwrap();
+
+        // Archive the first note, then pin the third (now at index 2)
+        let archived = service.set_archived_by_index(1, true).unwrap();
+        assert_eq!(archived.content, "First note");
+        let pinned = service.set_pinned_by_index(2, true).unwrap();
+        assert_eq!(pinned.content, "Third note");
+
+        let notes = service.list().unwrap();
+        let contents: Vec<&str> = notes.iter().map(|n| n.content.as_str()).collect();
+        assert_eq!(contents, ["Third note", "Second note", "First note"]);
+        assert!(notes[0].pinned);
+        assert!(notes[2].archived);
+
+        // Index-based commands resolve against the same ordering
+        let deleted_content = service.delete_by_index(1).unwrap();
+        assert_eq!(deleted_content, "Third note");
+
+        let unarchived = service.set_archived_by_index(2, false).unwrap();
+        assert_eq!(unarchived.content, "First note");
+        let notes = service.list().unwrap();
+        assert_eq!(notes[0].content, "First note");
+        assert!(!notes[0].archived);
+    }
+
+    #[test]
+    fn test_resolve_conflicts() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let storage = FileSystemStorage::new(temp_dir.path().to_path_buf()).unwrap();
+        let mut service = service_with(temp_dir.path(), storage, SequentialIdGenerator::default());
+
+        // Two devices pin and unpin the same note concurrently
+        let note = service.create("Shared note").unwrap();
+        let bytes = service.storage.get(&note.id).unwrap().unwrap();
+        let device1 = crdt_note::Note::from(&bytes).set_pinned(true);
+        let device2 = crdt_note::Note::from(&bytes).set_pinned(false);
+        service.save(device1.merge(&device2)).unwrap();
+
+        let notes = service.list().unwrap();
+        assert_eq!(notes[0].conflicts, vec!["pinned".to_string()]);
+
+        let (conflicted, conflicts) = service.conflicts_by_index(1).unwrap();
+        assert_eq!(conflicted.id, note.id);
+        assert_eq!(conflicts.len(), 1);
+        let (field, values) = &conflicts[0];
+        assert_eq!(field, "pinned");
+        let choice = values.iter().position(|v| v == "true").unwrap() + 1;
+
+        assert!(service.resolve(&note.id, "pinned", 3).is_err());
+        assert!(service.resolve(&note.id, "content", 1).is_err());
+
+        let resolved = service.resolve(&note.id, "pinned", choice).unwrap();
+        assert!(resolved.conflicts.is_empty());
+        assert!(resolved.pinned);
+        assert!(service.list().unwrap()[0].conflicts.is_empty());
+    }
+
+    #[test]
+    fn test_export() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let storage = FileSystemStorage::new(temp_dir.path().to_path_buf()).unwrap();
+        let mut service = service_with(temp_dir.path(), storage, SequentialIdGenerator::default());
+
+        let note1 = service.create("First note").unwrap();
+        service.create("Second note").unwrap();
+        service.set_archived_by_index(2, true).unwrap();
+
+        let snapshots = service.export().unwrap();
+        assert_eq!(snapshots.len(), 2);
+        assert_eq!(snapshots[0].id, note1.id);
+        assert_eq!(snapshots[0].content, "First note");
+        assert!(!snapshots[0].metadata.archived);
+        assert_eq!(snapshots[1].content, "Second note");
+        assert!(snapshots[1].metadata.archived);
+    }
+
+    #[test]
+    fn test_import_keeps_ids_and_skips_existing() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let storage = FileSystemStorage::new(temp_dir.path().to_path_buf()).unwrap();
+        let mut service = service_with(
+            temp_dir.path(),
+            storage,
+            SequentialIdGenerator::starting_at(1_000),
+        );
+
+        let existing = service.create("Existing note").unwrap();
+        let mut snapshots = service.export().unwrap();
+        snapshots.push(crdt_note::NoteSnapshot {
+            id: "0190b1c2-0000-7000-8000-000000000001".to_string(),
+            content: "Imported note".to_string(),
+            metadata: crdt_note::NoteMetadata {
+                pinned: true,
+                archived: false,
+            },
+            heads: vec![],
+        });
+
+        assert_eq!(service.import(&snapshots).unwrap(), 1);
+
+        let notes = service.list().unwrap();
+        assert_eq!(notes.len(), 2);
+        assert_eq!(notes[0].id, "0190b1c2-0000-7000-8000-000000000001");
+        assert!(notes[0].pinned);
+        assert_eq!(notes[1].id, existing.id);
+    }
+
+    #[test]
+    fn test_reconcile_conflict_copies() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut service = test_service(temp_dir.path());
+        let notes_dir = crate::storage::notes_dir(temp_dir.path());
+
+        let note = service.create("milk").unwrap();
+
+        // Two devices edited the note before the sync tool caught up
+        let pinned = service.get(&note.id).unwrap().unwrap().set_pinned(true);
+        let archived = service.get(&note.id).unwrap().unwrap().set_archived(true);
+        service.save(pinned).unwrap();
+        std::fs::write(
+            notes_dir.join(format!(
+                "{}.sync-conflict-20250101-120000-ABC.note",
+                note.id
+            )),
+            crdt_note::Note::into(&archived),
+        )
+        .unwrap();
+
+        // A copy of a note that is otherwise gone
+        let lost = crdt_note::Note::new("eggs");
+        std::fs::write(
+            notes_dir.join(format!(
+                "{} (Ada's conflicted copy 2025-01-01).note",
+                lost.id()
+            )),
+            crdt_note::Note::into(&lost),
+        )
+        .unwrap();
+
+        // Listing merges them in
+        let notes = service.list().unwrap();
+        assert_eq!(notes.len(), 2);
+        let merged = service.get(&note.id).unwrap().unwrap();
+        assert!(merged.pinned() && merged.archived());
+        assert_eq!(service.get(&lost.id()).unwrap().unwrap().content(), "eggs");
+        assert_eq!(service.storage.conflict_copies().unwrap(), []);
+        assert_eq!(service.reconcile().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_save_merges_with_note_on_disk() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut first = test_service(temp_dir.path());
+        let mut second = test_service(temp_dir.path());
+        first.create("milk").unwrap();
+
+        // Both read the note, then write in turn
+        second.list().unwrap();
+        first.set_pinned_by_index(1, true).unwrap();
+        let archived = second.set_archived_by_index(1, true).unwrap();
+        assert!(archived.pinned && archived.archived);
+
+        let notes = test_service(temp_dir.path()).list().unwrap();
+        assert!(notes[0].pinned && notes[0].archived);
+    }
+
+    #[test]
+    fn test_unreadable_conflict_copy_is_left_alone() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut service = test_service(temp_dir.path());
+        let note = service.create("milk").unwrap();
+
+        let copy = crate::storage::notes_dir(temp_dir.path()).join(format!(
+            "{}.sync-conflict-20250101-120000-ABC.note",
+            note.id
+        ));
+        std::fs::write(&copy, b"half a note").unwrap();
+
+        assert_eq!(service.reconcile().unwrap(), 0);
+        assert_eq!(service.list().unwrap().len(), 1);
+        assert!(copy.exists());
+    }
+
+    #[test]
+    fn test_signed_notes_and_unverified_history() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let laptop = DeviceKey::generate().unwrap();
+        let phone = DeviceKey::generate().unwrap();
+
+        let mut service = NoteService {
+            notes: HashMap::new(),
+            storage: Box::new(FileSystemStorage::new(temp_dir.path().to_path_buf()).unwrap()),
+            ids: Box::new(SequentialIdGenerator::default()),
+            signer: Some(laptop.clone()),
+            trusted: BTreeMap::from([(laptop.public_key(), "laptop".to_string())]),
+            outbox: Outbox::new(temp_dir.path()),
+            sort: Sort::default(),
+        };
+
+        let created = service.create("Signed note").unwrap();
+        assert!(!created.unverified);
+        let pinned = service.set_pinned_by_index(1, true).unwrap();
+        assert!(!pinned.unverified);
+
+        // Another device edits the note behind our back
+        let storage = FileSystemStorage::new(temp_dir.path().to_path_buf()).unwrap();
+        let bytes = storage.get(&created.id).unwrap().unwrap();
+        let edited = crdt_note::Note::from(&bytes)
+            .with_signer(&phone)
+            .update("Edited elsewhere");
+        storage
+            .set(&created.id, &crdt_note::Note::into(&edited))
+            .unwrap();
+
+        let notes = service.list().unwrap();
+        assert_eq!(notes[0].content, "Edited elsewhere");
+        assert!(notes[0].unverified);
+
+        // Until that device is trusted
+        service
+            .trusted
+            .insert(phone.public_key(), "phone".to_string());
+        assert!(!service.list().unwrap()[0].unverified);
+    }
 }
@@ -0,0 +1,228 @@
+use crate::storage::{Storage, StorageResult, Update};
+use rusqlite::{Connection, OptionalExtension, Transaction, TransactionBehavior, params};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// How long a write waits for another process to finish its own
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS notes (
+        id TEXT PRIMARY KEY NOT NULL,
+        bytes BLOB NOT NULL,
+        updated_at INTEGER NOT NULL,
+        title TEXT,
+        content TEXT
+    );
+";
+
+/// The database `SqliteStorage` keeps every note in
+pub fn database_path(base_path: &Path) -> PathBuf {
+    base_path.join("notes.db")
+}
+
+/// Removes the database and the journal files SQLite keeps beside it.
+pub fn remove_database(base_path: &Path) -> std::io::Result<()> {
+    let path = database_path(base_path);
+    for suffix in ["", "-wal", "-shm"] {
+        let mut file = path.clone().into_os_string();
+        file.push(suffix);
+        match std::fs::remove_file(file) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Storage in one SQLite database, so listing and reading notes does not
+/// touch a file per note.
+///
+/// Each row holds the note's bytes as given, plus its title (first line)
+/// and content for querying. Those are left empty for sealed notes, so an
+/// encrypted store keeps no plaintext. Writes run in immediate
+/// transactions, which `qot` processes sharing the database take in turn.
+pub struct SqliteStorage {
+    conn: Connection,
+}
+
+impl SqliteStorage {
+    pub fn new(base_path: &Path) -> StorageResult<Self> {
+        std::fs::create_dir_all(base_path)?;
+        let conn = Connection::open(database_path(base_path))?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(Self { conn })
+    }
+}
+
+fn read(conn: &Connection, key: &str) -> StorageResult<Option<Vec<u8>>> {
+    Ok(conn
+        .query_row("SELECT bytes FROM notes WHERE id = ?1", [key], |row| {
+            row.get(0)
+        })
+        .optional()?)
+}
+
+fn write(conn: &Connection, key: &str, value: &[u8]) -> StorageResult<()> {
+    let content = if crdt_note::is_sealed(value) {
+        None
+    } else {
+        let note = crdt_note::Note::from(value);
+        (!note.id().is_empty()).then(|| note.content())
+    };
+    let title = content
+        .as_deref()
+        .map(|content| content.lines().next().unwrap_or_default().to_string());
+    let updated_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64;
+
+    conn.execute(
+        "INSERT INTO notes (id, bytes, updated_at, title, content)
+         VALUES (?1, ?2, ?3, ?4, ?5)
+         ON CONFLICT (id) DO UPDATE SET
+             bytes = excluded.bytes,
+             updated_at = excluded.updated_at,
+             title = excluded.title,
+             content = excluded.content",
+        params![key, value, updated_at, title, content],
+    )?;
+    Ok(())
+}
+
+impl Storage for SqliteStorage {
+    fn get(&self, key: &str) -> StorageResult<Option<Vec<u8>>> {
+        read(&self.conn, key)
+    }
+
+    fn set(&self, key: &str, value: &[u8]) -> StorageResult<()> {
+        write(&self.conn, key, value)
+    }
+
+    fn update(
+        &self,
+        key: &str,
+        update: &mut dyn FnMut(Option<Vec<u8>>) -> Update,
+    ) -> StorageResult<()> {
+        // Dropping the transaction on an error rolls it back
+        let transaction = Transaction::new_unchecked(&self.conn, TransactionBehavior::Immediate)?;
+        let value = update(read(&transaction, key)?)?;
+        write(&transaction, key, &value)?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> StorageResult<()> {
+        self.conn
+            .execute("DELETE FROM notes WHERE id = ?1", [key])?;
+        Ok(())
+    }
+
+    fn list(&self) -> StorageResult<Vec<String>> {
+        let mut statement = self.conn.prepare("SELECT id FROM notes")?;
+        let ids = statement
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<Vec<String>, _>>()?;
+        Ok(ids)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{EncryptedStorage, StorageError};
+    use crdt_note::SealingKey;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_set_get_delete() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = SqliteStorage::new(temp_dir.path()).unwrap();
+
+        assert_eq!(storage.get("note-1").unwrap(), None);
+        storage.set("note-1", b"first").unwrap();
+        storage.set("note-1", b"second").unwrap();
+        storage.set("note-2", b"other").unwrap();
+        assert_eq!(storage.get("note-1").unwrap(), Some(b"second".to_vec()));
+
+        let mut list = storage.list().unwrap();
+        list.sort();
+        assert_eq!(list, ["note-1", "note-2"]);
+
+        storage.delete("note-1").unwrap();
+        storage.delete("note-1").unwrap();
+        assert_eq!(storage.list().unwrap(), ["note-2"]);
+
+        // Still there when opened again
+        let reopened = SqliteStorage::new(temp_dir.path()).unwrap();
+        assert_eq!(reopened.get("note-2").unwrap(), Some(b"other".to_vec()));
+    }
+
+    #[test]
+    fn test_title_and_content_are_kept_for_querying() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = SqliteStorage::new(temp_dir.path()).unwrap();
+        let note = crdt_note::Note::new("Groceries\nmilk and eggs");
+        storage
+            .set(&note.id(), &crdt_note::Note::into(&note))
+            .unwrap();
+
+        let (title, content): (String, String) = storage
+            .conn
+            .query_row("SELECT title, content FROM notes", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert_eq!(title, "Groceries");
+        assert_eq!(content, "Groceries\nmilk and eggs");
+    }
+
+    #[test]
+    fn test_sealed_notes_keep_no_plaintext() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = EncryptedStorage::new(
+            SqliteStorage::new(temp_dir.path()).unwrap(),
+            SealingKey::generate("correct horse").unwrap(),
+        );
+        let note = crdt_note::Note::new("secret plans");
+        storage
+            .set(&note.id(), &crdt_note::Note::into(&note))
+            .unwrap();
+
+        let raw = SqliteStorage::new(temp_dir.path()).unwrap();
+        let content: Option<String> = raw
+            .conn
+            .query_row("SELECT content FROM notes", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(content, None);
+        assert!(crdt_note::is_sealed(&raw.get(&note.id()).unwrap().unwrap()));
+    }
+
+    #[test]
+    fn test_failed_update_changes_nothing() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = SqliteStorage::new(temp_dir.path()).unwrap();
+        storage.set("note-1", b"kept").unwrap();
+
+        let result = storage.update("note-1", &mut |_| {
+            Err(StorageError::SerializationError("bad merge".to_string()))
+        });
+        assert!(result.is_err());
+        assert_eq!(storage.get("note-1").unwrap(), Some(b"kept".to_vec()));
+
+        storage
+            .update("note-1", &mut |current| {
+                let mut value = current.unwrap();
+                value.extend_from_slice(b" and more");
+                Ok(value)
+            })
+            .unwrap();
+        assert_eq!(
+            storage.get("note-1").unwrap(),
+            Some(b"kept and more".to_vec())
+        );
+    }
+}
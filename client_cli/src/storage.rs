@@ -1,12 +1,29 @@
+use crate::log_storage::{self, LogStorage};
+use crate::sqlite_storage::{self, SqliteStorage};
+use crdt_note::SealingKey;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
 use std::fs;
-use std::path::PathBuf;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+
+// A write takes moments, so a temp file this old was left by a crash
+const STALE_TEMP_AGE: Duration = Duration::from_secs(10 * 60);
+
+// Tells apart temp files written at once by one process
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
 
 pub type StorageResult<T> = Result<T, StorageError>;
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum StorageError {
     IoError(std::io::Error),
     SerializationError(String),
+    EncryptionError(String),
+    DatabaseError(String),
 }
 
 impl From<std::io::Error> for StorageError {
@@ -21,11 +38,25 @@ impl From<serde_json::Error> for StorageError {
     }
 }
 
+impl From<crdt_note::EnvelopeError> for StorageError {
+    fn from(e: crdt_note::EnvelopeError) -> Self {
+        StorageError::EncryptionError(e.to_string())
+    }
+}
+
+impl From<rusqlite::Error> for StorageError {
+    fn from(e: rusqlite::Error) -> Self {
+        StorageError::DatabaseError(e.to_string())
+    }
+}
+
 impl std::fmt::Display for StorageError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
             StorageError::IoError(e) => write!(f, "I/O error: {}", e),
             StorageError::SerializationError(e) => write!(f, "Serialization error: {}", e),
+            StorageError::EncryptionError(e) => write!(f, "Encryption error: {}", e),
+            StorageError::DatabaseError(e) => write!(f, "Database error: {}", e),
         }
     }
 }
@@ -38,23 +69,242 @@ pub trait Storage {
     fn set(&self, key: &str, value: &[u8]) -> StorageResult<()>;
     fn delete(&self, key: &str) -> StorageResult<()>;
     fn list(&self) -> StorageResult<Vec<String>>;
+
+    /// Copies of notes that a file sync tool left beside them after
+    /// conflicting writes, as (note id, key of the copy) pairs. They are left
+    /// out of `list`, but read and deleted by key like notes.
+    fn conflict_copies(&self) -> StorageResult<Vec<(String, String)>> {
+        Ok(Vec::new())
+    }
+
+    /// Replaces the value under `key` with what `update` makes of the
+    /// current one. Storages shared between processes hold other writers off
+    /// in between, so a write based on the current value is never lost.
+    fn update(
+        &self,
+        key: &str,
+        update: &mut dyn FnMut(Option<Vec<u8>>) -> Update,
+    ) -> StorageResult<()> {
+        let value = update(self.get(key)?)?;
+        self.set(key, &value)
+    }
+
+    /// Adds automerge changes, as `Note::changes_since` gives them, to the
+    /// note under `key` without reading it back. Returns false, writing
+    /// nothing, if this storage keeps whole notes or holds no note under
+    /// `key`; the caller then writes the whole note instead.
+    fn append(&self, _key: &str, _changes: &[u8]) -> StorageResult<bool> {
+        Ok(false)
+    }
+}
+
+/// The new value from a `Storage::update` callback
+pub type Update = StorageResult<Vec<u8>>;
+
+impl<S: Storage + ?Sized> Storage for Box<S> {
+    fn get(&self, key: &str) -> StorageResult<Option<Vec<u8>>> {
+        (**self).get(key)
+    }
+
+    fn set(&self, key: &str, value: &[u8]) -> StorageResult<()> {
+        (**self).set(key, value)
+    }
+
+    fn delete(&self, key: &str) -> StorageResult<()> {
+        (**self).delete(key)
+    }
+
+    fn list(&self) -> StorageResult<Vec<String>> {
+        (**self).list()
+    }
+
+    fn conflict_copies(&self) -> StorageResult<Vec<(String, String)>> {
+        (**self).conflict_copies()
+    }
+
+    fn update(
+        &self,
+        key: &str,
+        update: &mut dyn FnMut(Option<Vec<u8>>) -> Update,
+    ) -> StorageResult<()> {
+        (**self).update(key, update)
+    }
+
+    fn append(&self, key: &str, changes: &[u8]) -> StorageResult<bool> {
+        (**self).append(key, changes)
+    }
+}
+
+/// Where a store keeps its notes, chosen with the `storage` setting.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Backend {
+    /// One `<id>.note` file per note, which file sync tools can carry
+    #[default]
+    Files,
+    /// One SQLite database, `notes.db`
+    Sqlite,
+    /// One append-only log of changes, `notes.log`
+    Log,
+}
+
+impl std::str::FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "files" => Ok(Backend::Files),
+            "sqlite" => Ok(Backend::Sqlite),
+            "log" => Ok(Backend::Log),
+            _ => Err(format!(
+                "'{}' is not a storage: use files, sqlite or log",
+                s
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for Backend {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Backend::Files => write!(f, "files"),
+            Backend::Sqlite => write!(f, "sqlite"),
+            Backend::Log => write!(f, "log"),
+        }
+    }
+}
+
+impl Backend {
+    pub const ALL: [Backend; 3] = [Backend::Files, Backend::Sqlite, Backend::Log];
+
+    /// Opens the store under `base_path` this backend keeps, creating it if
+    /// needed.
+    pub fn open(self, base_path: &Path) -> StorageResult<Box<dyn Storage>> {
+        Ok(match self {
+            Backend::Files => Box::new(FileSystemStorage::new(base_path.to_path_buf())?),
+            Backend::Sqlite => Box::new(SqliteStorage::new(base_path)?),
+            Backend::Log => Box::new(LogStorage::new(base_path)?),
+        })
+    }
+
+    /// Whether this backend has notes under `base_path`, without creating
+    /// anything.
+    pub fn holds_notes(self, base_path: &Path) -> bool {
+        match self {
+            Backend::Files => fs::read_dir(notes_dir(base_path)).is_ok_and(|entries| {
+                entries.flatten().any(|entry| {
+                    entry
+                        .path()
+                        .extension()
+                        .is_some_and(|extension| extension == "note")
+                })
+            }),
+            Backend::Sqlite => sqlite_storage::database_path(base_path).exists(),
+            Backend::Log => log_storage::log_path(base_path).exists(),
+        }
+    }
+
+    /// Removes what is left of the store once its notes have been moved
+    /// out, so `holds_notes` no longer sees it.
+    pub fn remove(self, base_path: &Path) -> std::io::Result<()> {
+        match self {
+            Backend::Files => Ok(()),
+            Backend::Sqlite => sqlite_storage::remove_database(base_path),
+            Backend::Log => fs::remove_file(log_storage::log_path(base_path)),
+        }
+    }
+
+    /// The other backend that holds the notes under `base_path`, if this
+    /// one does not.
+    pub fn displaced_by(self, base_path: &Path) -> Option<Backend> {
+        if self.holds_notes(base_path) {
+            return None;
+        }
+        Backend::ALL
+            .into_iter()
+            .find(|other| *other != self && other.holds_notes(base_path))
+    }
+}
+
+/// Moves every note from one storage to another byte for byte, sealed
+/// notes staying sealed. Nothing is removed from `from` until every note
+/// has been written to `to` and read back the same. Returns how many notes
+/// moved.
+pub fn migrate(from: &dyn Storage, to: &dyn Storage) -> StorageResult<usize> {
+    let keys = from.list()?;
+    for key in &keys {
+        let Some(value) = from.get(key)? else {
+            continue;
+        };
+        to.set(key, &value)?;
+        if to.get(key)?.as_deref() != Some(value.as_slice()) {
+            return Err(StorageError::IoError(std::io::Error::other(format!(
+                "note {} did not read back as written",
+                key
+            ))));
+        }
+    }
+
+    for key in &keys {
+        from.delete(key)?;
+    }
+    Ok(keys.len())
 }
 
-/// Filesystem-based storage using platform-specific directories
+/// Filesystem-based storage using platform-specific directories.
+///
+/// Writes take an advisory lock on `notes/.lock`, so `qot` processes
+/// sharing the store take turns rather than overwriting each other.
 pub struct FileSystemStorage {
     notes_dir: PathBuf,
 }
 
+/// The directory `FileSystemStorage` keeps one `<id>.note` file per note in
+pub fn notes_dir(base_path: &Path) -> PathBuf {
+    base_path.join("notes")
+}
+
 impl FileSystemStorage {
     pub fn new(base_path: PathBuf) -> StorageResult<Self> {
-        let notes_dir = base_path.join("notes");
+        let notes_dir = notes_dir(&base_path);
         fs::create_dir_all(&notes_dir)?;
-        Ok(Self { notes_dir })
+        let storage = Self { notes_dir };
+        storage.remove_temp_files(STALE_TEMP_AGE)?;
+        Ok(storage)
+    }
+
+    /// Removes temp files left by writes that never finished, if they are
+    /// older than `age`. Younger ones may belong to a write still going on
+    /// in another process. Returns how many were removed.
+    pub fn remove_temp_files(&self, age: Duration) -> StorageResult<usize> {
+        let mut removed = 0;
+
+        for entry in fs::read_dir(&self.notes_dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if path.extension().is_none_or(|extension| extension != "tmp") {
+                continue;
+            }
+
+            let modified = entry.metadata().and_then(|metadata| metadata.modified());
+            let stale = modified
+                .ok()
+                .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+                .is_some_and(|elapsed| elapsed >= age);
+            if stale && fs::remove_file(&path).is_ok() {
+                removed += 1;
+            }
+        }
+
+        Ok(removed)
     }
 
     fn note_path(&self, uuid: &str) -> PathBuf {
         self.notes_dir.join(format!("{}.note", uuid))
     }
+
+    fn lock(&self) -> StorageResult<fs::File> {
+        Ok(lock_file(&self.notes_dir.join(".lock"))?)
+    }
 }
 
 impl Storage for FileSystemStorage {
@@ -68,12 +318,24 @@ impl Storage for FileSystemStorage {
     }
 
     fn set(&self, key: &str, value: &[u8]) -> StorageResult<()> {
-        let path = self.note_path(key);
-        fs::write(path, value)?;
+        let _lock = self.lock()?;
+        write_atomic(&self.note_path(key), value)?;
+        Ok(())
+    }
+
+    fn update(
+        &self,
+        key: &str,
+        update: &mut dyn FnMut(Option<Vec<u8>>) -> Update,
+    ) -> StorageResult<()> {
+        let _lock = self.lock()?;
+        let value = update(self.get(key)?)?;
+        write_atomic(&self.note_path(key), &value)?;
         Ok(())
     }
 
     fn delete(&self, key: &str) -> StorageResult<()> {
+        let _lock = self.lock()?;
         let path = self.note_path(key);
         if path.exists() {
             fs::remove_file(path)?;
@@ -90,17 +352,258 @@ impl Storage for FileSystemStorage {
             let path = entry.path();
 
             // Only include .note files
-            if let Some(extension) = path.extension() {
-                if extension == "note" {
-                    if let Some(stem) = path.file_stem() {
-                        uuids.push(stem.to_string_lossy().to_string());
-                    }
+            if let Some(extension) = path.extension()
+                && extension == "note"
+                && let Some(stem) = path.file_stem()
+            {
+                let stem = stem.to_string_lossy();
+                if conflict_copy_of(&stem).is_none() {
+                    uuids.push(stem.to_string());
                 }
             }
         }
 
         Ok(uuids)
     }
+
+    fn conflict_copies(&self) -> StorageResult<Vec<(String, String)>> {
+        let mut copies = Vec::new();
+
+        for entry in fs::read_dir(&self.notes_dir)? {
+            let path = entry?.path();
+            if let Some(extension) = path.extension()
+                && extension == "note"
+                && let Some(stem) = path.file_stem()
+            {
+                let stem = stem.to_string_lossy();
+                if let Some(id) = conflict_copy_of(&stem) {
+                    copies.push((id.to_string(), stem.to_string()));
+                }
+            }
+        }
+
+        copies.sort();
+        Ok(copies)
+    }
+}
+
+/// Storage that keeps every value in memory and nothing on disk, for tests
+/// and for embedding the notes code where there is no store to open.
+// `qot` itself always opens a store on disk
+#[cfg_attr(not(test), allow(dead_code))]
+#[derive(Default)]
+pub struct InMemoryStorage {
+    values: RefCell<BTreeMap<String, Vec<u8>>>,
+}
+
+#[cfg_attr(not(test), allow(dead_code))]
+impl InMemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Storage for InMemoryStorage {
+    fn get(&self, key: &str) -> StorageResult<Option<Vec<u8>>> {
+        Ok(self.values.borrow().get(key).cloned())
+    }
+
+    fn set(&self, key: &str, value: &[u8]) -> StorageResult<()> {
+        self.values
+            .borrow_mut()
+            .insert(key.to_string(), value.to_vec());
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> StorageResult<()> {
+        self.values.borrow_mut().remove(key);
+        Ok(())
+    }
+
+    fn list(&self) -> StorageResult<Vec<String>> {
+        Ok(self.values.borrow().keys().cloned().collect())
+    }
+}
+
+/// Takes an advisory lock on the file at `path`, creating it if needed,
+/// waiting for any other process holding it. The lock is held until the
+/// returned file is dropped.
+pub fn lock_file(path: &Path) -> std::io::Result<fs::File> {
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)?;
+    file.lock()?;
+    Ok(file)
+}
+
+/// Like `lock_file`, but `None` instead of waiting if another process holds
+/// the lock.
+pub fn try_lock_file(path: &Path) -> std::io::Result<Option<fs::File>> {
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)?;
+    match file.try_lock() {
+        Ok(()) => Ok(Some(file)),
+        Err(fs::TryLockError::WouldBlock) => Ok(None),
+        Err(fs::TryLockError::Error(e)) => Err(e),
+    }
+}
+
+/// Replaces the file at `path` with `bytes` in one step: the bytes go to a
+/// temp file beside it, reach the disk, then take its place. A crash leaves
+/// either the old file or the new one, never a mix. The temp file's name
+/// ends in `.tmp`, so it is not taken for a note.
+pub fn write_atomic(path: &Path, bytes: &[u8]) -> std::io::Result<()> {
+    replace(path, bytes, false)
+}
+
+/// Like `write_atomic`, but only the owner can read the new file.
+pub fn write_atomic_private(path: &Path, bytes: &[u8]) -> std::io::Result<()> {
+    replace(path, bytes, true)
+}
+
+fn replace(path: &Path, bytes: &[u8], private: bool) -> std::io::Result<()> {
+    #[cfg(not(unix))]
+    let _ = private;
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let temp_path = dir.join(format!(
+        ".{}.{}-{}.tmp",
+        file_name,
+        std::process::id(),
+        TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    let written = fs::File::create(&temp_path).and_then(|mut file| {
+        // Narrowed before anything is written, whatever the umask
+        #[cfg(unix)]
+        if private {
+            use std::os::unix::fs::PermissionsExt;
+            file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        }
+        file.write_all(bytes)?;
+        file.sync_all()
+    });
+    if let Err(e) = written.and_then(|_| fs::rename(&temp_path, path)) {
+        fs::remove_file(&temp_path).ok();
+        return Err(e);
+    }
+
+    // The rename itself is only durable once the directory is
+    #[cfg(unix)]
+    fs::File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+/// The note id a conflict copy's file name stem belongs to, for the names
+/// Syncthing (`<id>.sync-conflict-<date>-<device>`) and Dropbox
+/// (`<id> (<who>'s conflicted copy <date>)`) give them.
+pub fn conflict_copy_of(stem: &str) -> Option<&str> {
+    let id = match stem.split_once(".sync-conflict-") {
+        Some((id, _)) => id,
+        None => {
+            let (id, suffix) = stem.split_once(" (")?;
+            if !suffix.ends_with(')') || !suffix.contains("conflicted copy") {
+                return None;
+            }
+            id
+        }
+    };
+    (!id.is_empty()).then_some(id)
+}
+
+/// Wraps another storage and seals every value with a key held in memory.
+///
+/// Values written before the store was encrypted are still read as
+/// plaintext, so `encrypt_in_place` can be interrupted and run again. Once
+/// it has finished, `sealed_only` refuses them: a plaintext value then was
+/// planted by someone without the key.
+pub struct EncryptedStorage<S: Storage> {
+    inner: S,
+    key: SealingKey,
+    sealed_only: bool,
+}
+
+impl<S: Storage> EncryptedStorage<S> {
+    pub fn new(inner: S, key: SealingKey) -> Self {
+        Self {
+            inner,
+            key,
+            sealed_only: false,
+        }
+    }
+
+    /// Refuses values that are not sealed instead of reading them as
+    /// plaintext.
+    pub fn sealed_only(mut self) -> Self {
+        self.sealed_only = true;
+        self
+    }
+
+    fn open(&self, key: &str, value: Option<Vec<u8>>) -> StorageResult<Option<Vec<u8>>> {
+        match value {
+            Some(value) if crdt_note::is_sealed(&value) => Ok(Some(self.key.unseal(&value)?)),
+            Some(_) if self.sealed_only => Err(StorageError::EncryptionError(format!(
+                "{} is not encrypted",
+                key
+            ))),
+            other => Ok(other),
+        }
+    }
+
+    /// Seals every value still stored as plaintext. Returns how many were
+    /// encrypted.
+    pub fn encrypt_in_place(&self) -> StorageResult<usize> {
+        let mut encrypted = 0;
+
+        for key in self.inner.list()? {
+            if let Some(value) = self.inner.get(&key)?
+                && !crdt_note::is_sealed(&value)
+            {
+                self.set(&key, &value)?;
+                encrypted += 1;
+            }
+        }
+
+        Ok(encrypted)
+    }
+}
+
+impl<S: Storage> Storage for EncryptedStorage<S> {
+    fn get(&self, key: &str) -> StorageResult<Option<Vec<u8>>> {
+        self.open(key, self.inner.get(key)?)
+    }
+
+    fn set(&self, key: &str, value: &[u8]) -> StorageResult<()> {
+        self.inner.set(key, &self.key.seal(value)?)
+    }
+
+    fn update(
+        &self,
+        key: &str,
+        update: &mut dyn FnMut(Option<Vec<u8>>) -> Update,
+    ) -> StorageResult<()> {
+        self.inner.update(key, &mut |current| {
+            let current = self.open(key, current)?;
+            Ok(self.key.seal(&update(current)?)?)
+        })
+    }
+
+    fn delete(&self, key: &str) -> StorageResult<()> {
+        self.inner.delete(key)
+    }
+
+    fn list(&self) -> StorageResult<Vec<String>> {
+        self.inner.list()
+    }
+
+    fn conflict_copies(&self) -> StorageResult<Vec<(String, String)>> {
+        self.inner.conflict_copies()
+    }
 }
 
 #[cfg(test)]
@@ -108,6 +611,137 @@ mod tests {
     use super::*;
     use tempfile::TempDir;
 
+    // Keys every storage must take: note ids, and names with the spaces,
+    // punctuation and non-ASCII text people give things. Not empty, and
+    // with no path separators
+    const UNUSUAL_KEYS: &[&str] = &[
+        "0194d5c2-7a1e-7c3b-9f00-1a2b3c4d5e6f",
+        "with spaces  and  more",
+        "dots.in.the.name.note",
+        ".leading-dot",
+        "-leading-dash",
+        "note (draft)",
+        "'single' \"double\" `back`",
+        "a,b;c=d&e+f%20g#h@i!j~k$l",
+        "ünïcödé-заметка-笔记-📝",
+        "trailing-dot.",
+    ];
+
+    fn sorted(mut keys: Vec<String>) -> Vec<String> {
+        keys.sort();
+        keys
+    }
+
+    /// The behaviour every `Storage` shares, run against an empty one.
+    fn check_conformance(storage: &dyn Storage) {
+        // Missing keys
+        assert_eq!(storage.list().unwrap(), Vec::<String>::new());
+        assert_eq!(storage.get("missing").unwrap(), None);
+        storage.delete("missing").unwrap();
+        assert!(!storage.append("missing", b"changes").unwrap());
+        assert_eq!(storage.get("missing").unwrap(), None);
+
+        // Values come back byte for byte, empty and binary ones included
+        let binary: Vec<u8> = (0..=255).cycle().take(70_000).collect();
+        storage.set("note-1", b"first").unwrap();
+        storage.set("empty", b"").unwrap();
+        storage.set("binary", &binary).unwrap();
+        assert_eq!(storage.get("note-1").unwrap(), Some(b"first".to_vec()));
+        assert_eq!(storage.get("empty").unwrap(), Some(Vec::new()));
+        assert_eq!(storage.get("binary").unwrap(), Some(binary));
+
+        // Overwriting replaces the value and keeps one key
+        storage
+            .set("note-1", b"second, and longer than the first")
+            .unwrap();
+        storage.set("note-1", b"third").unwrap();
+        assert_eq!(storage.get("note-1").unwrap(), Some(b"third".to_vec()));
+        assert_eq!(
+            sorted(storage.list().unwrap()),
+            ["binary", "empty", "note-1"]
+        );
+
+        // Update sees the current value, or none
+        storage
+            .update("note-1", &mut |current| {
+                assert_eq!(current.as_deref(), Some(&b"third"[..]));
+                Ok(b"updated".to_vec())
+            })
+            .unwrap();
+        storage
+            .update("note-2", &mut |current| {
+                assert_eq!(current, None);
+                Ok(b"created".to_vec())
+            })
+            .unwrap();
+        assert_eq!(storage.get("note-1").unwrap(), Some(b"updated".to_vec()));
+        assert_eq!(storage.get("note-2").unwrap(), Some(b"created".to_vec()));
+
+        // A failed update writes nothing
+        let failed = storage.update("note-1", &mut |_| {
+            Err(StorageError::SerializationError("bad merge".to_string()))
+        });
+        assert!(failed.is_err());
+        assert_eq!(storage.get("note-1").unwrap(), Some(b"updated".to_vec()));
+
+        // Deleting removes only that key, and deleting twice is fine
+        storage.delete("note-1").unwrap();
+        storage.delete("note-1").unwrap();
+        assert_eq!(storage.get("note-1").unwrap(), None);
+        assert_eq!(
+            sorted(storage.list().unwrap()),
+            ["binary", "empty", "note-2"]
+        );
+        for key in ["binary", "empty", "note-2"] {
+            storage.delete(key).unwrap();
+        }
+        assert!(storage.list().unwrap().is_empty());
+
+        // Unusual keys are kept apart and listed as given
+        for (i, key) in UNUSUAL_KEYS.iter().enumerate() {
+            storage.set(key, format!("value {}", i).as_bytes()).unwrap();
+        }
+        let mut expected: Vec<String> = UNUSUAL_KEYS.iter().map(|key| key.to_string()).collect();
+        expected.sort();
+        assert_eq!(sorted(storage.list().unwrap()), expected);
+        for (i, key) in UNUSUAL_KEYS.iter().enumerate() {
+            assert_eq!(
+                storage.get(key).unwrap(),
+                Some(format!("value {}", i).into_bytes()),
+                "{:?}",
+                key
+            );
+            storage.delete(key).unwrap();
+            assert_eq!(storage.get(key).unwrap(), None, "{:?}", key);
+        }
+        assert!(storage.list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_in_memory_storage_conforms() {
+        check_conformance(&InMemoryStorage::new());
+    }
+
+    #[test]
+    fn test_every_backend_conforms() {
+        for backend in Backend::ALL {
+            let temp_dir = TempDir::new().unwrap();
+            check_conformance(&backend.open(temp_dir.path()).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_encrypted_storage_conforms() {
+        let salt = SealingKey::generate("correct horse").unwrap().salt();
+        let key = || SealingKey::derive("correct horse", &salt).unwrap();
+        check_conformance(&EncryptedStorage::new(InMemoryStorage::new(), key()).sealed_only());
+        for backend in Backend::ALL {
+            let temp_dir = TempDir::new().unwrap();
+            let inner = backend.open(temp_dir.path()).unwrap();
+            check_conformance(&EncryptedStorage::new(inner, key()).sealed_only());
+        }
+    }
+
     #[test]
     fn test_storage_can_initialize() {
         let temp_dir = TempDir::new().unwrap();
@@ -156,4 +790,199 @@ mod tests {
         assert!(list.contains(&uuid2.to_string()));
         assert!(list.contains(&uuid3.to_string()));
     }
+
+    #[test]
+    fn test_interrupted_write_keeps_the_old_note() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = FileSystemStorage::new(temp_dir.path().to_path_buf()).unwrap();
+        storage.set("note-1", b"first version").unwrap();
+
+        // A crash mid-write leaves a partial temp file behind
+        let temp_file = storage.notes_dir.join(".note-1.note.999-0.tmp");
+        fs::write(&temp_file, b"second ver").unwrap();
+
+        assert_eq!(
+            storage.get("note-1").unwrap(),
+            Some(b"first version".to_vec())
+        );
+        assert_eq!(storage.list().unwrap(), ["note-1"]);
+
+        // Too recent to be sure it is abandoned
+        FileSystemStorage::new(temp_dir.path().to_path_buf()).unwrap();
+        assert!(temp_file.exists());
+
+        assert_eq!(storage.remove_temp_files(Duration::ZERO).unwrap(), 1);
+        assert!(!temp_file.exists());
+        assert_eq!(
+            storage.get("note-1").unwrap(),
+            Some(b"first version".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_failed_write_leaves_no_temp_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = FileSystemStorage::new(temp_dir.path().to_path_buf()).unwrap();
+        storage.set("note-1", b"data").unwrap();
+
+        // The rename cannot replace a directory
+        fs::create_dir(storage.note_path("note-2")).unwrap();
+        assert!(storage.set("note-2", b"data").is_err());
+
+        let mut names: Vec<String> = fs::read_dir(&storage.notes_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        assert_eq!(names, [".lock", "note-1.note", "note-2.note"]);
+    }
+
+    #[test]
+    fn test_conflict_copies_are_kept_out_of_list() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = FileSystemStorage::new(temp_dir.path().to_path_buf()).unwrap();
+
+        let syncthing = "note-1.sync-conflict-20250101-120000-ABCDEFG";
+        let dropbox = "note-1 (Ada's conflicted copy 2025-01-01)";
+        storage.set("note-1", b"data").unwrap();
+        storage.set(syncthing, b"syncthing").unwrap();
+        storage.set(dropbox, b"dropbox").unwrap();
+        storage.set("note-2 (draft)", b"not a copy").unwrap();
+
+        let mut list = storage.list().unwrap();
+        list.sort();
+        assert_eq!(list, ["note-1", "note-2 (draft)"]);
+
+        let copies = storage.conflict_copies().unwrap();
+        assert_eq!(
+            copies,
+            [
+                ("note-1".to_string(), dropbox.to_string()),
+                ("note-1".to_string(), syncthing.to_string()),
+            ]
+        );
+        assert_eq!(storage.get(dropbox).unwrap(), Some(b"dropbox".to_vec()));
+    }
+
+    #[test]
+    fn test_conflict_copy_of() {
+        assert_eq!(
+            conflict_copy_of("abc.sync-conflict-20250101-120000-XYZ"),
+            Some("abc")
+        );
+        assert_eq!(
+            conflict_copy_of("abc (conflicted copy 2025-01-01)"),
+            Some("abc")
+        );
+        assert_eq!(conflict_copy_of("abc"), None);
+        assert_eq!(conflict_copy_of("abc (copy)"), None);
+        assert_eq!(conflict_copy_of(".sync-conflict-20250101"), None);
+    }
+
+    #[test]
+    fn test_encrypted_set_and_get() {
+        let temp_dir = TempDir::new().unwrap();
+        let key = SealingKey::generate("correct horse").unwrap();
+        let storage = EncryptedStorage::new(
+            FileSystemStorage::new(temp_dir.path().to_path_buf()).unwrap(),
+            key,
+        );
+
+        storage.set("test-uuid-123", b"test data").unwrap();
+        assert_eq!(
+            storage.get("test-uuid-123").unwrap(),
+            Some(b"test data".to_vec())
+        );
+
+        // Nothing readable reaches the disk
+        let raw = FileSystemStorage::new(temp_dir.path().to_path_buf())
+            .unwrap()
+            .get("test-uuid-123")
+            .unwrap()
+            .unwrap();
+        assert!(crdt_note::is_sealed(&raw));
+        assert!(!raw.windows(9).any(|w| w == b"test data"));
+    }
+
+    #[test]
+    fn test_encrypted_get_with_wrong_key_fails() {
+        let temp_dir = TempDir::new().unwrap();
+        let key = SealingKey::generate("correct horse").unwrap();
+        let salt = key.salt();
+        let storage = EncryptedStorage::new(
+            FileSystemStorage::new(temp_dir.path().to_path_buf()).unwrap(),
+            key,
+        );
+        storage.set("test-uuid-123", b"test data").unwrap();
+
+        let wrong = EncryptedStorage::new(
+            FileSystemStorage::new(temp_dir.path().to_path_buf()).unwrap(),
+            SealingKey::derive("battery staple", &salt).unwrap(),
+        );
+        assert!(matches!(
+            wrong.get("test-uuid-123"),
+            Err(StorageError::EncryptionError(_))
+        ));
+    }
+
+    #[test]
+    fn test_encrypt_in_place() {
+        let temp_dir = TempDir::new().unwrap();
+        let plain = FileSystemStorage::new(temp_dir.path().to_path_buf()).unwrap();
+        plain.set("test-uuid-1", b"data1").unwrap();
+        plain.set("test-uuid-2", b"data2").unwrap();
+
+        let storage = EncryptedStorage::new(
+            FileSystemStorage::new(temp_dir.path().to_path_buf()).unwrap(),
+            SealingKey::generate("correct horse").unwrap(),
+        );
+
+        // Plaintext values stay readable until they are migrated
+        assert_eq!(storage.get("test-uuid-1").unwrap(), Some(b"data1".to_vec()));
+
+        assert_eq!(storage.encrypt_in_place().unwrap(), 2);
+        assert_eq!(storage.encrypt_in_place().unwrap(), 0);
+        assert!(crdt_note::is_sealed(
+            &plain.get("test-uuid-2").unwrap().unwrap()
+        ));
+        assert_eq!(storage.get("test-uuid-2").unwrap(), Some(b"data2".to_vec()));
+
+        // Afterwards a plaintext value was not written by this device
+        let storage = storage.sealed_only();
+        plain.set("test-uuid-3", b"planted").unwrap();
+        assert!(matches!(
+            storage.get("test-uuid-3"),
+            Err(StorageError::EncryptionError(_))
+        ));
+        assert!(
+            storage
+                .update("test-uuid-3", &mut |_| Ok(b"replaced".to_vec()))
+                .is_err()
+        );
+        assert_eq!(storage.get("test-uuid-1").unwrap(), Some(b"data1".to_vec()));
+    }
+
+    #[test]
+    fn test_migrate_moves_every_note() {
+        let temp_dir = TempDir::new().unwrap();
+        let files = Backend::Files.open(temp_dir.path()).unwrap();
+        files.set("note-1", b"data1").unwrap();
+        files.set("note-2", b"\x00\xffbinary").unwrap();
+
+        let sqlite = Backend::Sqlite.open(temp_dir.path()).unwrap();
+        assert_eq!(migrate(&files, &sqlite).unwrap(), 2);
+
+        assert!(files.list().unwrap().is_empty());
+        assert!(!Backend::Files.holds_notes(temp_dir.path()));
+        assert!(Backend::Sqlite.holds_notes(temp_dir.path()));
+        assert_eq!(
+            sqlite.get("note-2").unwrap(),
+            Some(b"\x00\xffbinary".to_vec())
+        );
+
+        // And back again
+        assert_eq!(migrate(&sqlite, &files).unwrap(), 2);
+        assert_eq!(files.get("note-1").unwrap(), Some(b"data1".to_vec()));
+        assert!(sqlite.list().unwrap().is_empty());
+    }
 }
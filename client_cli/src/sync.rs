@@ -0,0 +1,737 @@
+use crate::outbox::{self, Operation};
+use crate::service::{NoteService, is_note_id};
+use crate::storage;
+use crdt_note::{SealingKey, WireNote};
+use qot_client::{ClientError, ClientResult, HttpClient};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const RETRIES: usize = 3;
+const RETRY_DELAY: Duration = Duration::from_millis(200);
+
+/// What `sync_state.json` holds: the ids of the notes on both sides after
+/// the last sync with an account. A note missing on one side but listed
+/// here was deleted there; one not listed is new.
+#[derive(Default, Serialize, Deserialize)]
+struct SyncFile {
+    server: String,
+    user_id: String,
+    synced: BTreeSet<String>,
+}
+
+/// Where the last sync with `server` left off
+#[derive(Clone)]
+pub struct SyncState {
+    path: PathBuf,
+    server: String,
+}
+
+impl SyncState {
+    pub fn new(base_path: &Path, server: &str) -> Self {
+        Self {
+            path: base_path.join("sync_state.json"),
+            server: server.to_string(),
+        }
+    }
+
+    /// The synced ids for an account. Another account's ids are no guide to
+    /// what was deleted, so they read as none.
+    fn load(&self, user_id: &str) -> Result<BTreeSet<String>, String> {
+        let sync_file: SyncFile = match fs::read(&self.path) {
+            Ok(json) => serde_json::from_slice(&json).map_err(|e| format!("{}", e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(BTreeSet::new()),
+            Err(e) => return Err(format!("{}", e)),
+        };
+
+        if sync_file.server == self.server && sync_file.user_id == user_id {
+            Ok(sync_file.synced)
+        } else {
+            Ok(BTreeSet::new())
+        }
+    }
+
+    fn save(&self, user_id: &str, synced: &BTreeSet<String>) -> Result<(), String> {
+        let sync_file = SyncFile {
+            server: self.server.clone(),
+            user_id: user_id.to_string(),
+            synced: synced.clone(),
+        };
+        let json = serde_json::to_vec_pretty(&sync_file).map_err(|e| format!("{}", e))?;
+        storage::write_atomic(&self.path, &json).map_err(|e| format!("{}", e))
+    }
+}
+
+/// What a sync changed. Counts cover both directions.
+#[derive(Debug, Default, PartialEq)]
+pub struct SyncReport {
+    /// Notes only one side had, copied to the other
+    pub created: usize,
+    /// Notes one side was behind on
+    pub updated: usize,
+    /// Notes both sides had changed
+    pub merged: usize,
+    /// Deletions carried over
+    pub deleted: usize,
+    /// Server notes that could not be read
+    pub skipped: usize,
+}
+
+/// Where notes sync to: the server over HTTP, or the notes channel in
+/// `qot watch`. A push either has landed when it returns `None`, or is on
+/// its way under the id returned, for `Syncer::confirm` once it lands.
+pub trait Remote {
+    fn put_note(&mut self, note: &WireNote) -> Result<Option<u64>, String>;
+    fn delete_note(&mut self, id: &str) -> Result<Option<u64>, String>;
+}
+
+impl Remote for HttpClient {
+    fn put_note(&mut self, note: &WireNote) -> Result<Option<u64>, String> {
+        with_retries(|| HttpClient::put_note(self, note)).map(|()| None)
+    }
+
+    fn delete_note(&mut self, id: &str) -> Result<Option<u64>, String> {
+        with_retries(|| HttpClient::delete_note(self, id)).map(|()| None)
+    }
+}
+
+// Tries again after failures that may pass: the network, or the server
+// having trouble. Both operations are safe to repeat.
+fn with_retries(mut operation: impl FnMut() -> ClientResult<()>) -> Result<(), String> {
+    let mut delay = RETRY_DELAY;
+    for _ in 1..RETRIES {
+        match operation() {
+            Err(ClientError::Transport(_)) => {}
+            Err(ClientError::Status { status, .. }) if status >= 500 => {}
+            result => return result.map_err(|e| format!("{}", e)),
+        }
+        std::thread::sleep(delay);
+        delay *= 2;
+    }
+    operation().map_err(|e| format!("{}", e))
+}
+
+/// Settles notes between this device and the server. Each note is settled
+/// and recorded before the next, so an interrupted sync picks up where it
+/// stopped. A deletion on either side wins over edits made on the other
+/// since the last sync.
+pub struct Syncer<'a> {
+    state: SyncState,
+    user_id: String,
+    key: Option<&'a SealingKey>,
+    synced: BTreeSet<String>,
+    // Pushes still on their way, by id
+    unconfirmed: BTreeMap<u64, Unconfirmed>,
+}
+
+// What a push records once it lands: whether its note is then on both
+// sides, and the outbox entries it carries
+struct Unconfirmed {
+    id: String,
+    synced: bool,
+    entries: Vec<outbox::Entry>,
+}
+
+impl<'a> Syncer<'a> {
+    pub fn new(
+        state: SyncState,
+        user_id: &str,
+        key: Option<&'a SealingKey>,
+    ) -> Result<Self, String> {
+        Ok(Self {
+            synced: state.load(user_id)?,
+            state,
+            user_id: user_id.to_string(),
+            key,
+            unconfirmed: BTreeMap::new(),
+        })
+    }
+
+    /// Settles every note against the server's full list, until both sides
+    /// hold the same. Changes in the outbox go first, in the order they were
+    /// made, and each leaves the outbox once the server has it.
+    pub fn sync_all(
+        &mut self,
+        service: &mut NoteService,
+        remote: &mut dyn Remote,
+        wire_notes: Vec<WireNote>,
+    ) -> Result<SyncReport, String> {
+        // Unreadable notes are left alone on both sides, rather than taken
+        // as missing from the server
+        let mut notes = BTreeMap::new();
+        let mut unreadable = BTreeSet::new();
+        for wire_note in wire_notes {
+            match self.open(&wire_note) {
+                Some(note) => {
+                    notes.insert(wire_note.id, note);
+                }
+                None => {
+                    unreadable.insert(wire_note.id);
+                }
+            }
+        }
+
+        let mut report = SyncReport {
+            skipped: unreadable.len(),
+            ..SyncReport::default()
+        };
+
+        let mut settled = BTreeSet::new();
+        for entry in service.pending()? {
+            let id = entry.operation.id().to_string();
+            if unreadable.contains(&id) {
+                continue;
+            }
+            if !settled.contains(&id) {
+                self.replay(
+                    service,
+                    remote,
+                    &entry.operation,
+                    notes.remove(&id),
+                    &mut report,
+                )?;
+                settled.insert(id);
+            }
+            self.acknowledge(service, vec![entry])?;
+        }
+
+        let ids: BTreeSet<String> = service
+            .ids()?
+            .into_iter()
+            .chain(notes.keys().cloned())
+            .filter(|id| !unreadable.contains(id) && !settled.contains(id))
+            .collect();
+
+        for id in ids {
+            let remote_note = notes.remove(&id);
+            self.settle(service, remote, &id, remote_note, &mut report)?;
+        }
+
+        Ok(report)
+    }
+
+    /// Pushes one change from the outbox. An upsert pushes the note as it
+    /// is now, so later edits to it go along.
+    pub fn replay(
+        &mut self,
+        service: &mut NoteService,
+        remote: &mut dyn Remote,
+        operation: &Operation,
+        remote_note: Option<crdt_note::Note>,
+        report: &mut SyncReport,
+    ) -> Result<(), String> {
+        // The deletion was made here, whatever the last sync recorded
+        if let Operation::Delete { id } = operation {
+            self.mark_synced(id);
+        }
+        self.settle(service, remote, operation.id(), remote_note, report)
+    }
+
+    /// Settles one note against what the server holds for it, `None` if
+    /// it holds nothing.
+    pub fn settle(
+        &mut self,
+        service: &mut NoteService,
+        remote: &mut dyn Remote,
+        id: &str,
+        remote_note: Option<crdt_note::Note>,
+        report: &mut SyncReport,
+    ) -> Result<(), String> {
+        let local = service.get(id)?;
+
+        // Half-written by another process; the next change to it settles it
+        if local.as_ref().is_some_and(|local| local.id() != id) {
+            return Ok(());
+        }
+
+        match (local, remote_note) {
+            (Some(local), Some(remote_note)) => {
+                let merged = local.merge(&remote_note);
+                let local_behind = merged.heads() != local.heads();
+                let remote_behind = merged.heads() != remote_note.heads();
+
+                let push = match remote_behind {
+                    true => self.upload(remote, &merged)?,
+                    false => None,
+                };
+                if local_behind {
+                    service.save_synced(merged)?;
+                }
+                match (local_behind, remote_behind) {
+                    (true, true) => report.merged += 1,
+                    (true, false) | (false, true) => report.updated += 1,
+                    (false, false) => {}
+                }
+                self.record(id, true, push);
+            }
+            (Some(_), None) if self.synced.contains(id) => {
+                service.delete_synced(id)?;
+                report.deleted += 1;
+                self.synced.remove(id);
+            }
+            (Some(local), None) => {
+                let push = self.upload(remote, &local)?;
+                report.created += 1;
+                self.record(id, true, push);
+            }
+            (None, Some(_)) if self.synced.contains(id) => {
+                let push = remote.delete_note(id)?;
+                report.deleted += 1;
+                self.record(id, false, push);
+            }
+            (None, Some(remote_note)) => {
+                service.save_synced(remote_note)?;
+                report.created += 1;
+                self.synced.insert(id.to_string());
+            }
+            (None, None) => {
+                self.synced.remove(id);
+            }
+        }
+
+        self.state.save(&self.user_id, &self.synced)
+    }
+
+    /// Drops outbox entries the server has, or leaves them to the push of
+    /// their note that is still on its way.
+    pub fn acknowledge(
+        &mut self,
+        service: &mut NoteService,
+        entries: Vec<outbox::Entry>,
+    ) -> Result<(), String> {
+        let mut done = Vec::new();
+        for entry in entries {
+            let push = self
+                .unconfirmed
+                .values_mut()
+                .rev()
+                .find(|push| push.id == entry.operation.id());
+            match push {
+                Some(push) => push.entries.push(entry),
+                None => done.push(entry),
+            }
+        }
+        service.acknowledge(&done)
+    }
+
+    /// Records a push the server has confirmed, and drops the outbox
+    /// entries it carried.
+    pub fn confirm(&mut self, service: &mut NoteService, push: u64) -> Result<(), String> {
+        let Some(push) = self.unconfirmed.remove(&push) else {
+            return Ok(());
+        };
+        match push.synced {
+            true => self.synced.insert(push.id),
+            false => self.synced.remove(&push.id),
+        };
+        self.state.save(&self.user_id, &self.synced)?;
+        service.acknowledge(&push.entries)
+    }
+
+    /// Gives up on a push, e.g. refused or lost with its connection. Its
+    /// outbox entries stay, to be pushed again.
+    pub fn forget(&mut self, push: u64) {
+        self.unconfirmed.remove(&push);
+    }
+
+    /// Gives up on every push still on its way.
+    pub fn forget_all(&mut self) {
+        self.unconfirmed.clear();
+    }
+
+    /// Records that both sides had the note, e.g. when the server reports
+    /// deleting it, so settling it carries the deletion over.
+    pub fn mark_synced(&mut self, id: &str) {
+        self.synced.insert(id.to_string());
+    }
+
+    /// A server note, unsealed with the key. Without a key only notes that
+    /// are not sealed, like the web app's, can be read; with one, only
+    /// sealed notes are, so the server cannot slip plaintext into the
+    /// store. `None` if it cannot be read, or its id is not one this device
+    /// would store.
+    pub fn open(&self, wire_note: &WireNote) -> Option<crdt_note::Note> {
+        if !is_note_id(&wire_note.id) {
+            return None;
+        }
+        let bytes = match self.key {
+            Some(key) if crdt_note::is_sealed(&wire_note.data) => {
+                key.unseal(&wire_note.data).ok()?
+            }
+            None if !crdt_note::is_sealed(&wire_note.data) => wire_note.data.clone(),
+            _ => return None,
+        };
+
+        let note = crdt_note::Note::from(&bytes);
+        (note.id() == wire_note.id).then_some(note)
+    }
+
+    // Whether the note is on both sides, now or once its push lands
+    fn record(&mut self, id: &str, synced: bool, push: Option<u64>) {
+        match push {
+            Some(push) => {
+                let id = id.to_string();
+                let entries = Vec::new();
+                self.unconfirmed.insert(
+                    push,
+                    Unconfirmed {
+                        id,
+                        synced,
+                        entries,
+                    },
+                );
+            }
+            None if synced => {
+                self.synced.insert(id.to_string());
+            }
+            None => {
+                self.synced.remove(id);
+            }
+        }
+    }
+
+    fn upload(
+        &self,
+        remote: &mut dyn Remote,
+        note: &crdt_note::Note,
+    ) -> Result<Option<u64>, String> {
+        let mut wire_note = WireNote::new(note);
+        if let Some(key) = self.key {
+            wire_note.data = key.seal(&wire_note.data).map_err(|e| format!("{}", e))?;
+        }
+        remote.put_note(&wire_note)
+    }
+}
+
+/// Merges the notes on this device with the ones on the server, both ways.
+pub fn sync(
+    service: &mut NoteService,
+    client: &mut HttpClient,
+    state: &SyncState,
+    key: Option<&SealingKey>,
+) -> Result<SyncReport, String> {
+    let user_id = match client.session() {
+        Some(session) => session.user.id.clone(),
+        None => return Err("Not logged in".to_string()),
+    };
+
+    let wire_notes = client.list_notes().map_err(|e| format!("{}", e))?;
+    Syncer::new(state.clone(), &user_id, key)?.sync_all(service, client, wire_notes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::test_service;
+    use qot_client::mock::MockServer;
+
+    fn logged_in(server: &MockServer, email: &str) -> HttpClient {
+        let mut client = HttpClient::new(&server.url());
+        client.set_session(Some(server.session(email)));
+        client
+    }
+
+    fn user_id(client: &HttpClient) -> String {
+        client.session().unwrap().user.id.clone()
+    }
+
+    #[test]
+    fn test_sync_creates_notes_both_ways() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let server = MockServer::start();
+        let mut client = logged_in(&server, "ada@example.com");
+        let state = SyncState::new(temp_dir.path(), &server.url());
+        let mut service = test_service(temp_dir.path());
+
+        let local = service.create("from the cli").unwrap();
+        let remote = crdt_note::Note::new("from the web");
+        server.put_note(
+            &user_id(&client),
+            &remote.id(),
+            &crdt_note::Note::into(&remote),
+        );
+
+        let report = sync(&mut service, &mut client, &state, None).unwrap();
+        assert_eq!(
+            report,
+            SyncReport {
+                created: 2,
+                ..SyncReport::default()
+            }
+        );
+
+        let on_server = server.notes(&user_id(&client));
+        assert!(on_server.contains_key(&local.id));
+        assert_eq!(
+            service.get(&remote.id()).unwrap().unwrap().content(),
+            "from the web"
+        );
+
+        // Nothing left to do
+        let report = sync(&mut service, &mut client, &state, None).unwrap();
+        assert_eq!(report, SyncReport::default());
+    }
+
+    #[test]
+    fn test_sync_merges_instead_of_overwriting() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let server = MockServer::start();
+        let mut client = logged_in(&server, "ada@example.com");
+        let state = SyncState::new(temp_dir.path(), &server.url());
+        let mut service = test_service(temp_dir.path());
+
+        let id = service.create("milk").unwrap().id;
+        sync(&mut service, &mut client, &state, None).unwrap();
+
+        // Edited here only
+        let edited = service.get(&id).unwrap().unwrap().update("oat milk");
+        service.save(edited).unwrap();
+        let report = sync(&mut service, &mut client, &state, None).unwrap();
+        assert_eq!(report.updated, 1);
+        let on_server = crdt_note::Note::from(&server.notes(&user_id(&client))[&id]);
+        assert_eq!(on_server.content(), "oat milk");
+
+        // Edited on both sides
+        server.put_note(
+            &user_id(&client),
+            &id,
+            &crdt_note::Note::into(&on_server.set_pinned(true)),
+        );
+        let edited = service.get(&id).unwrap().unwrap().set_archived(true);
+        service.save(edited).unwrap();
+
+        let report = sync(&mut service, &mut client, &state, None).unwrap();
+        assert_eq!(report.merged, 1);
+
+        let local = service.get(&id).unwrap().unwrap();
+        let on_server = crdt_note::Note::from(&server.notes(&user_id(&client))[&id]);
+        assert!(local.pinned() && local.archived());
+        assert_eq!(local.heads(), on_server.heads());
+    }
+
+    #[test]
+    fn test_sync_propagates_deletions() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let server = MockServer::start();
+        let mut client = logged_in(&server, "ada@example.com");
+        let state = SyncState::new(temp_dir.path(), &server.url());
+        let mut service = test_service(temp_dir.path());
+
+        let deleted_here = service.create("one").unwrap().id;
+        let deleted_there = service.create("two").unwrap().id;
+        sync(&mut service, &mut client, &state, None).unwrap();
+
+        service.delete(&deleted_here).unwrap();
+        server.delete_note(&user_id(&client), &deleted_there);
+
+        let report = sync(&mut service, &mut client, &state, None).unwrap();
+        assert_eq!(report.deleted, 2);
+        assert!(server.notes(&user_id(&client)).is_empty());
+        assert!(service.ids().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_sync_replays_outbox() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let server = MockServer::start();
+        let mut client = logged_in(&server, "ada@example.com");
+        let state = SyncState::new(temp_dir.path(), &server.url());
+        let mut service = test_service(temp_dir.path());
+
+        let id = service.create("gone soon").unwrap().id;
+        assert_eq!(service.pending().unwrap().len(), 1);
+        sync(&mut service, &mut client, &state, None).unwrap();
+        assert!(service.pending().unwrap().is_empty());
+
+        // Without the sync state the note would look new on the server,
+        // but the outbox still knows it was deleted here
+        std::fs::remove_file(temp_dir.path().join("sync_state.json")).unwrap();
+        service.delete(&id).unwrap();
+
+        let report = sync(&mut service, &mut client, &state, None).unwrap();
+        assert_eq!(report.deleted, 1);
+        assert!(server.notes(&user_id(&client)).is_empty());
+        assert!(service.ids().unwrap().is_empty());
+        assert!(service.pending().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_outbox_is_kept_when_sync_fails() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let state = SyncState::new(temp_dir.path(), "http://127.0.0.1:9");
+        let mut service = test_service(temp_dir.path());
+        service.create("offline").unwrap();
+
+        let mut client = HttpClient::new("http://127.0.0.1:9");
+        assert!(sync(&mut service, &mut client, &state, None).is_err());
+        assert_eq!(service.pending().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_retries_only_what_may_pass() {
+        let mut attempts = 0;
+        let result = with_retries(|| {
+            attempts += 1;
+            match attempts {
+                1 => Err(ClientError::Transport("reset".to_string())),
+                2 => Err(ClientError::Status {
+                    status: 503,
+                    message: "busy".to_string(),
+                }),
+                _ => Ok(()),
+            }
+        });
+        assert_eq!(result, Ok(()));
+        assert_eq!(attempts, 3);
+
+        let mut attempts = 0;
+        let result = with_retries(|| {
+            attempts += 1;
+            Err(ClientError::Unauthorized)
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn test_sync_state_is_per_account() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let server = MockServer::start();
+        let state = SyncState::new(temp_dir.path(), &server.url());
+        let mut service = test_service(temp_dir.path());
+
+        let id = service.create("shared").unwrap().id;
+        let mut ada = logged_in(&server, "ada@example.com");
+        sync(&mut service, &mut ada, &state, None).unwrap();
+
+        // Grace's account never had the note, so it is new there rather
+        // than deleted
+        let mut grace = logged_in(&server, "grace@example.com");
+        let report = sync(&mut service, &mut grace, &state, None).unwrap();
+        assert_eq!(report.created, 1);
+        assert_eq!(report.deleted, 0);
+        assert!(server.notes(&user_id(&grace)).contains_key(&id));
+    }
+
+    // Takes pushes without landing them, like the notes channel
+    #[derive(Default)]
+    struct Queued(Vec<WireNote>);
+
+    impl Remote for Queued {
+        fn put_note(&mut self, note: &WireNote) -> Result<Option<u64>, String> {
+            self.0.push(note.clone());
+            Ok(Some(self.0.len() as u64))
+        }
+
+        fn delete_note(&mut self, _id: &str) -> Result<Option<u64>, String> {
+            Ok(None)
+        }
+    }
+
+    #[test]
+    fn test_outbox_waits_for_pushes_to_land() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let state = SyncState::new(temp_dir.path(), "http://qot.test");
+        let mut service = test_service(temp_dir.path());
+        let id = service.create("on its way").unwrap().id;
+        let synced = || Syncer::new(state.clone(), "user-1", None).unwrap().synced;
+
+        let mut remote = Queued::default();
+        let mut syncer = Syncer::new(state.clone(), "user-1", None).unwrap();
+        syncer.sync_all(&mut service, &mut remote, vec![]).unwrap();
+        assert_eq!(remote.0.len(), 1);
+        assert_eq!(service.pending().unwrap().len(), 1);
+        assert!(synced().is_empty());
+
+        // A push that never lands leaves the change to be pushed again
+        syncer.forget_all();
+        syncer.confirm(&mut service, 1).unwrap();
+        assert_eq!(service.pending().unwrap().len(), 1);
+
+        syncer.sync_all(&mut service, &mut remote, vec![]).unwrap();
+        assert_eq!(remote.0.len(), 2);
+        syncer.confirm(&mut service, 2).unwrap();
+        assert!(service.pending().unwrap().is_empty());
+        assert!(synced().contains(&id));
+    }
+
+    // Hands out one id, whatever it is
+    struct FixedId(&'static str);
+
+    impl crdt_note::IdGenerator for FixedId {
+        fn next_id(&mut self) -> String {
+            self.0.to_string()
+        }
+    }
+
+    #[test]
+    fn test_sync_skips_ids_outside_the_notes_dir() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let server = MockServer::start();
+        let mut client = logged_in(&server, "ada@example.com");
+        let state = SyncState::new(temp_dir.path(), &server.url());
+        let mut service = test_service(temp_dir.path());
+
+        let id = "../../escaped";
+        let note = crdt_note::Note::generate(&mut FixedId(id), "planted");
+        assert_eq!(note.id(), id);
+        server.put_note(&user_id(&client), id, &crdt_note::Note::into(&note));
+
+        let report = sync(&mut service, &mut client, &state, None).unwrap();
+        assert_eq!(report.skipped, 1);
+        assert!(service.ids().unwrap().is_empty());
+        assert!(!temp_dir.path().join("escaped.note").exists());
+        assert!(
+            !temp_dir
+                .path()
+                .parent()
+                .unwrap()
+                .join("escaped.note")
+                .exists()
+        );
+        assert!(service.save_synced(note).is_err());
+        assert!(service.delete_synced(id).is_err());
+    }
+
+    #[test]
+    fn test_sync_seals_with_key() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let other_dir = tempfile::tempdir().unwrap();
+        let server = MockServer::start();
+        let mut client = logged_in(&server, "ada@example.com");
+        let key = SealingKey::generate("correct horse").unwrap();
+
+        let mut service = test_service(temp_dir.path());
+        let id = service.create("secret").unwrap().id;
+        let state = SyncState::new(temp_dir.path(), &server.url());
+        sync(&mut service, &mut client, &state, Some(&key)).unwrap();
+
+        let on_server = &server.notes(&user_id(&client))[&id];
+        assert!(crdt_note::is_sealed(on_server));
+
+        // Another device without the key leaves the note alone
+        let mut other = test_service(other_dir.path());
+        let other_state = SyncState::new(other_dir.path(), &server.url());
+        let report = sync(&mut other, &mut client, &other_state, None).unwrap();
+        assert_eq!(report.skipped, 1);
+        assert!(other.ids().unwrap().is_empty());
+        assert!(server.notes(&user_id(&client)).contains_key(&id));
+
+        let report = sync(&mut other, &mut client, &other_state, Some(&key)).unwrap();
+        assert_eq!(report.created, 1);
+        assert_eq!(other.get(&id).unwrap().unwrap().content(), "secret");
+
+        // With the key, a note the server holds in plaintext is not taken in
+        let planted = crdt_note::Note::new("planted");
+        server.put_note(
+            &user_id(&client),
+            &planted.id(),
+            &crdt_note::Note::into(&planted),
+        );
+        let report = sync(&mut service, &mut client, &state, Some(&key)).unwrap();
+        assert_eq!(report.skipped, 1);
+        assert!(service.get(&planted.id()).unwrap().is_none());
+    }
+}
@@ -0,0 +1,427 @@
+//! `qot watch`: keeps this device and the server in step until stopped.
+//!
+//! Changes pushed over the notes channel are merged into the note store as
+//! they arrive, and changes other `qot` invocations make to the notes
+//! directory (or, with other storage, record in the outbox) are pushed
+//! back. While the connection is down, local changes wait in the outbox and
+//! go out when the server's note list arrives after the next join. A Unix
+//! socket in the data dir answers `qot watch --status`, and SIGTERM or
+//! Ctrl-C shut it down cleanly.
+
+use crate::auth::{SessionStore, StoredSession};
+use crate::keys::{self, KeyStore};
+use crate::outbox::Outbox;
+use crate::service::{NoteService, is_note_id};
+use crate::storage::{self, Backend};
+use crate::sync::{Remote, SyncReport, SyncState, Syncer};
+use crdt_note::{WireMessage, WireNote};
+use futures_util::StreamExt;
+use notify::Watcher;
+use qot_client::{ChannelConfig, ChannelEvent, HttpClient, NotesChannel, Session};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+use tokio::net::UnixListener;
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::mpsc;
+
+// Editors and other processes write a note in bursts; settle it once
+const DEBOUNCE: Duration = Duration::from_millis(100);
+
+pub fn socket_path(base_path: &Path) -> PathBuf {
+    base_path.join("watch.sock")
+}
+
+/// Runs until SIGTERM or Ctrl-C.
+pub fn run(base_path: &Path, backend: Backend) -> Result<(), String> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(|e| format!("{}", e))?;
+    let result = runtime.block_on(watch(base_path, backend));
+    // A token refresh still under way is of no use any more
+    runtime.shutdown_background();
+    result
+}
+
+/// What a running `qot watch` reports about itself.
+pub fn status(base_path: &Path) -> Result<String, String> {
+    let mut stream = std::os::unix::net::UnixStream::connect(socket_path(base_path))
+        .map_err(|_| "qot watch is not running".to_string())?;
+    stream.set_read_timeout(Some(Duration::from_secs(1))).ok();
+
+    let mut reply = String::new();
+    stream
+        .read_to_string(&mut reply)
+        .map_err(|e| format!("{}", e))?;
+    Ok(reply.trim_end().to_string())
+}
+
+async fn watch(base_path: &Path, backend: Backend) -> Result<(), String> {
+    let sessions = SessionStore::new(base_path);
+    let stored = sessions
+        .load()?
+        .ok_or("Not logged in. Sign in with: qot login <email>")?;
+
+    // Two daemons would sync the same store against each other. The lock
+    // goes with the process, however it ends
+    let _running = storage::try_lock_file(&base_path.join("watch.lock"))
+        .map_err(|e| format!("{}", e))?
+        .ok_or("qot watch is already running for this data directory")?;
+    let socket = socket_path(base_path);
+
+    // Notes are sealed before they leave the device once a key is set up
+    let key = if KeyStore::new(base_path).exists() {
+        Some(keys::current_key(base_path)?)
+    } else {
+        None
+    };
+    // Opening the store creates the notes directory to watch
+    let service = NoteService::new(base_path, backend)?;
+
+    let socket_url =
+        std::env::var("QOT_SOCKET_URL").unwrap_or_else(|_| qot_client::socket_url(&stored.server));
+    let mut channel = NotesChannel::connect(ChannelConfig::new(&socket_url, &stored.session));
+
+    let (refreshed, mut refreshes) = mpsc::unbounded_channel();
+    let (changed, mut changes) = mpsc::unbounded_channel();
+    let outbox = Outbox::new(base_path);
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        for path in event.map(|event| event.paths).unwrap_or_default() {
+            if path
+                .extension()
+                .is_some_and(|extension| extension == "note")
+                && let Some(stem) = path.file_stem()
+            {
+                // A conflict copy changes the note it is a copy of
+                let stem = stem.to_string_lossy();
+                let id = storage::conflict_copy_of(&stem).unwrap_or(&stem);
+                changed.send(id.to_string()).ok();
+            } else if path.file_name() == outbox.path().file_name() {
+                // A database or log shows no change per note, but every change
+                // made through qot lands in the outbox
+                for entry in outbox.pending().unwrap_or_default() {
+                    changed.send(entry.operation.id().to_string()).ok();
+                }
+            }
+        }
+    })
+    .map_err(|e| format!("{}", e))?;
+    let watched = match backend {
+        Backend::Files => storage::notes_dir(base_path),
+        Backend::Sqlite | Backend::Log => base_path.to_path_buf(),
+    };
+    watcher
+        .watch(&watched, notify::RecursiveMode::NonRecursive)
+        .map_err(|e| format!("Could not watch {}: {}", watched.display(), e))?;
+
+    let listener = crate::agent::bind_private(&socket)
+        .and_then(|listener| {
+            listener.set_nonblocking(true)?;
+            UnixListener::from_std(listener)
+        })
+        .map_err(|e| format!("{}", e))?;
+
+    let mut terminate = signal(SignalKind::terminate()).map_err(|e| format!("{}", e))?;
+    let mut daemon = Daemon {
+        service,
+        syncer: Syncer::new(
+            SyncState::new(base_path, &stored.server),
+            &stored.session.user.id,
+            key.as_ref(),
+        )?,
+        refreshing: false,
+        refreshed,
+        sessions,
+        stored,
+        known: BTreeMap::new(),
+        connected: false,
+        last_activity: None,
+    };
+    daemon.log(&format!(
+        "Watching notes, syncing with {}",
+        daemon.stored.server
+    ));
+
+    loop {
+        tokio::select! {
+            event = channel.next() => match event {
+                Some(event) => daemon.handle_event(&channel, event),
+                None => break,
+            },
+            Some(session) = refreshes.recv() => daemon.token_refreshed(&channel, session),
+            Some(id) = changes.recv() => {
+                tokio::time::sleep(DEBOUNCE).await;
+                let mut ids = BTreeSet::from([id]);
+                while let Ok(id) = changes.try_recv() {
+                    ids.insert(id);
+                }
+                daemon.handle_local_changes(&channel, ids);
+            }
+            Ok((mut stream, _)) = listener.accept() => {
+                stream.write_all(daemon.status().as_bytes()).await.ok();
+            }
+            _ = terminate.recv() => break,
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
+
+    drop(watcher);
+    channel.close().await;
+    std::fs::remove_file(&socket).ok();
+    daemon.log("Stopped");
+    Ok(())
+}
+
+struct Daemon<'a> {
+    service: NoteService,
+    syncer: Syncer<'a>,
+    // Whether a token refresh is under way, and where it sends the new
+    // session if it gets one
+    refreshing: bool,
+    refreshed: mpsc::UnboundedSender<Option<Session>>,
+    sessions: SessionStore,
+    stored: StoredSession,
+    // What the server holds for each note, as last pushed either way
+    known: BTreeMap<String, WireNote>,
+    connected: bool,
+    last_activity: Option<String>,
+}
+
+impl Daemon<'_> {
+    fn handle_event(&mut self, channel: &NotesChannel, event: ChannelEvent) {
+        match event {
+            ChannelEvent::Joined => {
+                self.connected = true;
+                self.log("Connected");
+            }
+            ChannelEvent::Disconnected(reason) => {
+                self.connected = false;
+                // What was on its way may not have arrived; it stays in the
+                // outbox for the next join
+                self.syncer.forget_all();
+                self.log(&format!("Disconnected ({}), reconnecting", reason));
+                self.refresh_token();
+            }
+            ChannelEvent::Pushed(push) => {
+                if let Err(e) = self.syncer.confirm(&mut self.service, push) {
+                    self.log(&format!("Error recording a push: {}", e));
+                }
+            }
+            ChannelEvent::PushRefused(push, reason) => {
+                self.syncer.forget(push);
+                self.log(&format!("The server refused a change ({})", reason));
+            }
+            // Every note, after each join
+            ChannelEvent::Message(WireMessage::Notes { notes }) => {
+                self.known = notes
+                    .iter()
+                    .map(|note| (note.id.clone(), note.clone()))
+                    .collect();
+                let mut remote = ChannelRemote::new(channel, &mut self.known);
+                let result = self.syncer.sync_all(&mut self.service, &mut remote, notes);
+                match result {
+                    Ok(report) => {
+                        if report != SyncReport::default() {
+                            self.log(&format!(
+                                "Synced: {} created, {} updated, {} merged, {} deleted",
+                                report.created, report.updated, report.merged, report.deleted
+                            ));
+                        }
+                    }
+                    Err(e) => self.log(&format!("Error syncing: {}", e)),
+                }
+            }
+            // Ids name files, so ones that could reach outside the notes
+            // directory are dropped before they get near the store
+            ChannelEvent::Message(WireMessage::Note(wire_note)) if !is_note_id(&wire_note.id) => {
+                self.log(&format!(
+                    "Ignored a note with invalid id {:?}",
+                    wire_note.id
+                ));
+            }
+            ChannelEvent::Message(WireMessage::Delete { id }) if !is_note_id(&id) => {
+                self.log(&format!("Ignored a deletion of invalid id {:?}", id));
+            }
+            ChannelEvent::Message(WireMessage::Note(wire_note)) => {
+                let id = wire_note.id.clone();
+                let note = self.syncer.open(&wire_note);
+                self.known.insert(id.clone(), wire_note);
+                if note.is_some() {
+                    self.settle(channel, &id, "Received");
+                }
+            }
+            ChannelEvent::Message(WireMessage::Delete { id }) => {
+                self.known.remove(&id);
+                self.syncer.mark_synced(&id);
+                self.settle(channel, &id, "Received");
+            }
+        }
+    }
+
+    fn handle_local_changes(&mut self, channel: &NotesChannel, ids: BTreeSet<String>) {
+        if let Err(e) = self.service.reconcile() {
+            self.log(&format!("Error merging conflict copies: {}", e));
+        }
+
+        // Changes made by other commands are already in the outbox
+        if !self.connected {
+            let message = format!("Offline, {} change(s) waiting to be pushed", self.pending());
+            self.log(&message);
+            return;
+        }
+
+        for id in ids {
+            if self.settle(channel, &id, "Pushed") {
+                self.acknowledge(&id);
+            }
+        }
+    }
+
+    // Drops the outbox entries for a note that has been settled, once the
+    // server has what settling pushed
+    fn acknowledge(&mut self, id: &str) {
+        let result = self.service.pending().and_then(|pending| {
+            let done: Vec<_> = pending
+                .into_iter()
+                .filter(|entry| entry.operation.id() == id)
+                .collect();
+            self.syncer.acknowledge(&mut self.service, done)
+        });
+        if let Err(e) = result {
+            self.log(&format!("Error updating the outbox: {}", e));
+        }
+    }
+
+    fn pending(&self) -> usize {
+        self.service.pending().map_or(0, |pending| pending.len())
+    }
+
+    // Settles a note against what the server last held for it. Returns
+    // whether it worked.
+    fn settle(&mut self, channel: &NotesChannel, id: &str, direction: &str) -> bool {
+        let remote_note = self.known.get(id).and_then(|note| self.syncer.open(note));
+        let mut remote = ChannelRemote::new(channel, &mut self.known);
+        let mut report = SyncReport::default();
+
+        let result =
+            self.syncer
+                .settle(&mut self.service, &mut remote, id, remote_note, &mut report);
+        if let Err(e) = result {
+            self.log(&format!("Error syncing {}: {}", id, e));
+            return false;
+        }
+
+        let action = match report {
+            SyncReport { created: 1, .. } => "new note",
+            SyncReport { updated: 1, .. } => "update to",
+            SyncReport { merged: 1, .. } => "merge of",
+            SyncReport { deleted: 1, .. } => "deletion of",
+            _ => return true,
+        };
+        self.log(&format!("{} {} {}", direction, action, id));
+        true
+    }
+
+    // Access tokens are short-lived, so reconnect with a fresh one. The
+    // request blocks, so it runs off this thread, where it would hold up
+    // the channel's heartbeats
+    fn refresh_token(&mut self) {
+        if self.refreshing {
+            return;
+        }
+        self.refreshing = true;
+
+        let server = self.stored.server.clone();
+        let session = self.stored.session.clone();
+        let refreshed = self.refreshed.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut client = HttpClient::new(&server);
+            client.set_session(Some(session));
+            let session = client.refresh().ok().and(client.session().cloned());
+            refreshed.send(session).ok();
+        });
+    }
+
+    fn token_refreshed(&mut self, channel: &NotesChannel, session: Option<Session>) {
+        self.refreshing = false;
+        let Some(session) = session else {
+            return;
+        };
+
+        channel.set_token(&session.access_token);
+        self.stored.session = session.clone();
+        if let Err(e) = self.sessions.save(&self.stored) {
+            self.log(&format!("Error saving the refreshed session: {}", e));
+        }
+    }
+
+    fn status(&self) -> String {
+        let connection = if self.connected {
+            "connected"
+        } else {
+            "disconnected"
+        };
+        format!(
+            "Syncing with {}: {}\nChanges waiting to be pushed: {}\nLast activity: {}\n",
+            self.stored.server,
+            connection,
+            self.pending(),
+            self.last_activity.as_deref().unwrap_or("none")
+        )
+    }
+
+    fn log(&mut self, message: &str) {
+        let line = format!("[{}] {}", timestamp(), message);
+        println!("{}", line);
+        self.last_activity = Some(line);
+    }
+}
+
+// Sends over the notes channel, noting what the server will hold
+struct ChannelRemote<'a> {
+    channel: &'a NotesChannel,
+    known: &'a mut BTreeMap<String, WireNote>,
+}
+
+impl<'a> ChannelRemote<'a> {
+    fn new(channel: &'a NotesChannel, known: &'a mut BTreeMap<String, WireNote>) -> Self {
+        Self { channel, known }
+    }
+}
+
+impl Remote for ChannelRemote<'_> {
+    fn put_note(&mut self, note: &WireNote) -> Result<Option<u64>, String> {
+        let push = self
+            .channel
+            .send(WireMessage::Note(note.clone()))
+            .map_err(|e| format!("{}", e))?;
+        self.known.insert(note.id.clone(), note.clone());
+        Ok(Some(push))
+    }
+
+    fn delete_note(&mut self, id: &str) -> Result<Option<u64>, String> {
+        let push = self
+            .channel
+            .send(WireMessage::Delete { id: id.to_string() })
+            .map_err(|e| format!("{}", e))?;
+        self.known.remove(id);
+        Ok(Some(push))
+    }
+}
+
+// HH:MM:SS in UTC
+fn timestamp() -> String {
+    let seconds = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or_default();
+    format!(
+        "{:02}:{:02}:{:02}",
+        seconds / 3600 % 24,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
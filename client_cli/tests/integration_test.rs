@@ -3,8 +3,8 @@ use predicates::prelude::*;
 
 #[test]
 fn test_no_args_shows_help() {
-    Command::cargo_bin("qot")
-        .unwrap()
+    let data_dir = tempfile::tempdir().unwrap();
+    qot_in(data_dir.path())
         .assert()
         .success()
         .stdout(predicate::str::contains("Usage"))
@@ -13,8 +13,8 @@ fn test_no_args_shows_help() {
 
 #[test]
 fn test_create_note_with_single_word() {
-    Command::cargo_bin("qot")
-        .unwrap()
+    let data_dir = tempfile::tempdir().unwrap();
+    qot_in(data_dir.path())
         .arg("hello")
         .assert()
         .success()
@@ -24,30 +24,30 @@ fn test_create_note_with_single_word() {
 
 #[test]
 fn test_create_note_with_multiple_words() {
-    Command::cargo_bin("qot")
-        .unwrap()
-        .args(&["get", "milk"])
+    let data_dir = tempfile::tempdir().unwrap();
+    qot_in(data_dir.path())
+        .args(["get", "milk"])
         .assert()
         .success()
         .stdout(predicate::str::contains("Created note"))
         .stdout(predicate::str::contains("get milk"));
 }
 
-// #[test]
-// fn test_list_shows_placeholder() {
-//     Command::cargo_bin("qot")
-//         .unwrap()
-//         .arg("list")
-//         .assert()
-//         .success()
-//         .stdout(predicate::str::contains("No notes yet"));
-// }
+#[test]
+fn test_list_shows_placeholder() {
+    let data_dir = tempfile::tempdir().unwrap();
+    qot_in(data_dir.path())
+        .arg("list")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("No notes yet"));
+}
 
 #[test]
 fn test_create_note_with_special_characters() {
-    Command::cargo_bin("qot")
-        .unwrap()
-        .args(&["buy", "eggs", "&", "milk"])
+    let data_dir = tempfile::tempdir().unwrap();
+    qot_in(data_dir.path())
+        .args(["buy", "eggs", "&", "milk"])
         .assert()
         .success()
         .stdout(predicate::str::contains("buy eggs & milk"));
@@ -55,8 +55,8 @@ fn test_create_note_with_special_characters() {
 
 #[test]
 fn test_delete_with_no_index_shows_usage() {
-    Command::cargo_bin("qot")
-        .unwrap()
+    let data_dir = tempfile::tempdir().unwrap();
+    qot_in(data_dir.path())
         .arg("delete")
         .assert()
         .failure()
@@ -65,9 +65,9 @@ fn test_delete_with_no_index_shows_usage() {
 
 #[test]
 fn test_delete_with_invalid_index() {
-    Command::cargo_bin("qot")
-        .unwrap()
-        .args(&["delete", "abc"])
+    let data_dir = tempfile::tempdir().unwrap();
+    qot_in(data_dir.path())
+        .args(["delete", "abc"])
         .assert()
         .failure()
         .stderr(predicate::str::contains("invalid digit found in string"));
@@ -75,24 +75,1022 @@ fn test_delete_with_invalid_index() {
 
 #[test]
 fn test_list_shows_numbered_indices() {
+    let data_dir = tempfile::tempdir().unwrap();
     // First create a couple of notes
-    Command::cargo_bin("qot")
-        .unwrap()
-        .args(&["add", "first", "note"])
+    qot_in(data_dir.path())
+        .args(["add", "first", "note"])
         .assert()
         .success();
 
-    Command::cargo_bin("qot")
-        .unwrap()
-        .args(&["add", "second", "note"])
+    qot_in(data_dir.path())
+        .args(["add", "second", "note"])
         .assert()
         .success();
 
     // Now list should show numbered indices
-    Command::cargo_bin("qot")
-        .unwrap()
+    qot_in(data_dir.path())
         .arg("list")
         .assert()
         .success()
         .stdout(predicate::str::is_match(r"\d+\. .+").unwrap());
 }
+
+#[test]
+fn test_pin_with_invalid_index() {
+    let data_dir = tempfile::tempdir().unwrap();
+    qot_in(data_dir.path())
+        .args(["pin", "0"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("out of range"));
+}
+
+#[test]
+fn test_unlock_timeout_is_bounded() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let too_long: [&[&str]; 3] = [
+        &["unlock", "--timeout", "18446744073709551615"],
+        &["key", "unlock", "--timeout", "10081"],
+        &[
+            "agent",
+            "--socket",
+            "agent.sock",
+            "--timeout",
+            "18446744073709551615",
+        ],
+    ];
+    for args in too_long {
+        qot_in(data_dir.path())
+            .args(args)
+            .assert()
+            .failure()
+            .code(2)
+            .stderr(predicate::str::contains("not in 1.."));
+    }
+}
+
+#[test]
+fn test_export_prints_json() {
+    let data_dir = tempfile::tempdir().unwrap();
+    qot_in(data_dir.path())
+        .args(["add", "exported", "note"])
+        .assert()
+        .success();
+
+    let output = qot_in(data_dir.path())
+        .arg("export")
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+
+    let snapshots: serde_json::Value = serde_json::from_slice(&output).unwrap();
+    assert!(
+        snapshots
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|snapshot| snapshot["content"] == "exported note")
+    );
+}
+
+#[test]
+fn test_data_dir_flag_and_profiles() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let other_dir = tempfile::tempdir().unwrap();
+
+    // The flag wins over the environment
+    qot_in(data_dir.path())
+        .arg("--data-dir")
+        .arg(other_dir.path())
+        .args(["add", "elsewhere"])
+        .assert()
+        .success();
+    assert!(other_dir.path().join("notes").is_dir());
+
+    qot_in(data_dir.path())
+        .args(["--profile", "work", "add", "standup notes"])
+        .assert()
+        .success();
+    assert!(data_dir.path().join("profiles/work/notes").is_dir());
+
+    qot_in(data_dir.path())
+        .arg("list")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("No notes yet"));
+    qot_in(data_dir.path())
+        .args(["list", "--profile", "work"])
+        .env("QOT_PROFILE", "personal")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("standup notes"));
+    qot_in(data_dir.path())
+        .arg("list")
+        .env("QOT_PROFILE", "work")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("standup notes"));
+
+    qot_in(data_dir.path())
+        .args(["--profile", "../work", "list"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Invalid profile name"));
+}
+
+#[test]
+fn test_config_get_set_list() {
+    let data_dir = tempfile::tempdir().unwrap();
+
+    qot_in(data_dir.path())
+        .args(["config", "get", "server"])
+        .assert()
+        .success()
+        .stdout("http://localhost:4000\n");
+    qot_in(data_dir.path())
+        .args(["config", "set", "server", "https://qot.example"])
+        .assert()
+        .success();
+    qot_in(data_dir.path())
+        .args(["config", "get", "server"])
+        .assert()
+        .success()
+        .stdout("https://qot.example\n");
+    qot_in(data_dir.path())
+        .args(["config", "get", "server"])
+        .env("QOT_SERVER", "http://localhost:5000")
+        .assert()
+        .success()
+        .stdout("http://localhost:5000\n");
+    qot_in(data_dir.path())
+        .args(["config", "list"])
+        .env("QOT_LIST_FORMAT", "plain")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("server = https://qot.example "))
+        .stdout(predicate::str::contains(
+            "list.format = plain (from $QOT_LIST_FORMAT)",
+        ))
+        .stdout(predicate::str::contains("list.sort = oldest (default)"))
+        .stdout(predicate::str::contains("editor is not set"));
+
+    qot_in(data_dir.path())
+        .args(["config", "set", "list.srot", "newest"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("did you mean 'list.sort'?"));
+    qot_in(data_dir.path())
+        .args(["config", "set", "list.sort", "sideways"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("use oldest or newest"));
+}
+
+#[test]
+fn test_invalid_config_is_reported() {
+    let data_dir = tempfile::tempdir().unwrap();
+    std::fs::write(
+        data_dir.path().join("config.toml"),
+        "[list]\nformt = \"plain\"\n",
+    )
+    .unwrap();
+
+    qot_in(data_dir.path())
+        .arg("list")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Unknown key 'list.formt'"))
+        .stderr(predicate::str::contains("did you mean 'list.format'?"))
+        .stderr(predicate::str::contains("config.toml"));
+
+    // The config commands can still fix it
+    qot_in(data_dir.path())
+        .args(["config", "unset", "list.format"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("list.format is not set"));
+    std::fs::write(data_dir.path().join("config.toml"), "").unwrap();
+    qot_in(data_dir.path()).arg("list").assert().success();
+
+    qot_in(data_dir.path())
+        .arg("list")
+        .env("QOT_LIST_SORT", "sideways")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("$QOT_LIST_SORT"));
+}
+
+#[test]
+fn test_settings_shape_the_list() {
+    let data_dir = tempfile::tempdir().unwrap();
+    for content in ["first", "second", "third"] {
+        qot_in(data_dir.path())
+            .args(["add", content])
+            .assert()
+            .success();
+    }
+
+    qot_in(data_dir.path())
+        .args(["config", "set", "list.sort", "newest"])
+        .assert()
+        .success();
+    qot_in(data_dir.path())
+        .arg("list")
+        .assert()
+        .success()
+        .stdout("1. third\n2. second\n3. first\n");
+
+    // Indices follow the same order
+    qot_in(data_dir.path())
+        .args(["delete", "1"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("third"));
+
+    qot_in(data_dir.path())
+        .args(["config", "set", "list.format", "plain"])
+        .assert()
+        .success();
+    qot_in(data_dir.path())
+        .arg("list")
+        .assert()
+        .success()
+        .stdout("second\nfirst\n");
+    qot_in(data_dir.path())
+        .args(["list", "--format", "numbered"])
+        .env("QOT_LIST_SORT", "oldest")
+        .assert()
+        .success()
+        .stdout("1. first\n2. second\n");
+}
+
+#[test]
+fn test_profile_setting() {
+    let data_dir = tempfile::tempdir().unwrap();
+
+    qot_in(data_dir.path())
+        .args(["config", "set", "profile", "work"])
+        .assert()
+        .success();
+    qot_in(data_dir.path())
+        .args(["add", "standup"])
+        .assert()
+        .success();
+    assert!(data_dir.path().join("profiles/work/notes").is_dir());
+
+    qot_in(data_dir.path())
+        .args(["--profile", "home", "list"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("No notes yet"));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_edit_with_configured_editor() {
+    let data_dir = tempfile::tempdir().unwrap();
+    qot_in(data_dir.path())
+        .args(["add", "buy milk"])
+        .assert()
+        .success();
+
+    qot_in(data_dir.path())
+        .args(["config", "set", "editor", "sed -i s/milk/oat-milk/"])
+        .assert()
+        .success();
+    qot_in(data_dir.path())
+        .args(["edit", "1"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Edited note: buy oat-milk"));
+    qot_in(data_dir.path())
+        .arg("list")
+        .assert()
+        .success()
+        .stdout("1. buy oat-milk\n");
+
+    // The editor environment variable wins over the file
+    qot_in(data_dir.path())
+        .args(["edit", "1"])
+        .env("QOT_EDITOR", "true")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Unchanged"));
+    qot_in(data_dir.path())
+        .args(["edit", "1"])
+        .env("QOT_EDITOR", "false")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("exited with"));
+
+    // The file holds the note in plaintext, so only the owner may read it,
+    // and each edit has its own, so concurrent edits keep theirs
+    let script = data_dir.path().join("mode.sh");
+    std::fs::write(
+        &script,
+        format!(
+            "#!/bin/sh\nls -l \"$1\" | cut -c1-10 > {}\necho \"$1\" >> {}\n",
+            data_dir.path().join("mode").display(),
+            data_dir.path().join("paths").display()
+        ),
+    )
+    .unwrap();
+    for _ in 0..2 {
+        qot_in(data_dir.path())
+            .args(["edit", "1"])
+            .env("QOT_EDITOR", format!("sh {}", script.display()))
+            .assert()
+            .success();
+    }
+    assert_eq!(
+        std::fs::read_to_string(data_dir.path().join("mode")).unwrap(),
+        "-rw-------\n"
+    );
+    let paths = std::fs::read_to_string(data_dir.path().join("paths")).unwrap();
+    let paths: Vec<&str> = paths.lines().collect();
+    assert_eq!(paths.len(), 2);
+    assert_ne!(paths[0], paths[1]);
+
+    let leftovers: Vec<_> = std::fs::read_dir(data_dir.path())
+        .unwrap()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_name().to_string_lossy().starts_with(".edit-"))
+        .collect();
+    assert!(leftovers.is_empty());
+}
+
+// Runs qot with its data and config in `data_dir`, so tests stay out of the
+// real data directory and out of each other's way
+fn qot_in(data_dir: &std::path::Path) -> Command {
+    let mut command = Command::cargo_bin("qot").unwrap();
+    command
+        .env("QOT_DATA_DIR", data_dir)
+        .env("QOT_CONFIG", data_dir.join("config.toml"));
+    command
+}
+
+// Like `qot_in`, for commands that need to run alongside the test
+fn spawn_qot_in(data_dir: &std::path::Path) -> std::process::Command {
+    let mut command = std::process::Command::new(assert_cmd::cargo::cargo_bin("qot"));
+    command
+        .env("QOT_DATA_DIR", data_dir)
+        .env("QOT_CONFIG", data_dir.join("config.toml"));
+    command
+}
+
+#[test]
+fn test_login_with_pasted_token_then_logout() {
+    use std::io::Write;
+    use std::process::Stdio;
+
+    let server = qot_client::mock::MockServer::start();
+    let data_dir = tempfile::tempdir().unwrap();
+
+    qot_in(data_dir.path())
+        .arg("whoami")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Not logged in"));
+
+    let mut login = spawn_qot_in(data_dir.path())
+        .args(["login", "ada@example.com", "--server", &server.url()])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    let link = (0..100)
+        .find_map(|_| {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            server.magic_link("ada@example.com")
+        })
+        .unwrap();
+    writeln!(login.stdin.take().unwrap(), "{}", link).unwrap();
+
+    let output = login.wait_with_output().unwrap();
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Logged in as ada@example.com"));
+
+    qot_in(data_dir.path())
+        .arg("whoami")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("ada@example.com"))
+        .stdout(predicate::str::contains(server.url()));
+
+    qot_in(data_dir.path())
+        .arg("logout")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Logged out"));
+
+    qot_in(data_dir.path()).arg("whoami").assert().failure();
+}
+
+#[test]
+fn test_login_through_callback() {
+    use std::io::{Read, Write};
+
+    let server = qot_client::mock::MockServer::start();
+    let data_dir = tempfile::tempdir().unwrap();
+
+    let login = spawn_qot_in(data_dir.path())
+        .args(["login", "grace@example.com", "--server", &server.url()])
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    // Follow the link like a browser would
+    let link = (0..100)
+        .find_map(|_| {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            server.magic_link("grace@example.com")
+        })
+        .unwrap();
+    let (address, path) = link
+        .strip_prefix("http://")
+        .unwrap()
+        .split_once('/')
+        .unwrap();
+    let mut stream = std::net::TcpStream::connect(address).unwrap();
+    write!(
+        stream,
+        "GET /{} HTTP/1.1\r\nHost: {}\r\n\r\n",
+        path, address
+    )
+    .unwrap();
+    let mut page = String::new();
+    stream.read_to_string(&mut page).unwrap();
+    assert!(page.contains("Signed in"));
+
+    let output = login.wait_with_output().unwrap();
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Logged in as grace@example.com"));
+}
+
+#[test]
+fn test_login_with_bad_token() {
+    let server = qot_client::mock::MockServer::start();
+    let data_dir = tempfile::tempdir().unwrap();
+
+    qot_in(data_dir.path())
+        .args(["login", "ada@example.com", "--server", &server.url()])
+        .write_stdin("not-a-token\n")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("invalid or has expired"));
+
+    qot_in(data_dir.path()).arg("whoami").assert().failure();
+}
+
+// A data home signed in to `server`, as 'qot login' would leave it
+fn logged_in_home(server: &qot_client::mock::MockServer, email: &str) -> tempfile::TempDir {
+    let data_dir = tempfile::tempdir().unwrap();
+    let session = serde_json::json!({
+        "server": server.url(),
+        "session": server.session(email),
+    });
+    std::fs::write(data_dir.path().join("session.json"), session.to_string()).unwrap();
+    data_dir
+}
+
+#[test]
+fn test_sync_requires_login() {
+    let data_dir = tempfile::tempdir().unwrap();
+
+    qot_in(data_dir.path())
+        .arg("sync")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("qot login"));
+}
+
+#[test]
+fn test_sync_uploads_and_downloads_notes() {
+    let server = qot_client::mock::MockServer::start();
+    let data_dir = logged_in_home(&server, "ada@example.com");
+
+    qot_in(data_dir.path())
+        .args(["add", "from", "the", "cli"])
+        .assert()
+        .success();
+
+    qot_in(data_dir.path())
+        .arg("sync")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "1 created, 0 updated, 0 merged, 0 deleted",
+        ));
+    assert_eq!(server.notes("user-1").len(), 1);
+
+    let remote = crdt_note::Note::new("from the web");
+    server.put_note("user-1", &remote.id(), &crdt_note::Note::into(&remote));
+
+    qot_in(data_dir.path())
+        .arg("sync")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("1 created"));
+
+    qot_in(data_dir.path())
+        .arg("list")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("from the cli"))
+        .stdout(predicate::str::contains("from the web"));
+
+    qot_in(data_dir.path())
+        .args(["delete", "1"])
+        .assert()
+        .success();
+    qot_in(data_dir.path())
+        .arg("sync")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("1 deleted"));
+    assert_eq!(server.notes("user-1").len(), 1);
+}
+
+#[test]
+fn test_status_counts_changes_until_synced() {
+    let server = qot_client::mock::MockServer::start();
+    let data_dir = logged_in_home(&server, "ada@example.com");
+
+    qot_in(data_dir.path())
+        .args(["add", "first"])
+        .assert()
+        .success();
+    qot_in(data_dir.path())
+        .args(["add", "second"])
+        .assert()
+        .success();
+    qot_in(data_dir.path())
+        .args(["delete", "1"])
+        .assert()
+        .success();
+
+    qot_in(data_dir.path())
+        .arg("status")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(format!(
+            "Logged in as ada@example.com on {}",
+            server.url()
+        )))
+        .stdout(predicate::str::contains("Changes waiting to be pushed: 3"));
+
+    qot_in(data_dir.path()).arg("sync").assert().success();
+
+    qot_in(data_dir.path())
+        .arg("status")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Changes waiting to be pushed: 0"));
+    assert_eq!(server.notes("user-1").len(), 1);
+}
+
+#[test]
+fn test_sync_keeps_refreshed_token() {
+    let server = qot_client::mock::MockServer::start();
+    let data_dir = logged_in_home(&server, "ada@example.com");
+    let session_path = data_dir.path().join("session.json");
+    let before = std::fs::read_to_string(&session_path).unwrap();
+
+    server.expire_access_tokens();
+    qot_in(data_dir.path()).arg("sync").assert().success();
+
+    let after = std::fs::read_to_string(&session_path).unwrap();
+    assert_ne!(before, after);
+    qot_in(data_dir.path()).arg("sync").assert().success();
+}
+
+// Polls until `check` passes or five seconds go by
+fn eventually(mut check: impl FnMut() -> bool) -> bool {
+    (0..100).any(|_| {
+        if check() {
+            return true;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        false
+    })
+}
+
+#[cfg(unix)]
+#[test]
+fn test_watch_syncs_both_ways_until_terminated() {
+    use std::process::Stdio;
+
+    let server = qot_client::mock::MockServer::start();
+    let data_dir = logged_in_home(&server, "ada@example.com");
+    let listed = || {
+        let output = qot_in(data_dir.path()).arg("list").output().unwrap();
+        String::from_utf8_lossy(&output.stdout).to_string()
+    };
+
+    let watch = spawn_qot_in(data_dir.path())
+        .arg("watch")
+        .env("QOT_SOCKET_URL", server.socket_url())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    assert!(eventually(|| {
+        let output = qot_in(data_dir.path())
+            .args(["watch", "--status"])
+            .output()
+            .unwrap();
+        String::from_utf8_lossy(&output.stdout).contains(": connected")
+    }));
+
+    // A second daemon would sync against the first
+    qot_in(data_dir.path())
+        .arg("watch")
+        .env("QOT_SOCKET_URL", server.socket_url())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("already running"));
+    qot_in(data_dir.path())
+        .args(["watch", "--status"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(": connected"));
+
+    // Pushed from another device
+    let remote = crdt_note::Note::new("from the web");
+    server.put_note("user-1", &remote.id(), &crdt_note::Note::into(&remote));
+    assert!(eventually(|| listed().contains("from the web")));
+
+    // Written by another qot invocation
+    qot_in(data_dir.path())
+        .args(["add", "from", "the", "cli"])
+        .assert()
+        .success();
+    assert!(eventually(|| server.notes("user-1").len() == 2));
+
+    // Deleted here while the connection is down, and the token it
+    // connected with has expired
+    server.expire_access_tokens();
+    server.disconnect_sockets();
+    qot_in(data_dir.path())
+        .args(["delete", "1"])
+        .assert()
+        .success();
+    assert!(eventually(|| server.notes("user-1").len() == 1));
+
+    std::process::Command::new("kill")
+        .args(["-TERM", &watch.id().to_string()])
+        .status()
+        .unwrap();
+    let output = watch.wait_with_output().unwrap();
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Stopped"));
+
+    qot_in(data_dir.path())
+        .args(["watch", "--status"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("not running"));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_watch_stops_on_sigterm_while_offline() {
+    use std::process::Stdio;
+
+    let server = qot_client::mock::MockServer::start();
+    let data_dir = logged_in_home(&server, "ada@example.com");
+    let mut watch = spawn_qot_in(data_dir.path())
+        .arg("watch")
+        .env("QOT_SOCKET_URL", "ws://127.0.0.1:9/socket/websocket")
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    assert!(eventually(|| {
+        let output = qot_in(data_dir.path())
+            .args(["watch", "--status"])
+            .output()
+            .unwrap();
+        String::from_utf8_lossy(&output.stdout).contains(": disconnected")
+    }));
+    // Into the wait between attempts to reconnect
+    std::thread::sleep(std::time::Duration::from_millis(300));
+
+    std::process::Command::new("kill")
+        .args(["-TERM", &watch.id().to_string()])
+        .status()
+        .unwrap();
+    let exited = eventually(|| watch.try_wait().unwrap().is_some());
+    if !exited {
+        watch.kill().ok();
+    }
+    assert!(exited, "qot watch kept running after SIGTERM");
+    let output = watch.wait_with_output().unwrap();
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Stopped"));
+}
+
+// Hands out one id, whatever it is
+struct FixedId(&'static str);
+
+impl crdt_note::IdGenerator for FixedId {
+    fn next_id(&mut self) -> String {
+        self.0.to_string()
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn test_watch_ignores_ids_outside_the_notes_dir() {
+    use std::process::Stdio;
+
+    let server = qot_client::mock::MockServer::start();
+    let data_dir = logged_in_home(&server, "ada@example.com");
+    let listed = || {
+        let output = qot_in(data_dir.path()).arg("list").output().unwrap();
+        String::from_utf8_lossy(&output.stdout).to_string()
+    };
+
+    let watch = spawn_qot_in(data_dir.path())
+        .arg("watch")
+        .env("QOT_SOCKET_URL", server.socket_url())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    assert!(eventually(|| {
+        let output = qot_in(data_dir.path())
+            .args(["watch", "--status"])
+            .output()
+            .unwrap();
+        String::from_utf8_lossy(&output.stdout).contains(": connected")
+    }));
+
+    // A note file just outside the notes directory, and one to plant there
+    let outside = crdt_note::Note::generate(&mut FixedId("../outside"), "outside");
+    let outside_file = data_dir.path().join("outside.note");
+    std::fs::write(&outside_file, crdt_note::Note::into(&outside)).unwrap();
+    let planted = crdt_note::Note::generate(&mut FixedId("../planted"), "planted");
+    server.delete_note("user-1", "../outside");
+    server.put_note("user-1", "../planted", &crdt_note::Note::into(&planted));
+
+    // Messages arrive in order, so once this one is in the others are done
+    let after = crdt_note::Note::new("after the invalid ids");
+    server.put_note("user-1", &after.id(), &crdt_note::Note::into(&after));
+    assert!(eventually(|| listed().contains("after the invalid ids")));
+    assert!(outside_file.exists());
+    assert!(!data_dir.path().join("planted.note").exists());
+
+    std::process::Command::new("kill")
+        .args(["-TERM", &watch.id().to_string()])
+        .status()
+        .unwrap();
+    let output = watch.wait_with_output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Ignored a deletion of invalid id \"../outside\""));
+    assert!(stdout.contains("Ignored a note with invalid id \"../planted\""));
+}
+
+#[test]
+fn test_sync_with_peer() {
+    use std::io::BufRead;
+    use std::process::Stdio;
+
+    let serving_home = tempfile::tempdir().unwrap();
+    let syncing_home = tempfile::tempdir().unwrap();
+
+    qot_in(serving_home.path())
+        .args(["add", "from", "the", "desktop"])
+        .assert()
+        .success();
+    qot_in(syncing_home.path())
+        .args(["add", "from", "the", "laptop"])
+        .assert()
+        .success();
+
+    let mut serve = spawn_qot_in(serving_home.path())
+        .args(["serve", "--listen", "127.0.0.1:0"])
+        .env("QOT_PEER_KEY", "correct horse")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let mut stdout = std::io::BufReader::new(serve.stdout.take().unwrap());
+    let mut line = String::new();
+    stdout.read_line(&mut line).unwrap();
+    let address = line
+        .trim()
+        .strip_prefix("Listening on ")
+        .unwrap()
+        .to_string();
+
+    qot_in(syncing_home.path())
+        .args(["sync", "--peer", &address])
+        .env("QOT_PEER_KEY", "wrong")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("rejected the key"));
+
+    qot_in(syncing_home.path())
+        .args(["sync", "--peer", &address])
+        .env("QOT_PEER_KEY", "correct horse")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("1 updated here, 1 updated there"));
+
+    serve.kill().unwrap();
+    serve.wait().unwrap();
+
+    for home in [&serving_home, &syncing_home] {
+        qot_in(home.path())
+            .arg("list")
+            .assert()
+            .success()
+            .stdout(predicate::str::contains("from the desktop"))
+            .stdout(predicate::str::contains("from the laptop"));
+    }
+}
+
+#[test]
+fn test_reconcile_merges_conflict_copies() {
+    let data_dir = tempfile::tempdir().unwrap();
+    qot_in(data_dir.path())
+        .args(["add", "milk"])
+        .assert()
+        .success();
+
+    // What a file sync tool leaves when two devices write the same note
+    let notes_dir = data_dir.path().join("notes");
+    let note_files = || {
+        std::fs::read_dir(&notes_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| {
+                path.extension()
+                    .is_some_and(|extension| extension == "note")
+            })
+            .collect::<Vec<_>>()
+    };
+    let note = note_files().remove(0);
+    let id = note.file_stem().unwrap().to_string_lossy().to_string();
+    std::fs::copy(
+        &note,
+        notes_dir.join(format!("{}.sync-conflict-20250101-120000-ABC.note", id)),
+    )
+    .unwrap();
+
+    qot_in(data_dir.path())
+        .arg("reconcile")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Merged 1 conflict copies"));
+    assert_eq!(note_files(), [note]);
+
+    qot_in(data_dir.path())
+        .arg("reconcile")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("No conflict copies found"));
+}
+
+#[test]
+fn test_parallel_edits_to_one_note_all_land() {
+    assert_parallel_edits_land("files");
+}
+
+#[test]
+fn test_parallel_edits_with_sqlite_storage() {
+    assert_parallel_edits_land("sqlite");
+}
+
+#[test]
+fn test_parallel_edits_with_log_storage() {
+    assert_parallel_edits_land("log");
+}
+
+#[test]
+fn test_list_with_only_archived_notes() {
+    let data_dir = tempfile::tempdir().unwrap();
+    qot_in(data_dir.path())
+        .args(["add", "milk"])
+        .assert()
+        .success();
+    qot_in(data_dir.path())
+        .args(["archive", "1"])
+        .assert()
+        .success();
+
+    qot_in(data_dir.path())
+        .arg("list")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("qot list --archived"));
+    qot_in(data_dir.path())
+        .args(["list", "--archived"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("milk"));
+}
+
+fn assert_parallel_edits_land(storage: &str) {
+    let data_dir = tempfile::tempdir().unwrap();
+    qot_in(data_dir.path())
+        .args(["add", "milk"])
+        .env("QOT_STORAGE", storage)
+        .assert()
+        .success();
+
+    // Each process reads the note before the others have written it
+    let edits: Vec<_> = ["pin", "archive", "pin", "archive", "pin", "archive"]
+        .iter()
+        .map(|command| {
+            spawn_qot_in(data_dir.path())
+                .args([command, "1"])
+                .env("QOT_STORAGE", storage)
+                .stdout(std::process::Stdio::null())
+                .spawn()
+                .unwrap()
+        })
+        .collect();
+    for mut edit in edits {
+        assert!(edit.wait().unwrap().success());
+    }
+
+    let export = qot_in(data_dir.path())
+        .arg("export")
+        .env("QOT_STORAGE", storage)
+        .output()
+        .unwrap();
+    let notes: serde_json::Value = serde_json::from_slice(&export.stdout).unwrap();
+    assert_eq!(notes.as_array().unwrap().len(), 1);
+    assert_eq!(notes[0]["metadata"]["pinned"], true);
+    assert_eq!(notes[0]["metadata"]["archived"], true);
+}
+
+#[test]
+fn test_migrate_storage_between_backends() {
+    let data_dir = tempfile::tempdir().unwrap();
+    for content in ["first", "second", "third"] {
+        qot_in(data_dir.path())
+            .args(["add", content])
+            .assert()
+            .success();
+    }
+    qot_in(data_dir.path())
+        .args(["pin", "2"])
+        .assert()
+        .success();
+    let before = qot_in(data_dir.path()).arg("export").output().unwrap();
+
+    qot_in(data_dir.path())
+        .args(["migrate-storage", "--to", "sqlite"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Moved 3 notes to sqlite storage"));
+    assert!(data_dir.path().join("notes.db").exists());
+    qot_in(data_dir.path())
+        .args(["config", "get", "storage"])
+        .assert()
+        .stdout("sqlite\n");
+
+    let after = qot_in(data_dir.path()).arg("export").output().unwrap();
+    assert_eq!(after.stdout, before.stdout);
+    qot_in(data_dir.path())
+        .args(["add", "fourth"])
+        .assert()
+        .success();
+
+    // The old backend no longer holds the notes
+    qot_in(data_dir.path())
+        .arg("list")
+        .env("QOT_STORAGE", "files")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("qot migrate-storage --to files"));
+
+    qot_in(data_dir.path())
+        .args(["migrate-storage", "--to", "log"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Moved 4 notes to log storage"));
+    assert!(!data_dir.path().join("notes.db").exists());
+    qot_in(data_dir.path())
+        .args(["pin", "4"])
+        .assert()
+        .success();
+
+    qot_in(data_dir.path())
+        .args(["migrate-storage", "--to", "files"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Moved 4 notes to files storage"));
+    assert!(!data_dir.path().join("notes.log").exists());
+    qot_in(data_dir.path())
+        .arg("list")
+        .assert()
+        .success()
+        .stdout("1. [pinned] second\n2. [pinned] fourth\n3. first\n4. third\n");
+}
@@ -1,5 +1,6 @@
 import type { Network } from "./index";
 import * as messages from "./messages";
+import type { WireNote } from "./wire";
 
 type Message = messages.Message;
 
@@ -34,12 +35,12 @@ export class Http implements Network {
 
   async #sendNote(note: messages.Note): Promise<void> {
     try {
-      const base64 = btoa(String.fromCharCode(...note.bytes));
+      const { id, data } = messages.toWire(note) as WireNote;
 
       await fetch(`${this.#baseUrl}/api/notes/${note.id}`, {
         method: "PUT",
         headers: { "Content-Type": "application/json" },
-        body: JSON.stringify({ id: note.id, data: base64 }),
+        body: JSON.stringify({ id, data }),
       });
     } catch (err) {
       console.error("[http] send error:", err);
@@ -61,15 +62,10 @@ export class Http implements Network {
       const response = await fetch(`${this.#baseUrl}/api/notes`);
       const json = await response.json();
 
-      const notesList = json.notes.map(
-        (note: { id: string; data: string }) => ({
-          id: note.id,
-          bytes: Uint8Array.from(atob(note.data), (c) => c.charCodeAt(0)),
-        }),
+      const notesMessage = messages.notes(
+        messages.fromWireNotes(json.notes as WireNote[]),
       );
 
-      const notesMessage = messages.notes(notesList);
-
       this.#listeners.forEach((listener) => {
         try {
           listener(notesMessage);
@@ -1,3 +1,5 @@
+import type { WireMessage, WireNote } from "./wire";
+
 export interface Message {
   type: string;
 }
@@ -47,3 +49,51 @@ export function delete_(id: string): Delete {
     id,
   };
 }
+
+// The JSON shapes the server speaks, where note bytes are base64 strings.
+// `wire.ts` is generated from the Rust definitions in crdt_note.
+export function toWire(message: Message): WireMessage | Message {
+  switch (message.type) {
+    case "note": {
+      const { id, bytes } = message as Note;
+      return { type: "note", id, data: toBase64(bytes) };
+    }
+    case "notes":
+      return {
+        type: "notes",
+        notes: (message as Notes).notes.map(({ id, bytes }) => ({
+          id,
+          data: toBase64(bytes),
+        })),
+      };
+    default:
+      return message;
+  }
+}
+
+export function fromWire(wire: WireMessage): Message {
+  switch (wire.type) {
+    case "note":
+      return note(wire.id, fromBase64(wire.data));
+    case "notes":
+      return notes(fromWireNotes(wire.notes));
+    case "delete":
+      return delete_(wire.id);
+    default:
+      return wire;
+  }
+}
+
+export function fromWireNotes(
+  wireNotes: WireNote[],
+): { id: string; bytes: Uint8Array }[] {
+  return wireNotes.map(({ id, data }) => ({ id, bytes: fromBase64(data) }));
+}
+
+function toBase64(bytes: Uint8Array): string {
+  return btoa(String.fromCharCode(...bytes));
+}
+
+function fromBase64(data: string): Uint8Array {
+  return Uint8Array.from(atob(data), (c) => c.charCodeAt(0));
+}
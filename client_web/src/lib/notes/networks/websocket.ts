@@ -73,20 +73,7 @@ export class WebSocket implements Network {
     }
 
     // Convert message to match server format (base64 encoded data)
-    let payload: any;
-
-    if (message.type === "note") {
-      const noteMsg = message as messages.Note;
-      payload = {
-        type: "note",
-        id: noteMsg.id,
-        data: btoa(String.fromCharCode(...noteMsg.bytes)),
-      };
-    } else if (message.type === "delete") {
-      payload = message;
-    } else {
-      payload = message;
-    }
+    const payload = messages.toWire(message);
 
     // Wrap in Phoenix Channel protocol
     const channelMessage = {
@@ -134,39 +121,9 @@ export class WebSocket implements Network {
       }
 
       if (channelMsg.event === "message") {
-        // Extract our application message from the channel payload
-        const parsed = channelMsg.payload;
-
-        // Reconstruct Uint8Array fields if needed
-        let message: Message;
-
-        if (parsed.type === "note") {
-          // Phoenix sends base64 encoded data, need to decode
-          const binaryString = atob(parsed.data);
-          const bytes = new Uint8Array(binaryString.length);
-          for (let i = 0; i < binaryString.length; i++) {
-            bytes[i] = binaryString.charCodeAt(i);
-          }
-          message = messages.note(parsed.id, bytes);
-        } else if (parsed.type === "notes") {
-          message = messages.notes(
-            parsed.notes.map((n: { id: string; data: string }) => {
-              const binaryString = atob(n.data);
-              const bytes = new Uint8Array(binaryString.length);
-              for (let i = 0; i < binaryString.length; i++) {
-                bytes[i] = binaryString.charCodeAt(i);
-              }
-              return {
-                id: n.id,
-                bytes: bytes,
-              };
-            }),
-          );
-        } else if (parsed.type === "delete") {
-          message = messages.delete_(parsed.id);
-        } else {
-          message = parsed;
-        }
+        // Extract our application message from the channel payload,
+        // decoding the base64 note data
+        const message = messages.fromWire(channelMsg.payload);
 
         this.#notifyListeners(message);
       }
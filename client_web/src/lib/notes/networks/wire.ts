@@ -0,0 +1,12 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+export type WireMessage = { "type": "note" } & WireNote | { "type": "notes", notes: Array<WireNote>, } | { "type": "delete", id: string, };
+
+/**
+ * A note's id and its `Note::into` bytes.
+ */
+export type WireNote = { id: string, 
+/**
+ * Base64 in JSON
+ */
+data: string, };
@@ -0,0 +1,16 @@
+#![no_main]
+
+use crdt_note::Note;
+use libfuzzer_sys::fuzz_target;
+
+// Bytes from disk or the network must never panic, only load as a note or
+// fall back to an empty one.
+fuzz_target!(|bytes: &[u8]| {
+    let note = Note::from(bytes);
+    let _ = note.id();
+    let _ = note.content();
+    let _ = note.conflicts();
+
+    let reloaded = Note::from(&Note::into(&note));
+    assert_eq!(reloaded.content(), note.content());
+});
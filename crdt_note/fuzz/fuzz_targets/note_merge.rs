@@ -0,0 +1,31 @@
+#![no_main]
+
+use arbitrary::Arbitrary;
+use crdt_note::Note;
+use libfuzzer_sys::fuzz_target;
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    base: String,
+    left: Vec<String>,
+    right: Vec<String>,
+    foreign: Vec<u8>,
+}
+
+// Two replicas edited independently converge to the same content whichever
+// way they are merged, and merging arbitrary bytes never panics.
+fuzz_target!(|input: Input| {
+    let bytes = Note::into(&Note::new(&input.base));
+    let left = input
+        .left
+        .iter()
+        .fold(Note::from(&bytes), |note, edit| note.update(edit));
+    let right = input
+        .right
+        .iter()
+        .fold(Note::from(&bytes), |note, edit| note.update(edit));
+
+    assert_eq!(left.merge(&right).content(), right.merge(&left).content());
+
+    let _ = left.merge(&Note::from(&input.foreign)).content();
+});
@@ -0,0 +1,299 @@
+use argon2::Argon2;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use wasm_bindgen::prelude::*;
+
+// Envelope layout, version 1:
+//
+//   magic "QOTE" | version (1 byte) | salt (16 bytes) | nonce (24 bytes) | ciphertext
+//
+// The header before the nonce is authenticated along with the ciphertext, so
+// a tampered version or salt fails to open rather than being misread.
+const MAGIC: &[u8; 4] = b"QOTE";
+const VERSION: u8 = 1;
+pub const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+const KEY_LEN: usize = 32;
+const HEADER_LEN: usize = MAGIC.len() + 1 + SALT_LEN;
+
+pub type EnvelopeResult<T> = Result<T, EnvelopeError>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EnvelopeError {
+    /// The bytes are not a sealed envelope
+    Malformed,
+    /// The envelope was sealed with a newer format
+    UnsupportedVersion(u8),
+    /// The envelope was sealed with a key derived from another salt
+    SaltMismatch,
+    Encryption,
+    /// The key is wrong or the envelope was tampered with
+    Decryption,
+    KeyDerivation(String),
+    Random(String),
+}
+
+impl std::fmt::Display for EnvelopeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            EnvelopeError::Malformed => write!(f, "Not a sealed note envelope"),
+            EnvelopeError::UnsupportedVersion(v) => {
+                write!(f, "Unsupported envelope version: {}", v)
+            }
+            EnvelopeError::SaltMismatch => write!(f, "Envelope was sealed with a different key"),
+            EnvelopeError::Encryption => write!(f, "Failed to encrypt note"),
+            EnvelopeError::Decryption => write!(f, "Wrong passphrase or tampered envelope"),
+            EnvelopeError::KeyDerivation(e) => write!(f, "Key derivation error: {}", e),
+            EnvelopeError::Random(e) => write!(f, "Random number error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for EnvelopeError {}
+
+/// A symmetric key derived from a passphrase with Argon2id, used to seal the
+/// bytes from `Note::into` before they leave the device.
+///
+/// The salt travels in every envelope, so any device that knows the
+/// passphrase can derive the same key from an envelope it receives.
+#[wasm_bindgen]
+pub struct SealingKey {
+    salt: [u8; SALT_LEN],
+    key: [u8; KEY_LEN],
+    cipher: XChaCha20Poly1305,
+}
+
+impl SealingKey {
+    /// Derives a key with a fresh random salt.
+    pub fn generate(passphrase: &str) -> EnvelopeResult<Self> {
+        let mut salt = [0u8; SALT_LEN];
+        getrandom::fill(&mut salt).map_err(|e| EnvelopeError::Random(e.to_string()))?;
+        SealingKey::derive(passphrase, &salt)
+    }
+
+    pub fn derive(passphrase: &str, salt: &[u8]) -> EnvelopeResult<Self> {
+        let salt: [u8; SALT_LEN] = salt.try_into().map_err(|_| {
+            EnvelopeError::KeyDerivation(format!("salt must be {} bytes", SALT_LEN))
+        })?;
+
+        let mut key = [0u8; KEY_LEN];
+        Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), &salt, &mut key)
+            .map_err(|e| EnvelopeError::KeyDerivation(e.to_string()))?;
+
+        Ok(SealingKey::with_key(salt, key))
+    }
+
+    /// The salt and derived key, for holding an unlocked key in memory
+    /// elsewhere (e.g. a key agent process). Never write these to disk.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        [self.salt.as_slice(), self.key.as_slice()].concat()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> EnvelopeResult<Self> {
+        if bytes.len() != SALT_LEN + KEY_LEN {
+            return Err(EnvelopeError::KeyDerivation(format!(
+                "key must be {} bytes",
+                SALT_LEN + KEY_LEN
+            )));
+        }
+
+        let (salt, key) = bytes.split_at(SALT_LEN);
+        Ok(SealingKey::with_key(
+            salt.try_into().unwrap_or_default(),
+            key.try_into().unwrap_or_default(),
+        ))
+    }
+
+    fn with_key(salt: [u8; SALT_LEN], key: [u8; KEY_LEN]) -> Self {
+        Self {
+            salt,
+            key,
+            cipher: XChaCha20Poly1305::new(&key.into()),
+        }
+    }
+
+    pub fn salt(&self) -> Vec<u8> {
+        self.salt.to_vec()
+    }
+
+    pub fn seal(&self, plaintext: &[u8]) -> EnvelopeResult<Vec<u8>> {
+        let mut nonce = [0u8; NONCE_LEN];
+        getrandom::fill(&mut nonce).map_err(|e| EnvelopeError::Random(e.to_string()))?;
+
+        let mut envelope = Vec::with_capacity(HEADER_LEN + NONCE_LEN + plaintext.len() + 16);
+        envelope.extend_from_slice(MAGIC);
+        envelope.push(VERSION);
+        envelope.extend_from_slice(&self.salt);
+
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                XNonce::from_slice(&nonce),
+                Payload {
+                    msg: plaintext,
+                    aad: &envelope,
+                },
+            )
+            .map_err(|_| EnvelopeError::Encryption)?;
+
+        envelope.extend_from_slice(&nonce);
+        envelope.extend_from_slice(&ciphertext);
+        Ok(envelope)
+    }
+
+    pub fn unseal(&self, envelope: &[u8]) -> EnvelopeResult<Vec<u8>> {
+        if envelope_salt(envelope)? != self.salt {
+            return Err(EnvelopeError::SaltMismatch);
+        }
+
+        let (header, rest) = envelope.split_at(HEADER_LEN);
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+
+        self.cipher
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: header,
+                },
+            )
+            .map_err(|_| EnvelopeError::Decryption)
+    }
+}
+
+// Never print key material
+impl std::fmt::Debug for SealingKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("SealingKey")
+            .field("salt", &self.salt)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The salt an envelope was sealed with, for deriving the matching key.
+pub fn envelope_salt(envelope: &[u8]) -> EnvelopeResult<[u8; SALT_LEN]> {
+    if envelope.len() < HEADER_LEN + NONCE_LEN || !envelope.starts_with(MAGIC) {
+        return Err(EnvelopeError::Malformed);
+    }
+
+    let version = envelope[MAGIC.len()];
+    if version != VERSION {
+        return Err(EnvelopeError::UnsupportedVersion(version));
+    }
+
+    let mut salt = [0u8; SALT_LEN];
+    salt.copy_from_slice(&envelope[MAGIC.len() + 1..HEADER_LEN]);
+    Ok(salt)
+}
+
+/// Whether the bytes look like a sealed envelope rather than a plain note.
+pub fn is_sealed(bytes: &[u8]) -> bool {
+    envelope_salt(bytes).is_ok()
+}
+
+#[wasm_bindgen]
+impl SealingKey {
+    #[wasm_bindgen(js_name = generate)]
+    pub fn generate_js(passphrase: &str) -> Result<SealingKey, JsError> {
+        SealingKey::generate(passphrase).map_err(|e| JsError::new(&e.to_string()))
+    }
+
+    #[wasm_bindgen(js_name = derive)]
+    pub fn derive_js(passphrase: &str, salt: &[u8]) -> Result<SealingKey, JsError> {
+        SealingKey::derive(passphrase, salt).map_err(|e| JsError::new(&e.to_string()))
+    }
+
+    /// Derives the key for a received envelope from the salt it carries.
+    #[wasm_bindgen(js_name = forEnvelope)]
+    pub fn for_envelope_js(passphrase: &str, envelope: &[u8]) -> Result<SealingKey, JsError> {
+        envelope_salt(envelope)
+            .and_then(|salt| SealingKey::derive(passphrase, &salt))
+            .map_err(|e| JsError::new(&e.to_string()))
+    }
+
+    #[wasm_bindgen(js_name = salt)]
+    pub fn salt_js(&self) -> Vec<u8> {
+        self.salt()
+    }
+
+    #[wasm_bindgen(js_name = seal)]
+    pub fn seal_js(&self, plaintext: &[u8]) -> Result<Vec<u8>, JsError> {
+        self.seal(plaintext)
+            .map_err(|e| JsError::new(&e.to_string()))
+    }
+
+    #[wasm_bindgen(js_name = unseal)]
+    pub fn unseal_js(&self, envelope: &[u8]) -> Result<Vec<u8>, JsError> {
+        self.unseal(envelope)
+            .map_err(|e| JsError::new(&e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Note;
+
+    #[test]
+    fn test_seal_and_unseal_note() {
+        let note = Note::new("secret thought");
+        let key = SealingKey::generate("correct horse").unwrap();
+
+        let envelope = key.seal(&Note::into(&note)).unwrap();
+        assert!(is_sealed(&envelope));
+        assert!(!is_sealed(&Note::into(&note)));
+
+        // Another device derives the key from the passphrase and the salt
+        let salt = envelope_salt(&envelope).unwrap();
+        let other_key = SealingKey::derive("correct horse", &salt).unwrap();
+        let opened = Note::from(&other_key.unseal(&envelope).unwrap());
+        assert_eq!(opened.id(), note.id());
+        assert_eq!(opened.content(), "secret thought");
+    }
+
+    #[test]
+    fn test_to_bytes_and_from_bytes() {
+        let key = SealingKey::generate("correct horse").unwrap();
+        let envelope = key.seal(b"bytes").unwrap();
+
+        let restored = SealingKey::from_bytes(&key.to_bytes()).unwrap();
+        assert_eq!(restored.salt(), key.salt());
+        assert_eq!(restored.unseal(&envelope).unwrap(), b"bytes");
+
+        assert!(SealingKey::from_bytes(&[0u8; 12]).is_err());
+    }
+
+    #[test]
+    fn test_unseal_with_wrong_passphrase() {
+        let key = SealingKey::generate("correct horse").unwrap();
+        let envelope = key.seal(b"bytes").unwrap();
+
+        let wrong = SealingKey::derive("battery staple", &key.salt()).unwrap();
+        assert_eq!(wrong.unseal(&envelope), Err(EnvelopeError::Decryption));
+
+        let other_salt = SealingKey::generate("correct horse").unwrap();
+        assert_eq!(
+            other_salt.unseal(&envelope),
+            Err(EnvelopeError::SaltMismatch)
+        );
+    }
+
+    #[test]
+    fn test_unseal_tampered_or_malformed() {
+        let key = SealingKey::generate("correct horse").unwrap();
+        let mut envelope = key.seal(b"bytes").unwrap();
+
+        let last = envelope.len() - 1;
+        envelope[last] ^= 1;
+        assert_eq!(key.unseal(&envelope), Err(EnvelopeError::Decryption));
+
+        envelope[MAGIC.len()] = 9;
+        assert_eq!(
+            key.unseal(&envelope),
+            Err(EnvelopeError::UnsupportedVersion(9))
+        );
+
+        assert_eq!(key.unseal(b"QOTE"), Err(EnvelopeError::Malformed));
+    }
+}
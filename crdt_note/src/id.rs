@@ -0,0 +1,62 @@
+use uuid::{Builder, Uuid};
+
+/// Source of ids for new notes.
+///
+/// Ids are expected to sort by creation time, which UUIDv7 gives for free.
+pub trait IdGenerator {
+    fn next_id(&mut self) -> String;
+}
+
+/// UUIDv7 ids from the system clock, as used by `Note::new`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemIdGenerator;
+
+impl IdGenerator for SystemIdGenerator {
+    fn next_id(&mut self) -> String {
+        Uuid::now_v7().to_string()
+    }
+}
+
+/// Deterministic UUIDv7 ids from a millisecond counter, one millisecond
+/// apart, for tests that depend on creation order.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SequentialIdGenerator {
+    millis: u64,
+}
+
+impl SequentialIdGenerator {
+    pub fn starting_at(millis: u64) -> Self {
+        Self { millis }
+    }
+}
+
+impl IdGenerator for SequentialIdGenerator {
+    fn next_id(&mut self) -> String {
+        let counter = self.millis.to_be_bytes();
+        let mut random = [0u8; 10];
+        random[2..].copy_from_slice(&counter);
+
+        let id = Builder::from_unix_timestamp_millis(self.millis, &random).into_uuid();
+        self.millis += 1;
+        id.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sequential_ids_are_ordered_and_repeatable() {
+        let mut ids = SequentialIdGenerator::starting_at(1_700_000_000_000);
+        let first = ids.next_id();
+        let second = ids.next_id();
+
+        assert!(first < second);
+        assert_eq!(Uuid::try_parse(&first).unwrap().get_version_num(), 7);
+        assert_eq!(
+            SequentialIdGenerator::starting_at(1_700_000_000_000).next_id(),
+            first
+        );
+    }
+}
@@ -0,0 +1,61 @@
+use crate::Note;
+use automerge::{AutoCommit, ChangeHash};
+
+impl Note {
+    /// The changes made after `heads`, in automerge's incremental format: a
+    /// saved note with those heads followed by these bytes loads as this
+    /// note. Empty if there are none.
+    pub fn changes_since(&self, heads: &[String]) -> Vec<u8> {
+        let heads: Vec<ChangeHash> = heads.iter().filter_map(|hash| hash.parse().ok()).collect();
+        self.doc.clone().save_after(&heads)
+    }
+}
+
+/// A saved note followed by incremental changes, saved again as one
+/// snapshot. `None` if the bytes are not a saved note.
+pub fn compact(bytes: &[u8]) -> Option<Vec<u8>> {
+    AutoCommit::load(bytes).ok().map(|mut doc| doc.save())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_changes_since_append_to_the_old_note() {
+        let old = Note::new("milk");
+        let new = old.update("oat milk").set_pinned(true);
+
+        let changes = new.changes_since(&old.heads());
+        assert!(changes.len() < Note::into(&new).len());
+
+        let mut log = Note::into(&old);
+        log.extend_from_slice(&changes);
+        let loaded = Note::from(&log);
+        assert_eq!(loaded.content(), "oat milk");
+        assert!(loaded.pinned());
+        assert_eq!(loaded.heads(), new.heads());
+
+        assert_eq!(compact(&log), Some(Note::into(&new)));
+    }
+
+    #[test]
+    fn test_nothing_new_is_empty() {
+        let note = Note::new("same");
+        assert!(note.changes_since(&note.heads()).is_empty());
+    }
+
+    #[test]
+    fn test_changes_since_nothing_is_the_whole_note() {
+        let note = Note::new("whole").set_archived(true);
+
+        let loaded = Note::from(&note.changes_since(&[]));
+        assert_eq!(loaded.id(), note.id());
+        assert_eq!(loaded.heads(), note.heads());
+    }
+
+    #[test]
+    fn test_compact_rejects_other_bytes() {
+        assert_eq!(compact(b"not a note"), None);
+    }
+}
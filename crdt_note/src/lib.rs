@@ -1,37 +1,68 @@
+mod envelope;
+mod id;
+mod incremental;
+mod patch;
+mod peer;
+mod signing;
+#[cfg(feature = "serde")]
+mod snapshot;
+#[cfg(feature = "serde")]
+mod wire;
+
+pub use envelope::{EnvelopeError, EnvelopeResult, SALT_LEN, SealingKey, envelope_salt, is_sealed};
+pub use id::{IdGenerator, SequentialIdGenerator, SystemIdGenerator};
+pub use incremental::compact;
+pub use patch::NotePatch;
+pub use peer::PeerState;
+pub use signing::{ChangeStatus, ChangeVerification, DeviceKey};
+#[cfg(feature = "serde")]
+pub use snapshot::{NoteMetadata, NoteSnapshot};
+#[cfg(feature = "serde")]
+pub use wire::{MAX_FRAME_LEN, WireError, WireMessage, WireNote, WireResult};
+
 use automerge::{
-    AutoCommit, ObjType, ROOT, ReadDoc, ScalarValue, Value, transaction::Transactable,
+    AutoCommit, ChangeHash, ObjId, ObjType, ROOT, ReadDoc, ScalarValue, Value,
+    transaction::Transactable,
 };
-use uuid::Uuid;
 use wasm_bindgen::prelude::*;
 
 #[wasm_bindgen]
 #[derive(Debug)]
 pub struct Note {
     doc: AutoCommit,
+    // Heads of the note this one was derived from, so `patches` can report
+    // what the producing operation changed. Empty for new and loaded notes.
+    before: Vec<ChangeHash>,
+    // Signs every local change made through this note when set
+    signer: Option<DeviceKey>,
 }
 
 #[wasm_bindgen]
 impl Note {
     pub fn new(content: &str) -> Self {
-        let mut doc = AutoCommit::new();
-        let id = Uuid::now_v7().to_string();
+        Note::generate(&mut SystemIdGenerator, content)
+    }
 
-        if let Err(_) = doc.put(ROOT, "id", &id) {
-            return Self { doc: doc };
-        }
+    /// Creates a note with an id from elsewhere, e.g. when importing notes
+    /// from another system. Ids should be UUIDv7 to keep creation order.
+    pub fn new_with_id(id: &str, content: &str) -> Self {
+        Note::build(id, content, None)
+    }
 
-        match doc.put_object(ROOT, "content", ObjType::Text) {
-            Ok(ex_id) => {
-                if let Err(_) = doc.update_text(&ex_id, content) {
-                    return Self { doc: doc };
-                };
-            }
-            Err(_) => {
-                return Self { doc: doc };
-            }
-        }
+    /// Creates a note whose first change is signed with `key`, and which
+    /// signs its later local changes too.
+    pub fn new_signed(content: &str, key: &DeviceKey) -> Self {
+        Note::generate_signed(&mut SystemIdGenerator, content, key)
+    }
 
-        Note { doc: doc }
+    /// A copy of this note that signs the local changes made through it
+    /// with `key`. Changes already in the history are left as they are.
+    pub fn with_signer(&self, key: &DeviceKey) -> Self {
+        Self {
+            doc: self.doc.clone(),
+            before: self.before.clone(),
+            signer: Some(key.clone()),
+        }
     }
 
     pub fn id(&self) -> String {
@@ -40,12 +71,12 @@ impl Note {
         if let Ok(Some((Value::Scalar(v), _))) = doc.get(ROOT, "id") {
             let w = v.as_ref();
             if let ScalarValue::Str(id) = w {
-                return id.as_str().into();
+                id.as_str().into()
             } else {
-                return "".into();
+                "".into()
             }
         } else {
-            return "".into();
+            "".into()
         }
     }
 
@@ -54,22 +85,21 @@ impl Note {
 
         if let Ok(Some((_, ex_id))) = doc.get(ROOT, "content") {
             match doc.text(ex_id) {
-                Ok(content) => {
-                    return content;
-                }
-                Err(_automerge_error) => return "".into(),
+                Ok(content) => content,
+                Err(_automerge_error) => "".into(),
             }
         } else {
-            return "".into();
+            "".into()
         }
     }
 
     pub fn update(&self, new_content: &str) -> Self {
         let mut doc = self.doc.clone();
+        let before = doc.get_heads();
 
         if let Ok(Some((_, ex_id))) = doc.get(ROOT, "content") {
             match doc.update_text(&ex_id, new_content) {
-                Ok(_) => Self { doc: doc },
+                Ok(_) => self.commit(doc, before),
                 Err(_) => Note::empty(),
             }
         } else {
@@ -77,37 +107,286 @@ impl Note {
         }
     }
 
+    pub fn pinned(&self) -> bool {
+        self.flag("pinned")
+    }
+
+    pub fn set_pinned(&self, pinned: bool) -> Self {
+        self.set_flag("pinned", pinned)
+    }
+
+    pub fn archived(&self) -> bool {
+        self.flag("archived")
+    }
+
+    pub fn set_archived(&self, archived: bool) -> Self {
+        self.set_flag("archived", archived)
+    }
+
+    /// Fields that hold more than one distinct value because they were set
+    /// concurrently on different devices. Automerge still picks a winner for
+    /// reads like `id()`, but the losing values are kept until resolved.
+    pub fn conflicts(&self) -> Vec<String> {
+        self.doc
+            .keys(ROOT)
+            .filter(|field| self.field_values(field).len() > 1)
+            .collect()
+    }
+
+    /// The distinct concurrent values of a field, in the same order on every
+    /// device. Text is shown as its content, scalars as plain strings.
+    pub fn conflict_values(&self, field: &str) -> Vec<String> {
+        self.field_values(field)
+            .into_iter()
+            .map(|(shown, _, _)| shown)
+            .collect()
+    }
+
+    /// Settles a conflicted field on the value at `choice` (0-based, as in
+    /// `conflict_values`) by writing it again, which supersedes every
+    /// concurrent value. An out-of-range choice leaves the note unchanged.
+    pub fn resolve(&self, field: &str, choice: usize) -> Self {
+        let mut doc = self.doc.clone();
+        let before = doc.get_heads();
+        let Some((shown, value, _)) = self.field_values(field).into_iter().nth(choice) else {
+            return Self::derived(doc, before);
+        };
+
+        let result = match value {
+            Value::Scalar(v) => doc.put(ROOT, field, v.into_owned()).map(|_| ()),
+            Value::Object(ObjType::Text) => doc
+                .put_object(ROOT, field, ObjType::Text)
+                .and_then(|ex_id| doc.update_text(&ex_id, &shown)),
+            Value::Object(_) => return Self::derived(doc, before),
+        };
+
+        match result {
+            Ok(_) => self.commit(doc, before),
+            Err(_) => Note::empty(),
+        }
+    }
+
     pub fn merge(&self, other: &Note) -> Self {
         let mut doc = self.doc.clone();
         let mut other_doc = other.doc.clone();
+        let before = doc.get_heads();
 
         match doc.merge(&mut other_doc) {
-            Ok(_) => Self { doc: doc },
+            Ok(_) => self.with_doc(doc, before),
             Err(_) => Note::empty(),
         }
     }
 
+    /// Applies changes produced elsewhere, either a full `into()` save or
+    /// incremental changes, on top of this note.
+    pub fn apply(&self, changes: &[u8]) -> Self {
+        let mut doc = self.doc.clone();
+        let before = doc.get_heads();
+
+        match doc.load_incremental(changes) {
+            Ok(_) => self.with_doc(doc, before),
+            Err(_) => Note::empty(),
+        }
+    }
+
+    /// What the operation that produced this note changed, as an array of
+    /// plain objects (see `NotePatch`). A new or loaded note reports its
+    /// whole state.
+    #[wasm_bindgen(js_name = patches)]
+    pub fn patches_js(&self) -> js_sys::Array {
+        self.patches().iter().map(NotePatch::to_js).collect()
+    }
+
+    /// Who signed each change in the note's history, as an array of plain
+    /// objects (see `ChangeVerification`).
+    #[wasm_bindgen(js_name = verify)]
+    pub fn verify_js(&self) -> js_sys::Array {
+        self.verify()
+            .iter()
+            .map(ChangeVerification::to_js)
+            .collect()
+    }
+
+    /// The hex actor id this note's edits are made as, which `NotePatch`
+    /// reports as the actor of the patches they cause.
+    pub fn actor(&self) -> String {
+        self.doc.get_actor().to_hex_string()
+    }
+
+    /// The change hashes at the tip of the note's history, hex encoded. Two
+    /// notes with the same heads have the same state.
+    pub fn heads(&self) -> Vec<String> {
+        self.doc
+            .clone()
+            .get_heads()
+            .iter()
+            .map(|hash| hash.to_string())
+            .collect()
+    }
+
     pub fn into(&self) -> Vec<u8> {
         self.doc.clone().save()
     }
 
     pub fn from(bytes: &[u8]) -> Self {
         match AutoCommit::load(bytes) {
-            Ok(doc) => Self { doc: doc },
+            Ok(doc) => Self::derived(doc, vec![]),
             Err(_) => Note::empty(),
         }
     }
 
+    // Flags are plain scalars on the root map, so concurrent sets resolve
+    // last-writer-wins on merge. A missing flag reads as false, which keeps
+    // notes created before the flag existed valid.
+    fn flag(&self, key: &str) -> bool {
+        if let Ok(Some((Value::Scalar(v), _))) = self.doc.get(ROOT, key) {
+            matches!(v.as_ref(), ScalarValue::Boolean(true))
+        } else {
+            false
+        }
+    }
+
+    fn set_flag(&self, key: &str, value: bool) -> Self {
+        let mut doc = self.doc.clone();
+        let before = doc.get_heads();
+
+        match doc.put(ROOT, key, value) {
+            Ok(_) => self.commit(doc, before),
+            Err(_) => Note::empty(),
+        }
+    }
+
+    fn field_values(&self, field: &str) -> Vec<(String, Value<'_>, ObjId)> {
+        let mut values: Vec<(String, Value<'_>, ObjId)> = Vec::new();
+
+        for (value, ex_id) in self.doc.get_all(ROOT, field).unwrap_or_default() {
+            let shown = match &value {
+                Value::Scalar(v) => scalar_string(v),
+                Value::Object(ObjType::Text) => self.doc.text(&ex_id).unwrap_or_default(),
+                Value::Object(obj_type) => obj_type.to_string(),
+            };
+
+            // Identical values set concurrently are not a real conflict
+            if !values.iter().any(|(existing, _, _)| *existing == shown) {
+                values.push((shown, value, ex_id));
+            }
+        }
+
+        values
+    }
+
     fn empty() -> Self {
-        Self {
+        Self::derived(AutoCommit::new(), vec![])
+    }
+}
+
+impl Note {
+    /// What the operation that produced this note changed. A new or loaded
+    /// note reports its whole state.
+    pub fn patches(&self) -> Vec<NotePatch> {
+        let mut doc = self.doc.clone();
+        let heads = doc.get_heads();
+
+        let mut actors: Vec<String> = doc
+            .get_changes(&self.before)
+            .iter()
+            .map(|change| change.actor_id().to_hex_string())
+            .collect();
+        actors.sort();
+        actors.dedup();
+        let author = match actors.as_slice() {
+            [actor] => Some(actor.clone()),
+            _ => None,
+        };
+
+        doc.diff(&self.before, &heads)
+            .into_iter()
+            .filter_map(|patch| NotePatch::from_patch(&doc, patch, author.as_deref()))
+            .collect()
+    }
+
+    /// Creates a note with an id taken from `ids`.
+    pub fn generate(ids: &mut dyn IdGenerator, content: &str) -> Self {
+        Note::new_with_id(&ids.next_id(), content)
+    }
+
+    /// Like `generate`, but signed with `key` (see `new_signed`).
+    pub fn generate_signed(ids: &mut dyn IdGenerator, content: &str, key: &DeviceKey) -> Self {
+        Note::build(&ids.next_id(), content, Some(key))
+    }
+
+    /// Checks the signature on every change in the note's history, in
+    /// causal order. Whether a signing device is trusted is up to the caller.
+    pub fn verify(&self) -> Vec<ChangeVerification> {
+        signing::verify_changes(&self.doc)
+    }
+
+    fn build(id: &str, content: &str, signer: Option<&DeviceKey>) -> Self {
+        let base = Self {
             doc: AutoCommit::new(),
+            before: vec![],
+            signer: signer.cloned(),
+        };
+        let mut doc = AutoCommit::new();
+
+        if doc.put(ROOT, "id", id).is_err() {
+            return Self::derived(doc, vec![]);
+        }
+
+        match doc.put_object(ROOT, "content", ObjType::Text) {
+            Ok(ex_id) => {
+                if doc.update_text(&ex_id, content).is_err() {
+                    return Self::derived(doc, vec![]);
+                };
+            }
+            Err(_) => {
+                return Self::derived(doc, vec![]);
+            }
+        }
+
+        base.commit(doc, vec![])
+    }
+
+    fn derived(doc: AutoCommit, before: Vec<ChangeHash>) -> Self {
+        Self {
+            doc,
+            before,
+            signer: None,
+        }
+    }
+
+    // Keeps the signer, for docs that only gained changes from elsewhere
+    fn with_doc(&self, doc: AutoCommit, before: Vec<ChangeHash>) -> Self {
+        Self {
+            doc,
+            before,
+            signer: self.signer.clone(),
         }
     }
+
+    // For docs with a new local change on top of this note: signs it when
+    // there is a signer. Falls back to the unsigned change if signing fails.
+    fn commit(&self, mut doc: AutoCommit, before: Vec<ChangeHash>) -> Self {
+        let signed = self
+            .signer
+            .as_ref()
+            .and_then(|key| signing::sign_local_change(&self.doc, &mut doc, key));
+
+        self.with_doc(signed.unwrap_or(doc), before)
+    }
+}
+
+pub(crate) fn scalar_string(value: &ScalarValue) -> String {
+    match value {
+        ScalarValue::Str(s) => s.to_string(),
+        other => other.to_string(),
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use uuid::Uuid;
 
     #[test]
     fn test_new_and_id_and_content() {
@@ -151,4 +430,193 @@ mod tests {
         assert_eq!(note3.id(), note1.id());
         assert_eq!(note3.content(), "cool one two three wow");
     }
+
+    #[test]
+    fn test_pinned_and_archived() {
+        let note = Note::new("flags");
+        assert!(!note.pinned());
+        assert!(!note.archived());
+
+        let note = note.set_pinned(true).set_archived(true);
+        assert!(note.pinned());
+        assert!(note.archived());
+
+        let note = Note::from(&Note::into(&note)).set_pinned(false);
+        assert!(!note.pinned());
+        assert!(note.archived());
+        assert_eq!(note.content(), "flags");
+    }
+
+    #[test]
+    fn test_flags_merge() {
+        let bytes = Note::into(&Note::new("shared"));
+        let note1 = Note::from(&bytes).set_pinned(true);
+        let note2 = Note::from(&bytes).set_archived(true);
+
+        let note3 = note1.merge(&note2);
+        assert!(note3.pinned());
+        assert!(note3.archived());
+    }
+
+    #[test]
+    fn test_conflicts_and_resolve() {
+        let bytes = Note::into(&Note::new("shared"));
+        let note1 = Note::from(&bytes).set_pinned(true);
+        let note2 = Note::from(&bytes).set_pinned(false);
+        let note3 = Note::from(&bytes).set_archived(true);
+        assert!(note1.merge(&note3).conflicts().is_empty());
+
+        let merged = note1.merge(&note2);
+        assert_eq!(merged.conflicts(), vec!["pinned".to_string()]);
+
+        let mut values = merged.conflict_values("pinned");
+        values.sort();
+        assert_eq!(values, vec!["false".to_string(), "true".to_string()]);
+
+        let choice = merged
+            .conflict_values("pinned")
+            .iter()
+            .position(|v| v == "true")
+            .unwrap();
+        let resolved = merged.resolve("pinned", choice);
+        assert!(resolved.conflicts().is_empty());
+        assert!(resolved.pinned());
+        assert_eq!(resolved.content(), "shared");
+
+        // The resolution wins over the values it replaced on other devices
+        let synced = note2.merge(&resolved);
+        assert!(synced.conflicts().is_empty());
+        assert!(synced.pinned());
+    }
+
+    #[test]
+    fn test_identical_concurrent_values_are_not_conflicts() {
+        let bytes = Note::into(&Note::new("shared"));
+        let note1 = Note::from(&bytes).set_pinned(true);
+        let note2 = Note::from(&bytes).set_pinned(true);
+
+        assert!(note1.merge(&note2).conflicts().is_empty());
+    }
+
+    #[test]
+    fn test_patches_from_update() {
+        let note = Note::new("one two");
+        let actor = Some(note.actor());
+        assert!(note.patches().contains(&NotePatch::Insert {
+            field: "content".into(),
+            index: 0,
+            text: "one two".into(),
+            actor: actor.clone(),
+        }));
+
+        let updated = note.update("one two three");
+        assert_eq!(
+            updated.patches(),
+            vec![NotePatch::Insert {
+                field: "content".into(),
+                index: 7,
+                text: " three".into(),
+                actor: actor.clone(),
+            }]
+        );
+
+        let updated = updated.update("one two");
+        assert_eq!(
+            updated.patches(),
+            vec![NotePatch::Remove {
+                field: "content".into(),
+                index: 7,
+                length: 6,
+                actor,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_patches_from_merge_and_apply() {
+        let bytes = Note::into(&Note::new("one"));
+        let note1 = Note::from(&bytes).update("one two");
+        let note2 = Note::from(&bytes).set_pinned(true);
+
+        let merged = note1.merge(&note2);
+        assert_eq!(
+            merged.patches(),
+            vec![NotePatch::Put {
+                field: "pinned".into(),
+                value: "true".into(),
+                conflict: false,
+                actor: Some(note2.actor()),
+            }]
+        );
+
+        let applied = note2.apply(&Note::into(&note1));
+        assert_eq!(applied.content(), "one two");
+        assert_eq!(
+            applied.patches(),
+            vec![NotePatch::Insert {
+                field: "content".into(),
+                index: 3,
+                text: " two".into(),
+                actor: Some(note1.actor()),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_patches_name_the_actor_of_each_change() {
+        let bytes = Note::into(&Note::new("one two"));
+        let laptop = Note::from(&bytes).update("one two three");
+        let phone = Note::from(&bytes).update("zero one two").set_pinned(true);
+        assert_ne!(laptop.actor(), phone.actor());
+
+        // Text and values say who inserted and set them, however many
+        // devices the merged changes came from
+        let patches = laptop.merge(&phone).patches();
+        assert!(patches.contains(&NotePatch::Insert {
+            field: "content".into(),
+            index: 0,
+            text: "zero ".into(),
+            actor: Some(phone.actor()),
+        }));
+        assert!(patches.contains(&NotePatch::Put {
+            field: "pinned".into(),
+            value: "true".into(),
+            conflict: false,
+            actor: Some(phone.actor()),
+        }));
+
+        // A removal is only attributed when one actor made every change
+        let trimmed = Note::from(&Note::into(&laptop)).update("one");
+        let patches = phone.merge(&laptop.merge(&trimmed)).patches();
+        assert!(
+            patches
+                .iter()
+                .any(|patch| matches!(patch, NotePatch::Remove { actor: None, .. }))
+        );
+        let patches = laptop.apply(&Note::into(&trimmed)).patches();
+        assert!(patches.iter().any(|patch| matches!(
+            patch,
+            NotePatch::Remove { actor: Some(actor), .. } if *actor == trimmed.actor()
+        )));
+    }
+
+    #[test]
+    fn test_new_with_id() {
+        let note = Note::new_with_id("imported-1", "from elsewhere");
+        assert_eq!(note.id(), "imported-1");
+        assert_eq!(note.content(), "from elsewhere");
+    }
+
+    #[test]
+    fn test_generate() {
+        let mut ids = SequentialIdGenerator::starting_at(1_700_000_000_000);
+        let note1 = Note::generate(&mut ids, "first");
+        let note2 = Note::generate(&mut ids, "second");
+
+        assert!(note1.id() < note2.id());
+        assert_eq!(
+            note1.id(),
+            SequentialIdGenerator::starting_at(1_700_000_000_000).next_id()
+        );
+    }
 }
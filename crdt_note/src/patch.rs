@@ -0,0 +1,191 @@
+use crate::scalar_string;
+use automerge::{AutoCommit, ObjId, ObjType, Patch, PatchAction, Prop, ROOT, ReadDoc, Value};
+use wasm_bindgen::prelude::*;
+
+/// A single change to a note, as reported by `Note::patches`.
+///
+/// Only the shapes the note schema uses are represented: scalar fields on the
+/// note itself (`id`, `pinned`, `archived`) and text fields (`content`).
+///
+/// `actor` is the hex actor id of the change that caused the patch (see
+/// `Note::actor`). It is `None` for removals and conflicts brought in by
+/// changes from more than one actor, which automerge does not tell apart.
+#[derive(Debug, Clone, PartialEq)]
+pub enum NotePatch {
+    /// A field was set. `conflict` is true when other devices hold
+    /// concurrent values for the same field.
+    Put {
+        field: String,
+        value: String,
+        conflict: bool,
+        actor: Option<String>,
+    },
+    /// A field was removed
+    Delete {
+        field: String,
+        actor: Option<String>,
+    },
+    /// Text was inserted into a text field at a character index
+    Insert {
+        field: String,
+        index: usize,
+        text: String,
+        actor: Option<String>,
+    },
+    /// Characters were removed from a text field
+    Remove {
+        field: String,
+        index: usize,
+        length: usize,
+        actor: Option<String>,
+    },
+    /// A field became conflicted
+    Conflict {
+        field: String,
+        actor: Option<String>,
+    },
+}
+
+impl NotePatch {
+    /// `doc` is the note after the patch; `author` the one actor all the
+    /// changes since came from, if there is just one.
+    pub(crate) fn from_patch(doc: &AutoCommit, patch: Patch, author: Option<&str>) -> Option<Self> {
+        let field = if patch.obj == ROOT {
+            None
+        } else {
+            match patch.path.last() {
+                Some((_, Prop::Map(key))) => Some(key.clone()),
+                _ => return None,
+            }
+        };
+
+        match (field, patch.action) {
+            (
+                None,
+                PatchAction::PutMap {
+                    key,
+                    value,
+                    conflict,
+                },
+            ) => Some(NotePatch::Put {
+                field: key,
+                value: shown(&value.0),
+                conflict,
+                actor: actor_of(&value.1).or(author.map(str::to_string)),
+            }),
+            (None, PatchAction::DeleteMap { key }) => Some(NotePatch::Delete {
+                field: key,
+                actor: author.map(str::to_string),
+            }),
+            (
+                None,
+                PatchAction::Conflict {
+                    prop: Prop::Map(key),
+                },
+            ) => Some(NotePatch::Conflict {
+                field: key,
+                actor: author.map(str::to_string),
+            }),
+            // Patches come in document order, so the text inserted is still
+            // at `index` in the note they lead to
+            (Some(field), PatchAction::SpliceText { index, value, .. }) => {
+                let inserted = doc.get(&patch.obj, index).ok().flatten();
+                Some(NotePatch::Insert {
+                    field,
+                    index,
+                    text: value.make_string(),
+                    actor: inserted
+                        .and_then(|(_, id)| actor_of(&id))
+                        .or(author.map(str::to_string)),
+                })
+            }
+            (Some(field), PatchAction::DeleteSeq { index, length }) => Some(NotePatch::Remove {
+                field,
+                index,
+                length,
+                actor: author.map(str::to_string),
+            }),
+            _ => None,
+        }
+    }
+
+    /// The patch as a plain JS object tagged by `type`, e.g.
+    /// `{ type: "insert", field: "content", index: 0, text: "hi", actor: "…" }`.
+    pub(crate) fn to_js(&self) -> JsValue {
+        let object = js_sys::Object::new();
+        let set = |key: &str, value: JsValue| {
+            js_sys::Reflect::set(&object, &key.into(), &value).ok();
+        };
+
+        let actor = match self {
+            NotePatch::Put {
+                field,
+                value,
+                conflict,
+                actor,
+            } => {
+                set("type", "put".into());
+                set("field", field.into());
+                set("value", value.into());
+                set("conflict", (*conflict).into());
+                actor
+            }
+            NotePatch::Delete { field, actor } => {
+                set("type", "delete".into());
+                set("field", field.into());
+                actor
+            }
+            NotePatch::Insert {
+                field,
+                index,
+                text,
+                actor,
+            } => {
+                set("type", "insert".into());
+                set("field", field.into());
+                set("index", (*index).into());
+                set("text", text.into());
+                actor
+            }
+            NotePatch::Remove {
+                field,
+                index,
+                length,
+                actor,
+            } => {
+                set("type", "remove".into());
+                set("field", field.into());
+                set("index", (*index).into());
+                set("length", (*length).into());
+                actor
+            }
+            NotePatch::Conflict { field, actor } => {
+                set("type", "conflict".into());
+                set("field", field.into());
+                actor
+            }
+        };
+        set(
+            "actor",
+            actor.as_deref().map_or(JsValue::NULL, JsValue::from),
+        );
+
+        object.into()
+    }
+}
+
+// The actor that made the operation with this id
+fn actor_of(id: &ObjId) -> Option<String> {
+    match id {
+        ObjId::Id(_, actor, _) => Some(actor.to_hex_string()),
+        ObjId::Root => None,
+    }
+}
+
+fn shown(value: &Value<'_>) -> String {
+    match value {
+        Value::Scalar(v) => scalar_string(v),
+        Value::Object(ObjType::Text) => "".into(),
+        Value::Object(obj_type) => obj_type.to_string(),
+    }
+}
@@ -0,0 +1,120 @@
+use crate::Note;
+use automerge::AutoCommit;
+use automerge::sync::{self, SyncDoc};
+
+/// Where the automerge sync protocol stands with one peer for one note.
+///
+/// Each side keeps one per note and takes turns: send `sync_message`, apply
+/// what comes back with `receive_sync_message`. Once neither side has a
+/// message to send, both hold the same changes. Only the changes the other
+/// side is missing cross the wire.
+#[derive(Debug, Default)]
+pub struct PeerState(sync::State);
+
+impl PeerState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Note {
+    /// A note with no history yet, to sync a note only the peer has into.
+    pub fn blank() -> Self {
+        Note::empty()
+    }
+
+    /// The next message for the peer, or `None` if it has nothing to learn
+    /// from this side until it sends something.
+    pub fn sync_message(&self, state: &mut PeerState) -> Option<Vec<u8>> {
+        self.doc
+            .clone()
+            .sync()
+            .generate_sync_message(&mut state.0)
+            .map(sync::Message::encode)
+    }
+
+    /// This note with the changes in a message from the peer applied.
+    pub fn receive_sync_message(
+        &self,
+        state: &mut PeerState,
+        message: &[u8],
+    ) -> Result<Self, String> {
+        let message = sync::Message::decode(message).map_err(|e| format!("{}", e))?;
+        let mut doc: AutoCommit = self.doc.clone();
+        let before = doc.get_heads();
+
+        doc.sync()
+            .receive_sync_message(&mut state.0, message)
+            .map_err(|e| format!("{}", e))?;
+        Ok(self.with_doc(doc, before))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Runs the protocol to the end, as two peers taking turns would
+    fn converge(a: &Note, b: &Note) -> (Note, Note, usize) {
+        let (mut a, mut b) = (Note::from(&Note::into(a)), Note::from(&Note::into(b)));
+        let (mut a_state, mut b_state) = (PeerState::new(), PeerState::new());
+        let mut messages = 0;
+
+        loop {
+            let from_a = a.sync_message(&mut a_state);
+            if let Some(message) = &from_a {
+                b = b.receive_sync_message(&mut b_state, message).unwrap();
+                messages += 1;
+            }
+            let from_b = b.sync_message(&mut b_state);
+            if let Some(message) = &from_b {
+                a = a.receive_sync_message(&mut a_state, message).unwrap();
+                messages += 1;
+            }
+            if from_a.is_none() && from_b.is_none() {
+                return (a, b, messages);
+            }
+        }
+    }
+
+    #[test]
+    fn test_sync_into_blank() {
+        let note = Note::new("shared").set_pinned(true);
+
+        let (_, copy, _) = converge(&note, &Note::blank());
+        assert_eq!(copy.id(), note.id());
+        assert_eq!(copy.content(), "shared");
+        assert!(copy.pinned());
+        assert_eq!(copy.heads(), note.heads());
+    }
+
+    #[test]
+    fn test_sync_merges_concurrent_edits() {
+        let base = Note::new("milk");
+        let (_, copy, _) = converge(&base, &Note::blank());
+
+        let here = base.set_pinned(true);
+        let there = copy.set_archived(true);
+        let (here, there, _) = converge(&here, &there);
+
+        assert_eq!(here.heads(), there.heads());
+        assert!(here.pinned() && here.archived());
+    }
+
+    #[test]
+    fn test_already_in_step() {
+        let note = Note::new("same");
+        let (note, copy, _) = converge(&note, &Note::blank());
+
+        // Just the exchange of heads, no changes
+        let (_, _, messages) = converge(&note, &copy);
+        assert!(messages <= 2);
+    }
+
+    #[test]
+    fn test_garbage_is_rejected() {
+        let note = Note::new("note");
+        let result = note.receive_sync_message(&mut PeerState::new(), b"not a sync message");
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,337 @@
+use automerge::{AutoCommit, Change, ChangeHash};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use wasm_bindgen::prelude::*;
+
+// Signed changes carry their signature in the change message:
+//
+//   qot-sig:v1:<device public key, hex>:<signature, hex>
+//
+// The signature covers the hash of the same change with the message left
+// out, since a change's own hash already depends on its message.
+const PREFIX: &str = "qot-sig:v1:";
+
+/// An Ed25519 keypair identifying one device. Changes made through a note
+/// with a device key attached (see `Note::with_signer`) are signed with it.
+#[wasm_bindgen]
+#[derive(Clone)]
+pub struct DeviceKey {
+    signing_key: SigningKey,
+}
+
+impl DeviceKey {
+    pub fn generate() -> Result<Self, String> {
+        let mut secret = [0u8; 32];
+        getrandom::fill(&mut secret).map_err(|e| e.to_string())?;
+        Ok(Self {
+            signing_key: SigningKey::from_bytes(&secret),
+        })
+    }
+
+    pub fn from_bytes(secret: &[u8]) -> Result<Self, String> {
+        let secret: [u8; 32] = secret
+            .try_into()
+            .map_err(|_| "device key must be 32 bytes".to_string())?;
+        Ok(Self {
+            signing_key: SigningKey::from_bytes(&secret),
+        })
+    }
+
+    /// The secret key. Keep it on the device it belongs to.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.signing_key.to_bytes().to_vec()
+    }
+
+    /// The public key other devices trust, hex encoded
+    pub fn public_key(&self) -> String {
+        to_hex(self.signing_key.verifying_key().as_bytes())
+    }
+}
+
+// Never print the secret key
+impl std::fmt::Debug for DeviceKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("DeviceKey")
+            .field("public_key", &self.public_key())
+            .finish_non_exhaustive()
+    }
+}
+
+#[wasm_bindgen]
+impl DeviceKey {
+    #[wasm_bindgen(js_name = generate)]
+    pub fn generate_js() -> Result<DeviceKey, JsError> {
+        DeviceKey::generate().map_err(|e| JsError::new(&e))
+    }
+
+    #[wasm_bindgen(js_name = fromBytes)]
+    pub fn from_bytes_js(secret: &[u8]) -> Result<DeviceKey, JsError> {
+        DeviceKey::from_bytes(secret).map_err(|e| JsError::new(&e))
+    }
+
+    #[wasm_bindgen(js_name = toBytes)]
+    pub fn to_bytes_js(&self) -> Vec<u8> {
+        self.to_bytes()
+    }
+
+    #[wasm_bindgen(js_name = publicKey)]
+    pub fn public_key_js(&self) -> String {
+        self.public_key()
+    }
+}
+
+/// How a single change in a note's history checks out, as reported by
+/// `Note::verify`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChangeStatus {
+    /// Signed by the device with this public key
+    Signed { device: String },
+    /// No signature, e.g. made before signing was set up or by a client
+    /// without a device key
+    Unsigned,
+    /// Claims to be signed by this device but the signature does not match,
+    /// so the change was forged or altered
+    BadSignature { device: String },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChangeVerification {
+    pub hash: String,
+    pub actor: String,
+    pub status: ChangeStatus,
+}
+
+impl ChangeVerification {
+    pub(crate) fn to_js(&self) -> JsValue {
+        let object = js_sys::Object::new();
+        let set = |key: &str, value: JsValue| {
+            js_sys::Reflect::set(&object, &key.into(), &value).ok();
+        };
+
+        set("hash", self.hash.as_str().into());
+        set("actor", self.actor.as_str().into());
+        match &self.status {
+            ChangeStatus::Signed { device } => {
+                set("status", "signed".into());
+                set("device", device.as_str().into());
+            }
+            ChangeStatus::Unsigned => {
+                set("status", "unsigned".into());
+            }
+            ChangeStatus::BadSignature { device } => {
+                set("status", "bad_signature".into());
+                set("device", device.as_str().into());
+            }
+        }
+
+        object.into()
+    }
+}
+
+/// Replaces the single local change `draft` has on top of `base` with a
+/// signed copy. Returns `None` when there is no such change to sign.
+pub(crate) fn sign_local_change(
+    base: &AutoCommit,
+    draft: &mut AutoCommit,
+    key: &DeviceKey,
+) -> Option<AutoCommit> {
+    let mut base = base.clone();
+    let before = base.get_heads();
+
+    let actor = draft.get_actor().clone();
+    let changes = draft.get_changes(&before);
+    let [change] = changes.as_slice() else {
+        return None;
+    };
+    if *change.actor_id() != actor {
+        return None;
+    }
+
+    let signature = key.signing_key.sign(unsigned_hash(change).as_ref());
+    let mut expanded = change.decode();
+    expanded.hash = None;
+    expanded.message = Some(format!(
+        "{}{}:{}",
+        PREFIX,
+        key.public_key(),
+        to_hex(&signature.to_bytes())
+    ));
+
+    base.apply_changes([Change::from(expanded)]).ok()?;
+    Some(base)
+}
+
+pub(crate) fn verify_changes(doc: &AutoCommit) -> Vec<ChangeVerification> {
+    let mut doc = doc.clone();
+
+    doc.get_changes(&[])
+        .into_iter()
+        .map(|change| ChangeVerification {
+            hash: change.hash().to_string(),
+            actor: change.actor_id().to_hex_string(),
+            status: status(change),
+        })
+        .collect()
+}
+
+fn status(change: &Change) -> ChangeStatus {
+    let Some((device, signature)) = change
+        .message()
+        .and_then(|message| message.strip_prefix(PREFIX))
+        .and_then(|rest| rest.split_once(':'))
+    else {
+        return ChangeStatus::Unsigned;
+    };
+
+    let verified = from_hex(device)
+        .and_then(|bytes| VerifyingKey::try_from(bytes.as_slice()).ok())
+        .zip(from_hex(signature).and_then(|bytes| Signature::from_slice(&bytes).ok()))
+        .is_some_and(|(public_key, signature)| {
+            public_key
+                .verify(unsigned_hash(change).as_ref(), &signature)
+                .is_ok()
+        });
+
+    if verified {
+        ChangeStatus::Signed {
+            device: device.to_string(),
+        }
+    } else {
+        ChangeStatus::BadSignature {
+            device: device.to_string(),
+        }
+    }
+}
+
+// The hash the change would have without a message
+fn unsigned_hash(change: &Change) -> ChangeHash {
+    let mut expanded = change.decode();
+    expanded.hash = None;
+    expanded.message = None;
+    Change::from(expanded).hash()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{ChangeStatus, DeviceKey, Note};
+
+    fn statuses(note: &Note) -> Vec<ChangeStatus> {
+        note.verify().into_iter().map(|v| v.status).collect()
+    }
+
+    #[test]
+    fn test_signed_changes_verify() {
+        let laptop = DeviceKey::generate().unwrap();
+        let note = Note::new_signed("one", &laptop).update("one two");
+
+        let signed = ChangeStatus::Signed {
+            device: laptop.public_key(),
+        };
+        assert_eq!(statuses(&note), vec![signed.clone(), signed]);
+        assert_eq!(note.content(), "one two");
+
+        // Signatures survive saving and loading
+        let loaded = Note::from(&Note::into(&note));
+        assert_eq!(loaded.verify(), note.verify());
+    }
+
+    #[test]
+    fn test_unsigned_changes_are_reported() {
+        let laptop = DeviceKey::generate().unwrap();
+        let note = Note::new("one").with_signer(&laptop).update("one two");
+
+        assert_eq!(
+            statuses(&note),
+            vec![
+                ChangeStatus::Unsigned,
+                ChangeStatus::Signed {
+                    device: laptop.public_key(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_merge_keeps_each_devices_signatures() {
+        let laptop = DeviceKey::generate().unwrap();
+        let phone = DeviceKey::generate().unwrap();
+        let bytes = Note::into(&Note::new_signed("one", &laptop));
+
+        let note1 = Note::from(&bytes).with_signer(&laptop).update("one two");
+        let note2 = Note::from(&bytes).with_signer(&phone).set_pinned(true);
+        let merged = note1.merge(&note2);
+
+        let mut devices: Vec<String> = merged
+            .verify()
+            .into_iter()
+            .filter_map(|v| match v.status {
+                ChangeStatus::Signed { device } => Some(device),
+                _ => None,
+            })
+            .collect();
+        devices.sort();
+        let mut expected = vec![laptop.public_key(), laptop.public_key(), phone.public_key()];
+        expected.sort();
+        assert_eq!(devices, expected);
+
+        // Further edits on the merged note are still signed
+        let edited = merged.update("one two three");
+        assert!(matches!(
+            edited.verify().last().unwrap().status,
+            ChangeStatus::Signed { .. }
+        ));
+    }
+
+    #[test]
+    fn test_tampered_changes_are_detected() {
+        let laptop = DeviceKey::generate().unwrap();
+        let note = Note::new_signed("one", &laptop);
+        let change = note.doc.clone().get_changes(&[])[0].clone();
+
+        // Same signature, altered change
+        let mut tampered = change.decode();
+        tampered.hash = None;
+        tampered.time += 1;
+        // A signature that does not even parse
+        let mut garbled = change.decode();
+        garbled.hash = None;
+        garbled.message = Some(format!("qot-sig:v1:{}:zz", laptop.public_key()));
+
+        for expanded in [tampered, garbled] {
+            let mut doc = automerge::AutoCommit::new();
+            doc.apply_changes([automerge::Change::from(expanded)])
+                .unwrap();
+
+            let forged = Note::derived(doc, vec![]);
+            assert_eq!(forged.content(), "one");
+            assert_eq!(
+                statuses(&forged),
+                vec![ChangeStatus::BadSignature {
+                    device: laptop.public_key(),
+                }]
+            );
+        }
+    }
+
+    #[test]
+    fn test_device_key_bytes_round_trip() {
+        let laptop = DeviceKey::generate().unwrap();
+        let restored = DeviceKey::from_bytes(&laptop.to_bytes()).unwrap();
+        assert_eq!(restored.public_key(), laptop.public_key());
+        assert!(DeviceKey::from_bytes(&[0u8; 12]).is_err());
+    }
+}
@@ -0,0 +1,108 @@
+use crate::Note;
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::prelude::*;
+
+/// A portable, human-readable view of a note's current state.
+///
+/// A snapshot carries no history, so a note recreated from one starts a new
+/// history and will not merge with the note it was taken from.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NoteSnapshot {
+    pub id: String,
+    pub content: String,
+    #[serde(default)]
+    pub metadata: NoteMetadata,
+    /// Heads of the note the snapshot was taken from, for reference
+    #[serde(default)]
+    pub heads: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct NoteMetadata {
+    #[serde(default)]
+    pub pinned: bool,
+    #[serde(default)]
+    pub archived: bool,
+}
+
+impl Note {
+    pub fn snapshot(&self) -> NoteSnapshot {
+        NoteSnapshot {
+            id: self.id(),
+            content: self.content(),
+            metadata: NoteMetadata {
+                pinned: self.pinned(),
+                archived: self.archived(),
+            },
+            heads: self.heads(),
+        }
+    }
+
+    pub fn from_snapshot(snapshot: &NoteSnapshot) -> Self {
+        let mut note = Note::new_with_id(&snapshot.id, &snapshot.content);
+
+        if snapshot.metadata.pinned {
+            note = note.set_pinned(true);
+        }
+        if snapshot.metadata.archived {
+            note = note.set_archived(true);
+        }
+
+        note
+    }
+}
+
+#[wasm_bindgen]
+impl Note {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&self.snapshot()).unwrap_or_default()
+    }
+
+    pub fn from_json_snapshot(json: &str) -> Self {
+        match serde_json::from_str::<NoteSnapshot>(json) {
+            Ok(snapshot) => Note::from_snapshot(&snapshot),
+            Err(_) => Note::empty(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_json_and_from_json_snapshot() {
+        let note = Note::new("expected content!").set_pinned(true);
+
+        let json = note.to_json();
+        let snapshot: NoteSnapshot = serde_json::from_str(&json).unwrap();
+        assert_eq!(snapshot.id, note.id());
+        assert_eq!(snapshot.content, "expected content!");
+        assert!(snapshot.metadata.pinned);
+        assert!(!snapshot.metadata.archived);
+        assert_eq!(snapshot.heads, note.heads());
+
+        let recreated = Note::from_json_snapshot(&json);
+        assert_eq!(recreated.id(), note.id());
+        assert_eq!(recreated.content(), note.content());
+        assert!(recreated.pinned());
+        assert!(!recreated.archived());
+    }
+
+    #[test]
+    fn test_from_json_snapshot_defaults_missing_metadata() {
+        let json = r#"{"id":"imported-1","content":"from elsewhere"}"#;
+
+        let note = Note::from_json_snapshot(json);
+        assert_eq!(note.id(), "imported-1");
+        assert_eq!(note.content(), "from elsewhere");
+        assert!(!note.pinned());
+    }
+
+    #[test]
+    fn test_from_json_snapshot_invalid_json() {
+        let note = Note::from_json_snapshot("not json");
+        assert_eq!(note.id(), "");
+        assert_eq!(note.content(), "");
+    }
+}
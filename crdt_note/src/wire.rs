@@ -0,0 +1,404 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use serde::{Deserialize, Serialize};
+
+// The messages clients and the server exchange about notes.
+//
+// As JSON (the Phoenix channel payloads and the REST bodies) note bytes are
+// base64 strings:
+//
+//   {"type":"note","id":"...","data":"<base64>"}
+//   {"type":"notes","notes":[{"id":"...","data":"<base64>"}]}
+//   {"type":"delete","id":"..."}
+//
+// As binary frames, for peers that talk over a raw byte stream:
+//
+//   length (u32) | version (1 byte) | kind (1 byte) | body
+//
+// where strings are a u16 length then UTF-8 and bytes a u32 length then the
+// bytes, all integers big-endian. A note body is id then data, a notes body a
+// u32 count then that many note bodies, and a delete body the id. A body
+// is at most MAX_FRAME_LEN bytes.
+//
+// The TypeScript types in client_web/src/lib/notes/networks/wire.ts are
+// generated from these by `cargo test --features serde`.
+const VERSION: u8 = 1;
+const KIND_NOTE: u8 = 1;
+const KIND_NOTES: u8 = 2;
+const KIND_DELETE: u8 = 3;
+const LENGTH_LEN: usize = 4;
+
+/// The largest frame body `from_frame` accepts or `to_frame` writes, so a
+/// corrupt or hostile length cannot ask for gigabytes.
+pub const MAX_FRAME_LEN: usize = 64 * 1024 * 1024;
+
+pub type WireResult<T> = Result<T, WireError>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum WireError {
+    /// The bytes are not a valid message
+    Malformed,
+    /// The frame was written with a newer format
+    UnsupportedVersion(u8),
+    UnknownKind(u8),
+    /// A frame, or a string or note in one, over its length limit
+    TooLarge,
+    Json(String),
+}
+
+impl std::fmt::Display for WireError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            WireError::Malformed => write!(f, "Malformed message"),
+            WireError::UnsupportedVersion(v) => write!(f, "Unsupported frame version: {}", v),
+            WireError::UnknownKind(k) => write!(f, "Unknown message kind: {}", k),
+            WireError::TooLarge => write!(f, "Message too large for a frame"),
+            WireError::Json(e) => write!(f, "JSON error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for WireError {}
+
+// ts-rs export paths are relative to its default `bindings/` dir
+
+/// A note's id and its `Note::into` bytes.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(test, derive(ts_rs::TS))]
+#[cfg_attr(
+    test,
+    ts(export, export_to = "../../client_web/src/lib/notes/networks/wire.ts")
+)]
+pub struct WireNote {
+    pub id: String,
+    /// Base64 in JSON
+    #[serde(with = "base64_bytes")]
+    #[cfg_attr(test, ts(type = "string"))]
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+#[cfg_attr(test, derive(ts_rs::TS))]
+#[cfg_attr(
+    test,
+    ts(export, export_to = "../../client_web/src/lib/notes/networks/wire.ts")
+)]
+pub enum WireMessage {
+    /// One note was created or changed
+    Note(WireNote),
+    /// The full set of notes, e.g. on joining or after a fetch
+    Notes { notes: Vec<WireNote> },
+    /// A note was deleted
+    Delete { id: String },
+}
+
+impl WireNote {
+    pub fn new(note: &crate::Note) -> Self {
+        Self {
+            id: note.id(),
+            data: note.into(),
+        }
+    }
+
+    pub fn note(&self) -> crate::Note {
+        crate::Note::from(&self.data)
+    }
+}
+
+impl WireMessage {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+
+    pub fn from_json(json: &str) -> WireResult<Self> {
+        serde_json::from_str(json).map_err(|e| WireError::Json(e.to_string()))
+    }
+
+    /// The message as a length-prefixed binary frame. Fails if it would
+    /// not fit in `MAX_FRAME_LEN`, or an id in it is over 65535 bytes.
+    pub fn to_frame(&self) -> WireResult<Vec<u8>> {
+        let mut body = vec![VERSION];
+        match self {
+            WireMessage::Note(note) => {
+                body.push(KIND_NOTE);
+                put_note(&mut body, note)?;
+            }
+            WireMessage::Notes { notes } => {
+                body.push(KIND_NOTES);
+                put_u32(&mut body, notes.len())?;
+                for note in notes {
+                    put_note(&mut body, note)?;
+                }
+            }
+            WireMessage::Delete { id } => {
+                body.push(KIND_DELETE);
+                put_str(&mut body, id)?;
+            }
+        }
+
+        let mut frame = Vec::with_capacity(LENGTH_LEN + body.len());
+        put_u32(&mut frame, body.len())?;
+        frame.extend_from_slice(&body);
+        Ok(frame)
+    }
+
+    /// Reads the frame at the start of `bytes`. Returns the message and the
+    /// number of bytes it took, or `None` if the frame is not complete yet.
+    pub fn from_frame(bytes: &[u8]) -> WireResult<Option<(Self, usize)>> {
+        let Some(length) = bytes.get(..LENGTH_LEN) else {
+            return Ok(None);
+        };
+        let length = u32::from_be_bytes(length.try_into().unwrap_or_default()) as usize;
+        if length > MAX_FRAME_LEN {
+            return Err(WireError::TooLarge);
+        }
+        let end = LENGTH_LEN.checked_add(length).ok_or(WireError::TooLarge)?;
+        let Some(body) = bytes.get(LENGTH_LEN..end) else {
+            return Ok(None);
+        };
+
+        let mut reader = Reader { bytes: body };
+        let version = reader.u8()?;
+        if version != VERSION {
+            return Err(WireError::UnsupportedVersion(version));
+        }
+
+        let message = match reader.u8()? {
+            KIND_NOTE => WireMessage::Note(reader.note()?),
+            KIND_NOTES => {
+                let count = reader.u32()?;
+                let notes = (0..count)
+                    .map(|_| reader.note())
+                    .collect::<WireResult<_>>()?;
+                WireMessage::Notes { notes }
+            }
+            KIND_DELETE => WireMessage::Delete { id: reader.str()? },
+            kind => return Err(WireError::UnknownKind(kind)),
+        };
+
+        if !reader.bytes.is_empty() {
+            return Err(WireError::Malformed);
+        }
+        Ok(Some((message, end)))
+    }
+}
+
+fn put_note(body: &mut Vec<u8>, note: &WireNote) -> WireResult<()> {
+    put_str(body, &note.id)?;
+    put_u32(body, note.data.len())?;
+    body.extend_from_slice(&note.data);
+    Ok(())
+}
+
+fn put_str(body: &mut Vec<u8>, s: &str) -> WireResult<()> {
+    let length = u16::try_from(s.len()).map_err(|_| WireError::TooLarge)?;
+    body.extend_from_slice(&length.to_be_bytes());
+    body.extend_from_slice(s.as_bytes());
+    Ok(())
+}
+
+// A length or count; anything past `MAX_FRAME_LEN` could not be read back
+fn put_u32(body: &mut Vec<u8>, n: usize) -> WireResult<()> {
+    if n > MAX_FRAME_LEN {
+        return Err(WireError::TooLarge);
+    }
+    body.extend_from_slice(&(n as u32).to_be_bytes());
+    Ok(())
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl Reader<'_> {
+    fn take(&mut self, n: usize) -> WireResult<&[u8]> {
+        if self.bytes.len() < n {
+            return Err(WireError::Malformed);
+        }
+        let (taken, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> WireResult<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> WireResult<u32> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes(bytes.try_into().unwrap_or_default()))
+    }
+
+    fn str(&mut self) -> WireResult<String> {
+        let bytes = self.take(2)?;
+        let length = u16::from_be_bytes(bytes.try_into().unwrap_or_default()) as usize;
+        String::from_utf8(self.take(length)?.to_vec()).map_err(|_| WireError::Malformed)
+    }
+
+    fn note(&mut self) -> WireResult<WireNote> {
+        let id = self.str()?;
+        let length = self.u32()? as usize;
+        let data = self.take(length)?.to_vec();
+        Ok(WireNote { id, data })
+    }
+}
+
+mod base64_bytes {
+    use super::*;
+    use serde::{Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        STANDARD.decode(encoded).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Note;
+
+    fn messages() -> Vec<WireMessage> {
+        let note = WireNote {
+            id: "0190b1c2-0000-7000-8000-000000000001".to_string(),
+            data: vec![0, 1, 2, 254, 255],
+        };
+
+        vec![
+            WireMessage::Note(note.clone()),
+            WireMessage::Notes {
+                notes: vec![note.clone(), WireNote::new(&Note::new("second"))],
+            },
+            WireMessage::Notes { notes: vec![] },
+            WireMessage::Delete { id: note.id },
+        ]
+    }
+
+    #[test]
+    fn test_json_format() {
+        let [note, notes, empty, delete] = messages().try_into().unwrap();
+
+        assert_eq!(
+            note.to_json(),
+            r#"{"type":"note","id":"0190b1c2-0000-7000-8000-000000000001","data":"AAEC/v8="}"#
+        );
+        assert!(notes.to_json().starts_with(
+            r#"{"type":"notes","notes":[{"id":"0190b1c2-0000-7000-8000-000000000001","data":"AAEC/v8="},"#
+        ));
+        assert_eq!(empty.to_json(), r#"{"type":"notes","notes":[]}"#);
+        assert_eq!(
+            delete.to_json(),
+            r#"{"type":"delete","id":"0190b1c2-0000-7000-8000-000000000001"}"#
+        );
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        for message in messages() {
+            assert_eq!(WireMessage::from_json(&message.to_json()), Ok(message));
+        }
+
+        assert!(WireMessage::from_json(r#"{"type":"note","id":"x","data":"!!"}"#).is_err());
+        assert!(WireMessage::from_json(r#"{"type":"rename","id":"x"}"#).is_err());
+    }
+
+    #[test]
+    fn test_frame_format() {
+        let delete = WireMessage::Delete {
+            id: "ab".to_string(),
+        };
+        assert_eq!(
+            delete.to_frame(),
+            Ok(vec![0, 0, 0, 6, VERSION, KIND_DELETE, 0, 2, b'a', b'b'])
+        );
+    }
+
+    #[test]
+    fn test_frame_round_trip_and_streaming() {
+        let messages = messages();
+        let stream: Vec<u8> = messages
+            .iter()
+            .flat_map(|message| message.to_frame().unwrap())
+            .collect();
+
+        let mut decoded = Vec::new();
+        let mut rest = stream.as_slice();
+        while let Some((message, used)) = WireMessage::from_frame(rest).unwrap() {
+            decoded.push(message);
+            rest = &rest[used..];
+        }
+        assert_eq!(decoded, messages);
+        assert!(rest.is_empty());
+
+        // A partial frame waits for more bytes
+        let frame = messages[0].to_frame().unwrap();
+        assert_eq!(WireMessage::from_frame(&frame[..frame.len() - 1]), Ok(None));
+        assert_eq!(WireMessage::from_frame(&frame[..2]), Ok(None));
+    }
+
+    #[test]
+    fn test_frame_errors() {
+        let mut frame = messages()[0].to_frame().unwrap();
+        frame[LENGTH_LEN] = 9;
+        assert_eq!(
+            WireMessage::from_frame(&frame),
+            Err(WireError::UnsupportedVersion(9))
+        );
+
+        frame[LENGTH_LEN] = VERSION;
+        frame[LENGTH_LEN + 1] = 7;
+        assert_eq!(
+            WireMessage::from_frame(&frame),
+            Err(WireError::UnknownKind(7))
+        );
+
+        // A length that claims more than the body holds
+        assert_eq!(
+            WireMessage::from_frame(&[0, 0, 0, 4, VERSION, KIND_DELETE, 0, 9]),
+            Err(WireError::Malformed)
+        );
+    }
+
+    #[test]
+    fn test_frame_limits() {
+        let long_id = WireMessage::Delete {
+            id: "x".repeat(u16::MAX as usize + 1),
+        };
+        assert_eq!(long_id.to_frame(), Err(WireError::TooLarge));
+        let longest_id = WireMessage::Delete {
+            id: "x".repeat(u16::MAX as usize),
+        };
+        assert!(longest_id.to_frame().is_ok());
+
+        let big_note = WireMessage::Note(WireNote {
+            id: "big".to_string(),
+            data: vec![0; MAX_FRAME_LEN],
+        });
+        assert_eq!(big_note.to_frame(), Err(WireError::TooLarge));
+
+        // Refused from the length alone, before the body arrives
+        let length = (MAX_FRAME_LEN as u32 + 1).to_be_bytes();
+        assert_eq!(WireMessage::from_frame(&length), Err(WireError::TooLarge));
+        assert_eq!(
+            WireMessage::from_frame(&[0xff, 0xff, 0xff, 0xff]),
+            Err(WireError::TooLarge)
+        );
+    }
+
+    #[test]
+    fn test_wire_note_carries_a_note() {
+        let note = Note::new("over the wire");
+        let message = WireMessage::from_json(&WireMessage::Note(WireNote::new(&note)).to_json());
+
+        let Ok(WireMessage::Note(wire_note)) = message else {
+            panic!("expected a note message");
+        };
+        assert_eq!(wire_note.note().id(), note.id());
+        assert_eq!(wire_note.note().content(), "over the wire");
+    }
+}
@@ -0,0 +1,99 @@
+use crdt_note::Note;
+use proptest::prelude::*;
+
+// Each replica starts from the same saved note and applies its own sequence
+// of edits, as separate devices would before syncing.
+fn replicas(base: &str, edits: &[Vec<String>]) -> Vec<Note> {
+    let bytes = Note::into(&Note::new(base));
+
+    edits
+        .iter()
+        .map(|replica_edits| {
+            replica_edits
+                .iter()
+                .fold(Note::from(&bytes), |note, edit| note.update(edit))
+        })
+        .collect()
+}
+
+fn merge_all(notes: &[&Note]) -> Note {
+    let first = Note::from(&Note::into(notes[0]));
+    notes[1..].iter().fold(first, |acc, note| acc.merge(note))
+}
+
+fn state(note: &Note) -> (String, String, Vec<String>) {
+    let mut heads = note.heads();
+    heads.sort();
+    (note.id(), note.content(), heads)
+}
+
+fn edits() -> impl Strategy<Value = Vec<String>> {
+    prop::collection::vec("[a-z ]{0,12}", 0..4)
+}
+
+proptest! {
+    #[test]
+    fn merge_is_commutative(base in "[a-z ]{0,12}", a in edits(), b in edits()) {
+        let notes = replicas(&base, &[a, b]);
+
+        prop_assert_eq!(
+            state(&notes[0].merge(&notes[1])),
+            state(&notes[1].merge(&notes[0]))
+        );
+    }
+
+    #[test]
+    fn merge_is_associative(base in "[a-z ]{0,12}", a in edits(), b in edits(), c in edits()) {
+        let notes = replicas(&base, &[a, b, c]);
+
+        prop_assert_eq!(
+            state(&notes[0].merge(&notes[1]).merge(&notes[2])),
+            state(&notes[0].merge(&notes[1].merge(&notes[2])))
+        );
+    }
+
+    #[test]
+    fn merge_is_idempotent(base in "[a-z ]{0,12}", a in edits(), b in edits()) {
+        let notes = replicas(&base, &[a, b]);
+        let merged = notes[0].merge(&notes[1]);
+
+        prop_assert_eq!(state(&merged.merge(&merged)), state(&merged));
+        prop_assert_eq!(state(&merged.merge(&notes[1])), state(&merged));
+    }
+
+    #[test]
+    fn concurrent_updates_converge(
+        base in "[a-z ]{0,12}",
+        all_edits in prop::collection::vec(edits(), 2..5),
+        rotation in any::<usize>(),
+        reverse in any::<bool>(),
+    ) {
+        let notes = replicas(&base, &all_edits);
+        let forward: Vec<&Note> = notes.iter().collect();
+
+        // Merge the same replicas in another order
+        let mut reordered = forward.clone();
+        reordered.rotate_left(rotation % notes.len());
+        if reverse {
+            reordered.reverse();
+        }
+
+        prop_assert_eq!(state(&merge_all(&forward)), state(&merge_all(&reordered)));
+    }
+
+    #[test]
+    fn from_never_panics_on_arbitrary_bytes(bytes in prop::collection::vec(any::<u8>(), 0..512)) {
+        let note = Note::from(&bytes);
+        let _ = note.content();
+        let _ = note.merge(&Note::new("other")).content();
+    }
+
+    #[test]
+    fn into_and_from_round_trip(content in ".{0,64}", pinned in any::<bool>()) {
+        let note = Note::new(&content).set_pinned(pinned);
+        let loaded = Note::from(&Note::into(&note));
+
+        prop_assert_eq!(state(&loaded), state(&note));
+        prop_assert_eq!(loaded.pinned(), pinned);
+    }
+}
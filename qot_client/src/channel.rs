@@ -0,0 +1,537 @@
+//! A client for the server's Phoenix channel, which pushes note changes as
+//! they happen.
+//!
+//! Frames use the Phoenix v2 JSON serializer: arrays of
+//! `[join_ref, ref, topic, event, payload]`. The access token goes in the
+//! connect params and the client joins `notes:user:<id>`, then keeps the
+//! connection alive with heartbeats and reconnects with backoff when it drops.
+
+use crate::{ClientError, ClientResult, Session};
+use crdt_note::WireMessage;
+use futures_util::{SinkExt, Stream, StreamExt};
+use serde_json::{Value, json};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::{Notify, mpsc};
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::Message as Frame;
+
+const PHOENIX_TOPIC: &str = "phoenix";
+
+// Refs of sent messages, so replies can be matched to `send`'s push ids
+const PUSH_REF: &str = "push-";
+
+// How long closing waits for a connection attempt still under way
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChannelEvent {
+    /// Joined, or joined again after a reconnect. The server follows a join
+    /// with a `notes` message holding every note.
+    Joined,
+    Message(WireMessage),
+    /// The server took the message `send` returned this id for
+    Pushed(u64),
+    /// The server refused the message `send` returned this id for, and why
+    PushRefused(u64, String),
+    /// The connection dropped and the client will reconnect
+    Disconnected(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct ChannelConfig {
+    /// The socket endpoint, e.g. `ws://localhost:4000/socket/websocket`
+    pub url: String,
+    pub token: String,
+    pub user_id: String,
+    pub heartbeat: Duration,
+    /// First reconnect delay, doubled after each failed attempt
+    pub reconnect_after: Duration,
+    pub max_reconnect_after: Duration,
+}
+
+impl ChannelConfig {
+    pub fn new(url: &str, session: &Session) -> Self {
+        Self {
+            url: url.to_string(),
+            token: session.access_token.clone(),
+            user_id: session.user.id.clone(),
+            heartbeat: Duration::from_secs(30),
+            reconnect_after: Duration::from_secs(1),
+            max_reconnect_after: Duration::from_secs(30),
+        }
+    }
+}
+
+/// The socket endpoint of a server at an HTTP base URL.
+pub fn socket_url(base_url: &str) -> String {
+    let base_url = base_url.trim_end_matches('/');
+    let base_url = match base_url.split_once("://") {
+        Some(("https", rest)) => format!("wss://{}", rest),
+        Some((_, rest)) => format!("ws://{}", rest),
+        None => format!("ws://{}", base_url),
+    };
+    format!("{}/socket/websocket", base_url)
+}
+
+/// A live connection to the user's notes channel, as a stream of
+/// `ChannelEvent`s. Needs a tokio runtime. The connection runs in the
+/// background until the channel is closed or dropped.
+pub struct NotesChannel {
+    outgoing: Option<mpsc::UnboundedSender<(u64, WireMessage)>>,
+    pushes: AtomicU64,
+    events: mpsc::UnboundedReceiver<ChannelEvent>,
+    token: Arc<Mutex<String>>,
+    // Wakes the task from waiting to reconnect once the channel is closed
+    closing: Arc<Notify>,
+    task: JoinHandle<()>,
+}
+
+impl NotesChannel {
+    pub fn connect(config: ChannelConfig) -> Self {
+        let (outgoing, outgoing_rx) = mpsc::unbounded_channel();
+        let (events_tx, events) = mpsc::unbounded_channel();
+        let token = Arc::new(Mutex::new(config.token.clone()));
+        let closing = Arc::new(Notify::new());
+
+        let task = tokio::spawn(run(
+            config,
+            token.clone(),
+            closing.clone(),
+            outgoing_rx,
+            events_tx,
+        ));
+
+        Self {
+            outgoing: Some(outgoing),
+            pushes: AtomicU64::new(0),
+            events,
+            token,
+            closing,
+            task,
+        }
+    }
+
+    /// Sends a message to the server. Messages sent while disconnected go
+    /// out after the next join. Returns an id for the message, which a
+    /// `Pushed` or `PushRefused` event names once the server replies. A
+    /// message whose connection drops first may never have arrived.
+    pub fn send(&self, message: WireMessage) -> ClientResult<u64> {
+        let push = self.pushes.fetch_add(1, Ordering::Relaxed) + 1;
+        self.outgoing
+            .as_ref()
+            .and_then(|outgoing| outgoing.send((push, message)).ok())
+            .map(|()| push)
+            .ok_or_else(|| ClientError::Transport("Channel closed".to_string()))
+    }
+
+    /// The token to connect with from the next reconnect on, e.g. after
+    /// refreshing an expired one.
+    pub fn set_token(&self, token: &str) {
+        *self.token.lock().unwrap_or_else(|e| e.into_inner()) = token.to_string();
+    }
+
+    /// Sends what is queued, leaves the channel and closes the connection.
+    /// While disconnected, queued messages are dropped rather than waited
+    /// on.
+    pub async fn close(mut self) {
+        self.outgoing.take();
+        self.closing.notify_one();
+        tokio::time::timeout(CLOSE_TIMEOUT, &mut self.task)
+            .await
+            .ok();
+    }
+}
+
+impl Stream for NotesChannel {
+    type Item = ChannelEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<ChannelEvent>> {
+        self.events.poll_recv(cx)
+    }
+}
+
+impl Drop for NotesChannel {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn run(
+    config: ChannelConfig,
+    token: Arc<Mutex<String>>,
+    closing: Arc<Notify>,
+    mut outgoing: mpsc::UnboundedReceiver<(u64, WireMessage)>,
+    events: mpsc::UnboundedSender<ChannelEvent>,
+) {
+    let mut delay = config.reconnect_after;
+
+    loop {
+        if outgoing.is_closed() {
+            return;
+        }
+        let token = token.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        let mut joined = false;
+
+        match connection(&config, &token, &mut outgoing, &events, &mut joined).await {
+            Ok(()) => return,
+            Err(reason) => {
+                if events.send(ChannelEvent::Disconnected(reason)).is_err() {
+                    return;
+                }
+            }
+        }
+
+        // Back off from a server that keeps failing, but not from one that
+        // was working until the connection dropped
+        if joined {
+            delay = config.reconnect_after;
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = closing.notified() => return,
+        }
+        delay = (delay * 2).min(config.max_reconnect_after);
+    }
+}
+
+// One connection, from connect to disconnect. Returns Ok when closed on
+// purpose, otherwise why it dropped.
+async fn connection(
+    config: &ChannelConfig,
+    token: &str,
+    outgoing: &mut mpsc::UnboundedReceiver<(u64, WireMessage)>,
+    events: &mpsc::UnboundedSender<ChannelEvent>,
+    joined: &mut bool,
+) -> Result<(), String> {
+    let url = format!(
+        "{}?token={}&vsn=2.0.0",
+        config.url,
+        crate::http::percent_encode(token)
+    );
+    let (mut socket, _) = tokio_tungstenite::connect_async(url)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let topic = format!("notes:user:{}", config.user_id);
+    let mut refs = 0u64;
+    let mut next_ref = || {
+        refs += 1;
+        refs.to_string()
+    };
+
+    let join_ref = next_ref();
+    send(
+        &mut socket,
+        Some(&join_ref),
+        &join_ref,
+        &topic,
+        "phx_join",
+        json!({}),
+    )
+    .await?;
+
+    let mut heartbeat = tokio::time::interval(config.heartbeat);
+    heartbeat.tick().await;
+    let mut pending_heartbeat: Option<String> = None;
+
+    loop {
+        tokio::select! {
+            frame = socket.next() => {
+                let text = match frame {
+                    Some(Ok(Frame::Text(text))) => text,
+                    Some(Ok(Frame::Close(_))) | None => return Err("Connection closed".to_string()),
+                    Some(Ok(_)) => continue,
+                    Some(Err(e)) => return Err(e.to_string()),
+                };
+                let Ok((_, reply_ref, frame_topic, event, payload)) = serde_json::from_str::<(
+                    Option<String>,
+                    Option<String>,
+                    String,
+                    String,
+                    Value,
+                )>(&text) else {
+                    continue;
+                };
+                let push = reply_ref
+                    .as_deref()
+                    .and_then(|r| r.strip_prefix(PUSH_REF))
+                    .and_then(|id| id.parse::<u64>().ok());
+
+                match (frame_topic.as_str(), event.as_str()) {
+                    (PHOENIX_TOPIC, "phx_reply") if reply_ref == pending_heartbeat => {
+                        pending_heartbeat = None;
+                    }
+                    (t, "phx_reply") if t == topic && reply_ref.as_ref() == Some(&join_ref) => {
+                        if payload["status"] != "ok" {
+                            return Err(format!("Join refused: {}", payload["response"]));
+                        }
+                        *joined = true;
+                        if events.send(ChannelEvent::Joined).is_err() {
+                            return Ok(());
+                        }
+                    }
+                    (t, "phx_reply") if t == topic && push.is_some() => {
+                        let push = push.unwrap_or_default();
+                        let event = if payload["status"] == "ok" {
+                            ChannelEvent::Pushed(push)
+                        } else {
+                            ChannelEvent::PushRefused(push, payload["response"].to_string())
+                        };
+                        if events.send(event).is_err() {
+                            return Ok(());
+                        }
+                    }
+                    (t, "message") if t == topic => {
+                        if let Ok(message) = serde_json::from_value::<WireMessage>(payload)
+                            && events.send(ChannelEvent::Message(message)).is_err()
+                        {
+                            return Ok(());
+                        }
+                    }
+                    (t, "phx_error" | "phx_close") if t == topic => {
+                        return Err(format!("Channel {}", event.trim_start_matches("phx_")));
+                    }
+                    _ => {}
+                }
+            }
+            message = outgoing.recv(), if *joined => {
+                let Some((push, message)) = message else {
+                    socket.close(None).await.ok();
+                    return Ok(());
+                };
+                let payload = serde_json::to_value(&message).map_err(|e| e.to_string())?;
+                let push_ref = format!("{}{}", PUSH_REF, push);
+                send(&mut socket, Some(&join_ref), &push_ref, &topic, "message", payload).await?;
+            }
+            _ = heartbeat.tick() => {
+                if pending_heartbeat.is_some() {
+                    return Err("Heartbeat timed out".to_string());
+                }
+                let heartbeat_ref = next_ref();
+                send(&mut socket, None, &heartbeat_ref, PHOENIX_TOPIC, "heartbeat", json!({})).await?;
+                pending_heartbeat = Some(heartbeat_ref);
+            }
+        }
+    }
+}
+
+async fn send<S>(
+    socket: &mut S,
+    join_ref: Option<&str>,
+    message_ref: &str,
+    topic: &str,
+    event: &str,
+    payload: Value,
+) -> Result<(), String>
+where
+    S: futures_util::Sink<Frame, Error = tokio_tungstenite::tungstenite::Error> + Unpin,
+{
+    let frame = json!([join_ref, message_ref, topic, event, payload]);
+    socket
+        .send(Frame::text(frame.to_string()))
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockServer;
+    use crdt_note::{Note, WireNote};
+
+    fn config(server: &MockServer, session: &Session) -> ChannelConfig {
+        ChannelConfig {
+            heartbeat: Duration::from_millis(50),
+            reconnect_after: Duration::from_millis(20),
+            max_reconnect_after: Duration::from_millis(100),
+            ..ChannelConfig::new(&server.socket_url(), session)
+        }
+    }
+
+    async fn next(channel: &mut NotesChannel) -> ChannelEvent {
+        tokio::time::timeout(Duration::from_secs(5), channel.next())
+            .await
+            .expect("event in time")
+            .expect("channel open")
+    }
+
+    fn wire_note(note: &crdt_note::Note) -> WireNote {
+        WireNote::new(note)
+    }
+
+    #[test]
+    fn test_socket_url() {
+        assert_eq!(
+            socket_url("http://localhost:4000/"),
+            "ws://localhost:4000/socket/websocket"
+        );
+        assert_eq!(
+            socket_url("https://qot.example"),
+            "wss://qot.example/socket/websocket"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_join_receives_notes_then_pushes() {
+        let server = MockServer::start();
+        let session = server.session("ada@example.com");
+        let existing = Note::new("already there");
+        server.put_note(&session.user.id, &existing.id(), &Note::into(&existing));
+
+        let mut channel = NotesChannel::connect(config(&server, &session));
+        assert_eq!(next(&mut channel).await, ChannelEvent::Joined);
+        assert_eq!(
+            next(&mut channel).await,
+            ChannelEvent::Message(WireMessage::Notes {
+                notes: vec![wire_note(&existing)],
+            })
+        );
+
+        // Changes from other devices arrive as they happen
+        let other = Note::new("from another device");
+        server.put_note(&session.user.id, &other.id(), &Note::into(&other));
+        assert_eq!(
+            next(&mut channel).await,
+            ChannelEvent::Message(WireMessage::Note(wire_note(&other)))
+        );
+
+        server.delete_note(&session.user.id, &other.id());
+        assert_eq!(
+            next(&mut channel).await,
+            ChannelEvent::Message(WireMessage::Delete { id: other.id() })
+        );
+
+        channel.close().await;
+    }
+
+    #[tokio::test]
+    async fn test_send_reaches_server_and_other_clients() {
+        let server = MockServer::start();
+        let session = server.session("ada@example.com");
+
+        let mut laptop = NotesChannel::connect(config(&server, &session));
+        let mut phone = NotesChannel::connect(config(&server, &session));
+        for channel in [&mut laptop, &mut phone] {
+            assert_eq!(next(channel).await, ChannelEvent::Joined);
+            assert!(matches!(next(channel).await, ChannelEvent::Message(_)));
+        }
+
+        let note = Note::new("typed on the laptop");
+        let push = laptop.send(WireMessage::Note(wire_note(&note))).unwrap();
+        assert_eq!(next(&mut laptop).await, ChannelEvent::Pushed(push));
+        assert_eq!(
+            next(&mut phone).await,
+            ChannelEvent::Message(WireMessage::Note(wire_note(&note)))
+        );
+        assert_eq!(
+            server.notes(&session.user.id).get(&note.id()),
+            Some(&Note::into(&note))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_heartbeats_keep_connection_alive() {
+        let server = MockServer::start();
+        let session = server.session("ada@example.com");
+
+        let mut channel = NotesChannel::connect(config(&server, &session));
+        assert_eq!(next(&mut channel).await, ChannelEvent::Joined);
+        next(&mut channel).await;
+
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(server.heartbeats() >= 3);
+
+        // Nothing but the heartbeat replies happened meanwhile
+        let event = tokio::time::timeout(Duration::from_millis(50), channel.next()).await;
+        assert!(event.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reconnects_and_sends_queued_messages() {
+        let server = MockServer::start();
+        let session = server.session("ada@example.com");
+
+        let mut channel = NotesChannel::connect(config(&server, &session));
+        assert_eq!(next(&mut channel).await, ChannelEvent::Joined);
+        next(&mut channel).await;
+
+        server.disconnect_sockets();
+        assert!(matches!(
+            next(&mut channel).await,
+            ChannelEvent::Disconnected(_)
+        ));
+
+        let note = Note::new("written offline");
+        let push = channel.send(WireMessage::Note(wire_note(&note))).unwrap();
+
+        assert_eq!(next(&mut channel).await, ChannelEvent::Joined);
+        next(&mut channel).await;
+        assert_eq!(next(&mut channel).await, ChannelEvent::Pushed(push));
+        // The server echoes the queued note back to every client
+        assert_eq!(
+            next(&mut channel).await,
+            ChannelEvent::Message(WireMessage::Note(wire_note(&note)))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_close_while_offline_stops_reconnecting() {
+        let mut channel = NotesChannel::connect(ChannelConfig {
+            url: "ws://127.0.0.1:9/socket/websocket".to_string(),
+            token: "token".to_string(),
+            user_id: "user-1".to_string(),
+            heartbeat: Duration::from_secs(30),
+            reconnect_after: Duration::from_secs(30),
+            max_reconnect_after: Duration::from_secs(30),
+        });
+        assert!(matches!(
+            next(&mut channel).await,
+            ChannelEvent::Disconnected(_)
+        ));
+
+        // Waiting out the backoff, and the close timeout, would take longer
+        tokio::time::timeout(Duration::from_secs(1), channel.close())
+            .await
+            .expect("closed without waiting to reconnect");
+    }
+
+    #[tokio::test]
+    async fn test_bad_token_retries_with_new_token() {
+        let server = MockServer::start();
+        let session = server.session("ada@example.com");
+        server.expire_access_tokens();
+
+        let mut channel = NotesChannel::connect(config(&server, &session));
+        assert!(matches!(
+            next(&mut channel).await,
+            ChannelEvent::Disconnected(_)
+        ));
+
+        channel.set_token(&server.session("ada@example.com").access_token);
+        loop {
+            if next(&mut channel).await == ChannelEvent::Joined {
+                break;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_join_refused_for_other_users_topic() {
+        let server = MockServer::start();
+        let session = server.session("ada@example.com");
+        let grace = server.session("grace@example.com");
+
+        let mut channel = NotesChannel::connect(ChannelConfig {
+            user_id: grace.user.id,
+            ..config(&server, &session)
+        });
+        let ChannelEvent::Disconnected(reason) = next(&mut channel).await else {
+            panic!("expected the join to be refused");
+        };
+        assert!(reason.contains("unauthorized"));
+    }
+}
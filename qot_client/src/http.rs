@@ -0,0 +1,412 @@
+use crate::transport::{Method, Request, Response, Transport, UreqTransport};
+use crdt_note::WireNote;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+pub type ClientResult<T> = Result<T, ClientError>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClientError {
+    /// The request got no response
+    Transport(String),
+    /// The session is missing, or expired and could not be refreshed
+    Unauthorized,
+    /// The server answered with an error status
+    Status { status: u16, message: String },
+    /// The response was not what the API returns
+    Decode(String),
+}
+
+impl std::fmt::Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ClientError::Transport(e) => write!(f, "Could not reach the server: {}", e),
+            ClientError::Unauthorized => write!(f, "Not logged in or session expired"),
+            ClientError::Status { status, message } => {
+                write!(f, "Server error {}: {}", status, message)
+            }
+            ClientError::Decode(e) => write!(f, "Unexpected response: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct User {
+    pub id: String,
+    pub email: String,
+}
+
+/// The tokens from verifying a magic link. The access token is short-lived
+/// and renewed with the refresh token.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Session {
+    pub access_token: String,
+    pub refresh_token: String,
+    pub user: User,
+}
+
+#[derive(Deserialize)]
+struct NotesBody {
+    notes: Vec<WireNote>,
+}
+
+#[derive(Deserialize)]
+struct AccessTokenBody {
+    access_token: String,
+}
+
+#[derive(Deserialize)]
+struct ErrorBody {
+    error: String,
+}
+
+/// A client for the qot server's HTTP API.
+///
+/// Note requests need a session, from `verify` or `set_session`. When the
+/// access token has expired the client refreshes it once and retries, so
+/// callers that persist the session should save `session()` afterwards.
+pub struct HttpClient {
+    transport: Box<dyn Transport>,
+    session: Option<Session>,
+}
+
+impl HttpClient {
+    pub fn new(base_url: &str) -> Self {
+        HttpClient::with_transport(Box::new(UreqTransport::new(base_url)))
+    }
+
+    pub fn with_transport(transport: Box<dyn Transport>) -> Self {
+        Self {
+            transport,
+            session: None,
+        }
+    }
+
+    pub fn session(&self) -> Option<&Session> {
+        self.session.as_ref()
+    }
+
+    pub fn set_session(&mut self, session: Option<Session>) {
+        self.session = session;
+    }
+
+    /// Asks the server to email a sign-in link to `email`.
+    pub fn request_magic_link(&self, email: &str) -> ClientResult<()> {
+        let body = serde_json::json!({ "email": email });
+        let response = self.send(Method::Post, "/api/auth/magic-link", None, Some(body))?;
+        check(response).map(|_| ())
+    }
+
+    /// Like `request_magic_link`, but the link points at `redirect_uri`
+    /// instead of the web app. The server only accepts loopback addresses,
+    /// for a local listener to catch the token.
+    pub fn request_magic_link_to(&self, email: &str, redirect_uri: &str) -> ClientResult<()> {
+        let body = serde_json::json!({ "email": email, "redirect_uri": redirect_uri });
+        let response = self.send(Method::Post, "/api/auth/magic-link", None, Some(body))?;
+        check(response).map(|_| ())
+    }
+
+    /// Exchanges the token from a magic link for a session.
+    pub fn verify(&mut self, token: &str) -> ClientResult<Session> {
+        let path = format!("/api/auth/verify?token={}", percent_encode(token));
+        let session: Session = decode(self.send(Method::Get, &path, None, None)?)?;
+        self.session = Some(session.clone());
+        Ok(session)
+    }
+
+    /// Gets a new access token with the session's refresh token.
+    pub fn refresh(&mut self) -> ClientResult<()> {
+        let session = self.session.as_mut().ok_or(ClientError::Unauthorized)?;
+        let body = serde_json::json!({ "refresh_token": session.refresh_token });
+
+        let response = self.transport.send(&request(
+            Method::Post,
+            "/api/auth/refresh",
+            None,
+            Some(body),
+        ))?;
+        if response.status == 401 {
+            return Err(ClientError::Unauthorized);
+        }
+
+        let refreshed: AccessTokenBody = decode(response)?;
+        session.access_token = refreshed.access_token;
+        Ok(())
+    }
+
+    /// Revokes the refresh token and forgets the session.
+    pub fn logout(&mut self) -> ClientResult<()> {
+        let Some(session) = self.session.take() else {
+            return Ok(());
+        };
+
+        let body = serde_json::json!({ "refresh_token": session.refresh_token });
+        let response = self.send(Method::Post, "/api/auth/logout", None, Some(body))?;
+        check(response).map(|_| ())
+    }
+
+    pub fn list_notes(&mut self) -> ClientResult<Vec<WireNote>> {
+        let body: NotesBody = decode(self.authorized(Method::Get, "/api/notes", None)?)?;
+        Ok(body.notes)
+    }
+
+    /// Creates or replaces a note on the server.
+    pub fn put_note(&mut self, note: &WireNote) -> ClientResult<()> {
+        let path = format!("/api/notes/{}", percent_encode(&note.id));
+        let body = serde_json::to_value(note).map_err(|e| ClientError::Decode(e.to_string()))?;
+        check(self.authorized(Method::Put, &path, Some(body))?).map(|_| ())
+    }
+
+    pub fn delete_note(&mut self, id: &str) -> ClientResult<()> {
+        let path = format!("/api/notes/{}", percent_encode(id));
+        check(self.authorized(Method::Delete, &path, None)?).map(|_| ())
+    }
+
+    // Sends with the access token, refreshing it once on a 401
+    fn authorized(
+        &mut self,
+        method: Method,
+        path: &str,
+        body: Option<serde_json::Value>,
+    ) -> ClientResult<Response> {
+        let token = |client: &Self| {
+            client
+                .session
+                .as_ref()
+                .map(|session| session.access_token.clone())
+                .ok_or(ClientError::Unauthorized)
+        };
+
+        let response = self.send(method, path, Some(token(self)?), body.clone())?;
+        if response.status != 401 {
+            return Ok(response);
+        }
+
+        self.refresh()?;
+        let response = self.send(method, path, Some(token(self)?), body)?;
+        if response.status == 401 {
+            return Err(ClientError::Unauthorized);
+        }
+        Ok(response)
+    }
+
+    fn send(
+        &self,
+        method: Method,
+        path: &str,
+        bearer: Option<String>,
+        body: Option<serde_json::Value>,
+    ) -> ClientResult<Response> {
+        self.transport.send(&request(method, path, bearer, body))
+    }
+}
+
+fn request(
+    method: Method,
+    path: &str,
+    bearer: Option<String>,
+    body: Option<serde_json::Value>,
+) -> Request {
+    Request {
+        bearer,
+        body: body.map(|body| body.to_string().into_bytes()),
+        ..Request::new(method, path)
+    }
+}
+
+// Turns error statuses into errors, with the server's message if it sent one
+fn check(response: Response) -> ClientResult<Response> {
+    match response.status {
+        200..=299 => Ok(response),
+        401 => Err(ClientError::Unauthorized),
+        status => {
+            let message = serde_json::from_slice::<ErrorBody>(&response.body)
+                .map(|body| body.error)
+                .unwrap_or_else(|_| String::from_utf8_lossy(&response.body).into_owned());
+            Err(ClientError::Status { status, message })
+        }
+    }
+}
+
+fn decode<T: DeserializeOwned>(response: Response) -> ClientResult<T> {
+    let response = check(response)?;
+    serde_json::from_slice(&response.body).map_err(|e| ClientError::Decode(e.to_string()))
+}
+
+// Percent-encodes everything but unreserved characters
+pub(crate) fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockServer;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn logged_in(server: &MockServer) -> HttpClient {
+        let mut client = HttpClient::new(&server.url());
+        client.request_magic_link("ada@example.com").unwrap();
+        let token = server.magic_token("ada@example.com").unwrap();
+        client.verify(&token).unwrap();
+        client
+    }
+
+    #[test]
+    fn test_magic_link_login_and_logout() {
+        let server = MockServer::start();
+        let mut client = HttpClient::new(&server.url());
+
+        assert_eq!(client.verify("bogus"), Err(ClientError::Unauthorized));
+
+        client.request_magic_link("ada@example.com").unwrap();
+        let token = server.magic_token("ada@example.com").unwrap();
+        let session = client.verify(&token).unwrap();
+        assert_eq!(session.user.email, "ada@example.com");
+        assert_eq!(client.session(), Some(&session));
+
+        // Links are single use
+        assert_eq!(client.verify(&token), Err(ClientError::Unauthorized));
+
+        client
+            .request_magic_link_to("ada@example.com", "http://127.0.0.1:9/callback")
+            .unwrap();
+        let token = server.magic_token("ada@example.com").unwrap();
+        assert_eq!(
+            server.magic_link("ada@example.com").unwrap(),
+            format!("http://127.0.0.1:9/callback?token={}", token)
+        );
+
+        client.logout().unwrap();
+        assert_eq!(client.session(), None);
+        assert_eq!(client.list_notes(), Err(ClientError::Unauthorized));
+
+        // The refresh token was revoked
+        client.set_session(Some(session));
+        server.expire_access_tokens();
+        assert_eq!(client.list_notes(), Err(ClientError::Unauthorized));
+    }
+
+    #[test]
+    fn test_note_crud() {
+        let server = MockServer::start();
+        let mut client = logged_in(&server);
+        assert_eq!(client.list_notes().unwrap(), vec![]);
+
+        let note = crdt_note::Note::new("synced");
+        client.put_note(&WireNote::new(&note)).unwrap();
+
+        let notes = client.list_notes().unwrap();
+        assert_eq!(notes.len(), 1);
+        assert_eq!(notes[0].id, note.id());
+        assert_eq!(notes[0].note().content(), "synced");
+
+        client.delete_note(&note.id()).unwrap();
+        assert_eq!(client.list_notes().unwrap(), vec![]);
+    }
+
+    #[test]
+    fn test_notes_are_per_user() {
+        let server = MockServer::start();
+        let mut ada = logged_in(&server);
+        ada.put_note(&WireNote::new(&crdt_note::Note::new("ada's")))
+            .unwrap();
+
+        let mut grace = HttpClient::new(&server.url());
+        grace.request_magic_link("grace@example.com").unwrap();
+        grace
+            .verify(&server.magic_token("grace@example.com").unwrap())
+            .unwrap();
+        assert_eq!(grace.list_notes().unwrap(), vec![]);
+    }
+
+    #[test]
+    fn test_refreshes_expired_access_token() {
+        let server = MockServer::start();
+        let mut client = logged_in(&server);
+        let before = client.session().unwrap().access_token.clone();
+
+        server.expire_access_tokens();
+        assert_eq!(client.list_notes().unwrap(), vec![]);
+        assert_ne!(client.session().unwrap().access_token, before);
+    }
+
+    #[test]
+    fn test_server_errors() {
+        let server = MockServer::start();
+        let client = HttpClient::new(&server.url());
+
+        assert!(matches!(
+            client.request_magic_link(""),
+            Err(ClientError::Status { status: 400, .. })
+        ));
+
+        let unreachable = HttpClient::new("http://127.0.0.1:9");
+        assert!(matches!(
+            unreachable.request_magic_link("ada@example.com"),
+            Err(ClientError::Transport(_))
+        ));
+    }
+
+    // Records requests and answers from a script, without any server
+    struct Scripted {
+        sent: Rc<RefCell<Vec<Request>>>,
+        responses: RefCell<Vec<Response>>,
+    }
+
+    impl Transport for Scripted {
+        fn send(&self, request: &Request) -> ClientResult<Response> {
+            self.sent.borrow_mut().push(request.clone());
+            Ok(self.responses.borrow_mut().remove(0))
+        }
+    }
+
+    #[test]
+    fn test_custom_transport() {
+        let sent = Rc::new(RefCell::new(Vec::new()));
+        let respond = |status: u16, body: &str| Response {
+            status,
+            body: body.as_bytes().to_vec(),
+        };
+        let mut client = HttpClient::with_transport(Box::new(Scripted {
+            sent: sent.clone(),
+            responses: RefCell::new(vec![
+                respond(401, r#"{"errors":{"detail":"Unauthorized"}}"#),
+                respond(200, r#"{"access_token":"fresh"}"#),
+                respond(200, r#"{"deleted":true}"#),
+            ]),
+        }));
+        client.set_session(Some(Session {
+            access_token: "stale".to_string(),
+            refresh_token: "refresh".to_string(),
+            user: User {
+                id: "1".to_string(),
+                email: "ada@example.com".to_string(),
+            },
+        }));
+
+        client.delete_note("a b/c").unwrap();
+
+        let sent = sent.borrow();
+        assert_eq!(sent[0].method, Method::Delete);
+        assert_eq!(sent[0].path, "/api/notes/a%20b%2Fc");
+        assert_eq!(sent[0].bearer.as_deref(), Some("stale"));
+        assert_eq!(sent[1].path, "/api/auth/refresh");
+        assert_eq!(
+            sent[1].body.as_deref(),
+            Some(br#"{"refresh_token":"refresh"}"#.as_slice())
+        );
+        assert_eq!(sent[2].bearer.as_deref(), Some("fresh"));
+    }
+}
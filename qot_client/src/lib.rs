@@ -0,0 +1,15 @@
+//! A Rust client for the qot server.
+//!
+//! `HttpClient` covers the HTTP API: magic-link sign-in and the note
+//! endpoints. `NotesChannel` is the live connection the server pushes note
+//! changes over. Note bytes travel as `crdt_note::WireNote`, base64 in JSON.
+
+mod channel;
+mod http;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
+mod transport;
+
+pub use channel::{ChannelConfig, ChannelEvent, NotesChannel, socket_url};
+pub use http::{ClientError, ClientResult, HttpClient, Session, User};
+pub use transport::{Method, Request, Response, Transport, UreqTransport};
@@ -0,0 +1,377 @@
+//! An in-process fake of the qot server for tests: the HTTP API and the
+//! notes channel socket. It keeps everything in memory and speaks the same
+//! JSON as the Phoenix server. Instead of emailing a magic link it keeps the
+//! token, for `magic_token`.
+
+mod socket;
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::JoinHandle;
+
+use crdt_note::{WireMessage, WireNote};
+use serde_json::{Value, json};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+#[derive(Default)]
+struct State {
+    users: HashMap<String, String>,
+    magic_tokens: HashMap<String, String>,
+    magic_links: HashMap<String, String>,
+    access_tokens: HashMap<String, String>,
+    refresh_tokens: HashMap<String, String>,
+    notes: HashMap<String, BTreeMap<String, Vec<u8>>>,
+    next_token: u64,
+    sockets: Vec<socket::Subscriber>,
+    heartbeats: usize,
+}
+
+impl State {
+    fn token(&mut self, kind: &str) -> String {
+        self.next_token += 1;
+        format!("{}-{}", kind, self.next_token)
+    }
+
+    fn user_id(&mut self, email: &str) -> String {
+        let next = self.users.len() + 1;
+        self.users
+            .entry(email.to_string())
+            .or_insert_with(|| format!("user-{}", next))
+            .clone()
+    }
+
+    // Like the server, every change is pushed to all the user's sockets
+    fn set_note(&mut self, user_id: &str, id: &str, data: Vec<u8>) {
+        let message = WireMessage::Note(WireNote {
+            id: id.to_string(),
+            data: data.clone(),
+        });
+        self.notes
+            .entry(user_id.to_string())
+            .or_default()
+            .insert(id.to_string(), data);
+        socket::broadcast(self, user_id, &message);
+    }
+
+    fn delete_note(&mut self, user_id: &str, id: &str) {
+        if let Some(notes) = self.notes.get_mut(user_id) {
+            notes.remove(id);
+        }
+        let message = WireMessage::Delete { id: id.to_string() };
+        socket::broadcast(self, user_id, &message);
+    }
+
+    fn wire_notes(&self, user_id: &str) -> Vec<WireNote> {
+        self.notes
+            .get(user_id)
+            .into_iter()
+            .flatten()
+            .map(|(id, data)| WireNote {
+                id: id.clone(),
+                data: data.clone(),
+            })
+            .collect()
+    }
+}
+
+pub struct MockServer {
+    server: Arc<Server>,
+    state: Arc<Mutex<State>>,
+    thread: Option<JoinHandle<()>>,
+    socket: socket::SocketServer,
+}
+
+impl MockServer {
+    /// Starts serving on a free local port until dropped.
+    pub fn start() -> Self {
+        let server = Arc::new(Server::http("127.0.0.1:0").expect("bind mock server"));
+        let state = Arc::new(Mutex::new(State::default()));
+
+        let thread = {
+            let server = server.clone();
+            let state = state.clone();
+            std::thread::spawn(move || {
+                for request in server.incoming_requests() {
+                    handle(&state, request);
+                }
+            })
+        };
+
+        Self {
+            server,
+            socket: socket::SocketServer::start(state.clone()),
+            state,
+            thread: Some(thread),
+        }
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://{}", self.server.server_addr())
+    }
+
+    /// The notes channel endpoint. It listens on its own port, so it is not
+    /// `channel::socket_url(&self.url())`.
+    pub fn socket_url(&self) -> String {
+        format!("ws://{}/socket/websocket", self.socket.addr())
+    }
+
+    /// The token from the last magic link sent to `email`.
+    pub fn magic_token(&self, email: &str) -> Option<String> {
+        self.state()
+            .magic_tokens
+            .iter()
+            .find(|(_, to)| *to == email)
+            .map(|(token, _)| token.clone())
+    }
+
+    /// The link emailed to `email` with the last magic token.
+    pub fn magic_link(&self, email: &str) -> Option<String> {
+        let token = self.magic_token(email)?;
+        self.state().magic_links.get(&token).cloned()
+    }
+
+    /// Signs `email` in directly, as if they had followed a magic link.
+    pub fn session(&self, email: &str) -> crate::Session {
+        let mut state = self.state();
+        let id = state.user_id(email);
+        let access_token = state.token("access");
+        let refresh_token = state.token("refresh");
+        state.access_tokens.insert(access_token.clone(), id.clone());
+        state
+            .refresh_tokens
+            .insert(refresh_token.clone(), id.clone());
+
+        crate::Session {
+            access_token,
+            refresh_token,
+            user: crate::User {
+                id,
+                email: email.to_string(),
+            },
+        }
+    }
+
+    /// Makes every access token invalid, as if they had all timed out.
+    pub fn expire_access_tokens(&self) {
+        self.state().access_tokens.clear();
+    }
+
+    /// The notes stored for a user, by id.
+    pub fn notes(&self, user_id: &str) -> BTreeMap<String, Vec<u8>> {
+        self.state().notes.get(user_id).cloned().unwrap_or_default()
+    }
+
+    /// Stores a note for a user, as if another device had uploaded it.
+    pub fn put_note(&self, user_id: &str, id: &str, data: &[u8]) {
+        self.state().set_note(user_id, id, data.to_vec());
+    }
+
+    /// Deletes a note for a user, as if another device had deleted it.
+    pub fn delete_note(&self, user_id: &str, id: &str) {
+        self.state().delete_note(user_id, id);
+    }
+
+    /// How many heartbeats the sockets have answered.
+    pub fn heartbeats(&self) -> usize {
+        self.state().heartbeats
+    }
+
+    /// Drops every socket connection, as a server restart would.
+    pub fn disconnect_sockets(&self) {
+        socket::disconnect_all(&mut self.state());
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.socket.stop();
+        self.server.unblock();
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+fn handle(state: &Mutex<State>, mut request: Request) {
+    let mut body = String::new();
+    request.as_reader().read_to_string(&mut body).ok();
+    let body: Value = serde_json::from_str(&body).unwrap_or(Value::Null);
+
+    let bearer = request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv("Authorization"))
+        .and_then(|h| h.value.as_str().strip_prefix("Bearer ").map(str::to_string));
+
+    let (path, query) = request
+        .url()
+        .split_once('?')
+        .map(|(path, query)| (path.to_string(), query.to_string()))
+        .unwrap_or_else(|| (request.url().to_string(), String::new()));
+
+    let mut state = state.lock().unwrap_or_else(|e| e.into_inner());
+    let (status, reply) = route(
+        &mut state,
+        request.method(),
+        &path,
+        &query,
+        bearer.as_deref(),
+        &body,
+    );
+    drop(state);
+
+    let header = Header::from_bytes("Content-Type", "application/json").unwrap();
+    let response = Response::from_string(reply.to_string())
+        .with_status_code(status)
+        .with_header(header);
+    request.respond(response).ok();
+}
+
+fn route(
+    state: &mut State,
+    method: &Method,
+    path: &str,
+    query: &str,
+    bearer: Option<&str>,
+    body: &Value,
+) -> (u16, Value) {
+    let text = |key: &str| {
+        body.get(key)
+            .and_then(Value::as_str)
+            .filter(|s| !s.is_empty())
+    };
+
+    match (method, path) {
+        (Method::Post, "/api/auth/magic-link") => match text("email") {
+            Some(email) => {
+                let token = state.token("magic");
+                let link = match text("redirect_uri").filter(|uri| is_loopback(uri)) {
+                    Some(uri) => format!("{}?token={}", uri, token),
+                    None => format!("http://localhost:5173/auth/verify?token={}", token),
+                };
+                state.magic_links.insert(token.clone(), link);
+                state.magic_tokens.insert(token, email.to_string());
+                (
+                    200,
+                    json!({ "message": format!("Magic link sent to {}", email) }),
+                )
+            }
+            None => (400, json!({ "error": "Email is required" })),
+        },
+        (Method::Get, "/api/auth/verify") => {
+            let token = query
+                .split('&')
+                .find_map(|pair| pair.strip_prefix("token="))
+                .unwrap_or_default();
+            match state.magic_tokens.remove(&decode(token)) {
+                Some(email) => {
+                    let id = state.user_id(&email);
+                    let access_token = state.token("access");
+                    let refresh_token = state.token("refresh");
+                    state.access_tokens.insert(access_token.clone(), id.clone());
+                    state
+                        .refresh_tokens
+                        .insert(refresh_token.clone(), id.clone());
+                    (
+                        200,
+                        json!({
+                            "access_token": access_token,
+                            "refresh_token": refresh_token,
+                            "user": { "id": id, "email": email },
+                        }),
+                    )
+                }
+                None => (401, json!({ "error": "Invalid or expired token" })),
+            }
+        }
+        (Method::Post, "/api/auth/refresh") => {
+            match text("refresh_token").and_then(|t| state.refresh_tokens.get(t).cloned()) {
+                Some(id) => {
+                    let access_token = state.token("access");
+                    state.access_tokens.insert(access_token.clone(), id);
+                    (200, json!({ "access_token": access_token }))
+                }
+                None => (401, json!({ "error": "Invalid or expired refresh token" })),
+            }
+        }
+        (Method::Post, "/api/auth/logout") => match text("refresh_token") {
+            Some(token) => {
+                state.refresh_tokens.remove(token);
+                (200, json!({ "message": "Logged out successfully" }))
+            }
+            None => (400, json!({ "error": "Refresh token is required" })),
+        },
+        (_, path) if path.starts_with("/api/notes") => {
+            let Some(user_id) = bearer.and_then(|t| state.access_tokens.get(t).cloned()) else {
+                return (401, json!({ "errors": { "detail": "Unauthorized" } }));
+            };
+            notes_route(state, method, path, &user_id, body)
+        }
+        _ => (404, json!({ "errors": { "detail": "Not Found" } })),
+    }
+}
+
+fn notes_route(
+    state: &mut State,
+    method: &Method,
+    path: &str,
+    user_id: &str,
+    body: &Value,
+) -> (u16, Value) {
+    let id = path.strip_prefix("/api/notes/").map(decode);
+
+    match (method, id) {
+        (Method::Get, None) => (200, json!({ "notes": state.wire_notes(user_id) })),
+        (Method::Put, Some(id)) => {
+            // The body is a WireNote, so this also decodes the base64
+            match serde_json::from_value::<WireNote>(body.clone()).ok() {
+                Some(note) => {
+                    state.set_note(user_id, &id, note.data);
+                    (200, json!({ "id": id }))
+                }
+                None => (400, json!({ "error": "Invalid base64 data" })),
+            }
+        }
+        (Method::Delete, Some(id)) => {
+            state.delete_note(user_id, &id);
+            (200, json!({ "deleted": true }))
+        }
+        _ => (404, json!({ "errors": { "detail": "Not Found" } })),
+    }
+}
+
+// Like the mailer, links only go to a listener on this machine
+fn is_loopback(uri: &str) -> bool {
+    ["http://127.0.0.1:", "http://localhost:"]
+        .iter()
+        .any(|prefix| uri.starts_with(prefix))
+        && !uri.contains('?')
+}
+
+// Reverses percent-encoding
+fn decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
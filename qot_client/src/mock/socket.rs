@@ -0,0 +1,245 @@
+// The fake notes channel, speaking the Phoenix v2 JSON serializer. Runs on
+// its own thread with a single-threaded tokio runtime.
+
+use super::{State, decode};
+use crdt_note::WireMessage;
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{Value, json};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot};
+use tokio_tungstenite::tungstenite::Message as Frame;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::StatusCode;
+
+// [join_ref, ref, topic, event, payload]
+type Incoming = (Option<String>, Option<String>, String, String, Value);
+
+pub(super) enum Command {
+    Push(Value),
+    Disconnect,
+}
+
+pub(super) struct Subscriber {
+    user_id: String,
+    topic: String,
+    commands: mpsc::UnboundedSender<Command>,
+}
+
+pub(super) struct SocketServer {
+    addr: SocketAddr,
+    shutdown: Option<oneshot::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl SocketServer {
+    pub(super) fn start(state: Arc<Mutex<State>>) -> Self {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("bind mock socket");
+        listener.set_nonblocking(true).ok();
+        let addr = listener.local_addr().expect("mock socket address");
+        let (shutdown, stopped) = oneshot::channel();
+
+        let thread = std::thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .expect("mock socket runtime");
+
+            runtime.block_on(async move {
+                let listener = TcpListener::from_std(listener).expect("mock socket listener");
+                tokio::select! {
+                    _ = accept(listener, state) => {}
+                    _ = stopped => {}
+                }
+            });
+        });
+
+        Self {
+            addr,
+            shutdown: Some(shutdown),
+            thread: Some(thread),
+        }
+    }
+
+    pub(super) fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub(super) fn stop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            shutdown.send(()).ok();
+        }
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+pub(super) fn broadcast(state: &mut State, user_id: &str, message: &WireMessage) {
+    let payload = serde_json::to_value(message).unwrap_or_default();
+    state.sockets.retain(|subscriber| {
+        subscriber.user_id != user_id
+            || subscriber
+                .commands
+                .send(Command::Push(frame(
+                    None,
+                    None,
+                    &subscriber.topic,
+                    "message",
+                    payload.clone(),
+                )))
+                .is_ok()
+    });
+}
+
+pub(super) fn disconnect_all(state: &mut State) {
+    for subscriber in state.sockets.drain(..) {
+        subscriber.commands.send(Command::Disconnect).ok();
+    }
+}
+
+async fn accept(listener: TcpListener, state: Arc<Mutex<State>>) {
+    while let Ok((stream, _)) = listener.accept().await {
+        tokio::spawn(connection(stream, state.clone()));
+    }
+}
+
+async fn connection(stream: TcpStream, state: Arc<Mutex<State>>) {
+    let lock = || state.lock().unwrap_or_else(|e| e.into_inner());
+
+    // Like UserSocket.connect: refuse the upgrade without a valid token.
+    // The large error type is tungstenite's callback signature.
+    let mut user_id = None;
+    #[allow(clippy::result_large_err)]
+    let check_token = |request: &Request, response: Response| {
+        let token = request
+            .uri()
+            .query()
+            .unwrap_or_default()
+            .split('&')
+            .find_map(|pair| pair.strip_prefix("token="))
+            .map(decode)
+            .unwrap_or_default();
+
+        match lock().access_tokens.get(&token) {
+            Some(id) => {
+                user_id = Some(id.clone());
+                Ok(response)
+            }
+            None => {
+                let mut refused = ErrorResponse::new(None);
+                *refused.status_mut() = StatusCode::FORBIDDEN;
+                Err(refused)
+            }
+        }
+    };
+    let Ok(mut socket) = tokio_tungstenite::accept_hdr_async(stream, check_token).await else {
+        return;
+    };
+    let Some(user_id) = user_id else {
+        return;
+    };
+
+    let (commands, mut commands_rx) = mpsc::unbounded_channel();
+
+    loop {
+        tokio::select! {
+            frame = socket.next() => {
+                let Some(Ok(Frame::Text(text))) = frame else {
+                    return;
+                };
+                let Ok(incoming) = serde_json::from_str::<Incoming>(&text) else {
+                    continue;
+                };
+
+                let replies = handle(&mut lock(), &user_id, &commands, incoming);
+                for reply in replies {
+                    if socket.send(Frame::text(reply.to_string())).await.is_err() {
+                        return;
+                    }
+                }
+            }
+            command = commands_rx.recv() => match command {
+                Some(Command::Push(frame)) => {
+                    if socket.send(Frame::text(frame.to_string())).await.is_err() {
+                        return;
+                    }
+                }
+                Some(Command::Disconnect) | None => {
+                    socket.close(None).await.ok();
+                    return;
+                }
+            },
+        }
+    }
+}
+
+// The frames to send back, like NotesChannel's join and handle_in
+fn handle(
+    state: &mut State,
+    user_id: &str,
+    commands: &mpsc::UnboundedSender<Command>,
+    (join_ref, msg_ref, topic, event, payload): Incoming,
+) -> Vec<Value> {
+    let reply = |status: &str, response: Value| {
+        frame(
+            join_ref.as_deref(),
+            msg_ref.as_deref(),
+            &topic,
+            "phx_reply",
+            json!({ "status": status, "response": response }),
+        )
+    };
+
+    match event.as_str() {
+        "heartbeat" if topic == "phoenix" => {
+            state.heartbeats += 1;
+            vec![reply("ok", json!({}))]
+        }
+        "phx_join" => {
+            if topic != format!("notes:user:{}", user_id) {
+                return vec![reply("error", json!({ "reason": "unauthorized" }))];
+            }
+
+            state.sockets.push(Subscriber {
+                user_id: user_id.to_string(),
+                topic: topic.clone(),
+                commands: commands.clone(),
+            });
+            let notes = WireMessage::Notes {
+                notes: state.wire_notes(user_id),
+            };
+            vec![
+                reply("ok", json!({})),
+                frame(
+                    join_ref.as_deref(),
+                    None,
+                    &topic,
+                    "message",
+                    serde_json::to_value(notes).unwrap_or_default(),
+                ),
+            ]
+        }
+        "message" => {
+            match serde_json::from_value::<WireMessage>(payload) {
+                Ok(WireMessage::Note(note)) => state.set_note(user_id, &note.id, note.data),
+                Ok(WireMessage::Delete { id }) => state.delete_note(user_id, &id),
+                _ => return vec![reply("error", json!({ "reason": "Invalid message" }))],
+            }
+            vec![reply("ok", json!({}))]
+        }
+        _ => vec![],
+    }
+}
+
+fn frame(
+    join_ref: Option<&str>,
+    msg_ref: Option<&str>,
+    topic: &str,
+    event: &str,
+    payload: Value,
+) -> Value {
+    json!([join_ref, msg_ref, topic, event, payload])
+}
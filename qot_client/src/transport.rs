@@ -0,0 +1,108 @@
+use crate::{ClientError, ClientResult};
+use std::io::Read;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Method {
+    Get,
+    Post,
+    Put,
+    Delete,
+}
+
+/// An HTTP request relative to the server's base URL.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Request {
+    pub method: Method,
+    /// Path and query, e.g. `/api/notes`
+    pub path: String,
+    pub bearer: Option<String>,
+    /// JSON body
+    pub body: Option<Vec<u8>>,
+}
+
+impl Request {
+    pub fn new(method: Method, path: &str) -> Self {
+        Self {
+            method,
+            path: path.to_string(),
+            bearer: None,
+            body: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub body: Vec<u8>,
+}
+
+/// Sends requests to the server. `HttpClient` only talks to the server
+/// through this, so tests can swap in their own.
+pub trait Transport {
+    /// Returns the response for any status. Errors are for requests that
+    /// got no response at all.
+    fn send(&self, request: &Request) -> ClientResult<Response>;
+}
+
+/// The default transport, over HTTP(S) with ureq.
+pub struct UreqTransport {
+    base_url: String,
+    agent: ureq::Agent,
+}
+
+impl UreqTransport {
+    pub fn new(base_url: &str) -> Self {
+        let agent = ureq::Agent::config_builder()
+            .http_status_as_error(false)
+            .build()
+            .into();
+
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            agent,
+        }
+    }
+}
+
+impl Transport for UreqTransport {
+    fn send(&self, request: &Request) -> ClientResult<Response> {
+        let url = format!("{}{}", self.base_url, request.path);
+        let bearer = request.bearer.as_ref().map(|t| format!("Bearer {}", t));
+        let body = request.body.as_deref().unwrap_or_default();
+
+        let result = match request.method {
+            Method::Get => with_auth(self.agent.get(&url), &bearer).call(),
+            Method::Delete => with_auth(self.agent.delete(&url), &bearer).call(),
+            Method::Post => with_auth(self.agent.post(&url), &bearer)
+                .content_type("application/json")
+                .send(body),
+            Method::Put => with_auth(self.agent.put(&url), &bearer)
+                .content_type("application/json")
+                .send(body),
+        };
+
+        let mut response = result.map_err(|e| ClientError::Transport(e.to_string()))?;
+        let mut body = Vec::new();
+        response
+            .body_mut()
+            .as_reader()
+            .read_to_end(&mut body)
+            .map_err(|e| ClientError::Transport(e.to_string()))?;
+
+        Ok(Response {
+            status: response.status().as_u16(),
+            body,
+        })
+    }
+}
+
+fn with_auth<B>(
+    request: ureq::RequestBuilder<B>,
+    bearer: &Option<String>,
+) -> ureq::RequestBuilder<B> {
+    match bearer {
+        Some(bearer) => request.header("Authorization", bearer),
+        None => request,
+    }
+}
@@ -56,9 +56,10 @@ defmodule Qot.Accounts do
 
   @doc """
   Creates a magic link token and sends it via email.
+  The link goes to `redirect_uri` when it is a loopback address.
   Returns :ok or {:error, reason}
   """
-  def create_magic_link(email) do
+  def create_magic_link(email, redirect_uri \\ nil) do
     # Generate token
     token = Token.generate_magic_link_token()
     hashed_token = Token.hash_token(token)
@@ -73,7 +74,7 @@ defmodule Qot.Accounts do
     case Repo.query(query, [hashed_token, email, expires_at, DateTime.utc_now()]) do
       {:ok, _} ->
         # Send email with the unhashed token
-        Mailer.send_magic_link(email, token)
+        Mailer.send_magic_link(email, token, redirect_uri)
         :ok
 
       {:error, reason} ->
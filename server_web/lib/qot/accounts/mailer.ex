@@ -9,8 +9,8 @@ defmodule Qot.Accounts.Mailer do
   @doc """
   Sends a magic link email to the given email address.
   """
-  def send_magic_link(email, token) do
-    magic_link_url = build_magic_link_url(token)
+  def send_magic_link(email, token, redirect_uri \\ nil) do
+    magic_link_url = magic_link_url(token, redirect_uri)
 
     email_content =
       new()
@@ -42,11 +42,32 @@ defmodule Qot.Accounts.Mailer do
     deliver_email(email_content)
   end
 
-  defp build_magic_link_url(token) do
-    frontend_url = System.get_env("FRONTEND_URL") || "http://localhost:5173"
-    "#{frontend_url}/auth/verify?token=#{token}"
+  @doc """
+  Builds the link for a token. A `redirect_uri` is only honoured on a
+  loopback address, so a link can never carry the token to another host.
+  """
+  def magic_link_url(token, redirect_uri \\ nil) do
+    if loopback?(redirect_uri) do
+      "#{redirect_uri}?token=#{token}"
+    else
+      frontend_url = System.get_env("FRONTEND_URL") || "http://localhost:5173"
+      "#{frontend_url}/auth/verify?token=#{token}"
+    end
+  end
+
+  defp loopback?(uri) when is_binary(uri) do
+    case URI.parse(uri) do
+      %URI{scheme: "http", host: host, query: nil, userinfo: nil}
+      when host in ["127.0.0.1", "localhost", "::1"] ->
+        true
+
+      _ ->
+        false
+    end
   end
 
+  defp loopback?(_uri), do: false
+
   defp deliver_email(email) do
     # In development, Swoosh.Adapters.Local will capture emails
     # You can view them at http://localhost:4000/dev/mailbox (if you add the route)
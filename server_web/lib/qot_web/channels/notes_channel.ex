@@ -60,7 +60,7 @@ defmodule QotWeb.NotesChannel do
       {:ok, binary_data} ->
         # This will trigger PubSub broadcast to all user's clients
         {:ok, _note} = Notes.set(user_id, id, binary_data)
-        {:noreply, socket}
+        {:reply, :ok, socket}
 
       :error ->
         {:reply, {:error, %{reason: "Invalid base64"}}, socket}
@@ -72,6 +72,6 @@ defmodule QotWeb.NotesChannel do
     user_id = socket.assigns.user_id
     # This will trigger PubSub broadcast to all user's clients
     :ok = Notes.delete(user_id, id)
-    {:noreply, socket}
+    {:reply, :ok, socket}
   end
 end
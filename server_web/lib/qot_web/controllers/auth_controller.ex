@@ -7,9 +7,12 @@ defmodule QotWeb.AuthController do
   POST /api/auth/magic-link
   Request a magic link to be sent to the given email.
   Body: {"email": "user@example.com"}
+
+  An optional "redirect_uri" on a loopback address (e.g. the CLI's one-shot
+  listener) is used as the link instead of the web app's verify page.
   """
-  def request_magic_link(conn, %{"email" => email}) do
-    case Accounts.create_magic_link(email) do
+  def request_magic_link(conn, %{"email" => email} = params) do
+    case Accounts.create_magic_link(email, params["redirect_uri"]) do
       :ok ->
         json(conn, %{message: "Magic link sent to #{email}"})
 
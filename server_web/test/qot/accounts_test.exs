@@ -66,6 +66,31 @@ defmodule Qot.AccountsTest do
     end
   end
 
+  describe "Mailer.magic_link_url/2" do
+    alias Qot.Accounts.Mailer
+
+    test "links to the web app by default" do
+      assert Mailer.magic_link_url("abc") =~ ~r{/auth/verify\?token=abc$}
+    end
+
+    test "links to a loopback redirect_uri" do
+      assert Mailer.magic_link_url("abc", "http://127.0.0.1:53123/callback") ==
+               "http://127.0.0.1:53123/callback?token=abc"
+    end
+
+    test "ignores other redirect_uris" do
+      uris = [
+        "https://evil.example/callback",
+        "http://10.0.0.1:53123/callback",
+        "http://localhost:53123/callback?next=/"
+      ]
+
+      for uri <- uris do
+        assert Mailer.magic_link_url("abc", uri) =~ ~r{/auth/verify\?token=abc$}
+      end
+    end
+  end
+
   describe "verify_magic_link/1" do
     test "rejects invalid token" do
       assert {:error, :invalid_or_expired_token} = Accounts.verify_magic_link("invalid_token")
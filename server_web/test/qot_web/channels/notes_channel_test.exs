@@ -34,8 +34,9 @@ defmodule QotWeb.NotesChannelTest do
       {:ok, _, joined_socket} = subscribe_and_join(socket, "notes:user:#{auth.user_id}", %{})
 
       note_data = Base.encode64("test note content")
-      push(joined_socket, "message", %{"type" => "note", "id" => "test-note-id", "data" => note_data})
+      ref = push(joined_socket, "message", %{"type" => "note", "id" => "test-note-id", "data" => note_data})
 
+      assert_reply ref, :ok
       assert_push "message", %{type: "note", id: "test-note-id", data: ^note_data}
     end
   end
@@ -8,6 +8,16 @@ defmodule QotWeb.AuthControllerTest do
       assert json_response(conn, 200) == %{"message" => "Magic link sent to test@example.com"}
     end
 
+    test "accepts a loopback redirect_uri", %{conn: conn} do
+      conn =
+        post(conn, "/api/auth/magic-link", %{
+          email: "test@example.com",
+          redirect_uri: "http://127.0.0.1:53123/callback"
+        })
+
+      assert json_response(conn, 200) == %{"message" => "Magic link sent to test@example.com"}
+    end
+
     test "returns error without email", %{conn: conn} do
       conn = post(conn, "/api/auth/magic-link", %{})
 